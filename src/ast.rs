@@ -25,6 +25,8 @@ pub enum ConvError {
     YamlInvalidHashKey(Yaml),
     /// Failed to parse yaml real
     YamlCannotParseReal(String),
+    /// Json null is unsupported
+    JsonNull,
 }
 impl Value {
     pub fn from_yaml(yaml: Yaml) -> Result<Self, ConvError> {
@@ -57,6 +59,46 @@ impl Value {
             Yaml::String(s) => Ok(Value::Str(s)),
         }
     }
+
+    pub fn from_json(json: serde_json::Value) -> Result<Self, ConvError> {
+        match json {
+            serde_json::Value::Null => Err(ConvError::JsonNull),
+            serde_json::Value::Bool(b) => Ok(Value::Bool(b)),
+            serde_json::Value::Number(n) => Ok(if let Some(i) = n.as_i64() {
+                Value::Int(i)
+            } else {
+                Value::Real(n.as_f64().unwrap_or(f64::NAN))
+            }),
+            serde_json::Value::String(s) => Ok(Value::Str(s)),
+            serde_json::Value::Array(arr) => Ok(Value::Array(
+                arr.into_iter()
+                    .map(Self::from_json)
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+            serde_json::Value::Object(obj) => Ok(Value::Hash(
+                obj.into_iter()
+                    .map(|(key, val)| Ok((key, Self::from_json(val)?)))
+                    .collect::<Result<HashMap<_, _>, _>>()?,
+            )),
+        }
+    }
+
+    pub fn from_toml(toml: toml::Value) -> Self {
+        match toml {
+            toml::Value::String(s) => Value::Str(s),
+            toml::Value::Integer(i) => Value::Int(i),
+            toml::Value::Float(r) => Value::Real(r),
+            toml::Value::Boolean(b) => Value::Bool(b),
+            toml::Value::Datetime(dt) => Value::Str(dt.to_string()),
+            toml::Value::Array(arr) => Value::Array(arr.into_iter().map(Self::from_toml).collect()),
+            toml::Value::Table(table) => Value::Hash(
+                table
+                    .into_iter()
+                    .map(|(key, val)| (key, Self::from_toml(val)))
+                    .collect(),
+            ),
+        }
+    }
 }
 
 impl Value {
@@ -90,6 +132,24 @@ impl Value {
             None
         }
     }
+
+    /// Convert into liquid value to expose to templates.
+    pub fn to_liquid(&self) -> liquid::model::Value {
+        match self {
+            Value::Int(i) => liquid::model::Value::scalar(*i),
+            Value::Real(r) => liquid::model::Value::scalar(*r),
+            Value::Str(s) => liquid::model::Value::scalar(s.to_owned()),
+            Value::Bool(b) => liquid::model::Value::scalar(*b),
+            Value::Array(arr) => {
+                liquid::model::Value::Array(arr.iter().map(Self::to_liquid).collect())
+            }
+            Value::Hash(hash) => liquid::model::Value::Object(
+                hash.iter()
+                    .map(|(key, val)| (key.to_owned().into(), val.to_liquid()))
+                    .collect(),
+            ),
+        }
+    }
 }
 
 fn not_allowed_member<'a, K, T>(map: &'a HashMap<K, T>, allowed: &[&K]) -> Vec<(&'a K, &'a T)>
@@ -117,13 +177,13 @@ pub fn verify_hash(
     let allowed = allowed.iter().map(|s| (*s).to_owned()).collect::<Vec<_>>();
     let not_allowed = not_allowed_member(hash, allowed.iter().collect::<Vec<_>>().as_slice());
     if !not_allowed.is_empty() {
-        return Err(crate::Error::UnrecognizedMembers {
+        Err(crate::Error::UnrecognizedMembers {
             prefix: prefix.map(|s| s.to_owned()),
             members: not_allowed
                 .iter()
                 .map(|(k, v)| ((*k).clone(), (*v).clone()))
                 .collect::<Vec<_>>(),
-        });
+        })
     } else {
         Ok(())
     }
@@ -143,7 +203,7 @@ mod test {
             "d" => 4,
         };
         let mut not_allowed = not_allowed_member(&map, &[&"a", &"c"]);
-        not_allowed.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+        not_allowed.sort_by_key(|(k, _)| *k);
 
         assert_eq!(not_allowed, vec![(&"b", &2), (&"d", &4)]);
     }
@@ -0,0 +1,320 @@
+//! Backups of destination files which tasks overwrite or delete.
+//!
+//! Each deploy stores previous contents under `$XDG_STATE_HOME/dotman/backups/<run-id>/`
+//! (`~/.local/state` if unset). `files/` mirrors the absolute paths of saved files, and
+//! `manifest.json` records what was saved. `dotman backups` lists, restores and removes them.
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const MANIFEST: &str = "manifest.json";
+const FILES: &str = "files";
+
+/// Kind of saved path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Regular file
+    File,
+    /// Directory saved with its descendants
+    Dir,
+    /// Symlink saved as is
+    Symlink,
+}
+
+/// Saved path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Absolute path the content was saved from
+    pub path: PathBuf,
+    /// Kind of the path
+    pub kind: Kind,
+    /// Permission bits
+    pub mode: u32,
+}
+
+/// Record of a run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Run ID (UTC time and pid, e.g. `20230120T083000-1234`)
+    pub id: String,
+    /// Saved paths in saved order
+    pub entries: Vec<Entry>,
+}
+
+/// Backup of a run shared by tasks. Nothing is written until the first file is saved.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    dir: PathBuf,
+    enabled: bool,
+    manifest: Arc<Mutex<Manifest>>,
+}
+
+/// Default directory of backups.
+pub fn root() -> Option<PathBuf> {
+    crate::util::state_dir().map(|dir| dir.join("backups"))
+}
+
+/// Format unix time `secs` as `YYYYMMDDTHHMMSS` in UTC.
+fn timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // civil from days (Howard Hinnant)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Where the content of `path` is stored in backup `dir`.
+fn stored_path(dir: &Path, path: &Path) -> PathBuf {
+    dir.join(FILES).join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn copy_tree(src: &Path, dest: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if meta.file_type().is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(src)?, dest)
+    } else if meta.is_dir() {
+        fs::create_dir_all(dest)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+        fs::set_permissions(dest, meta.permissions())
+    } else if meta.is_file() {
+        fs::copy(src, dest).map(|_| ())
+    } else {
+        // fifos, sockets and devices have no content to save
+        Ok(())
+    }
+}
+
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+impl Backup {
+    /// Backup of a new run under `root`. `enabled` is the default of tasks.
+    pub fn new(root: &Path, enabled: bool) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+        let id = format!("{}-{}", timestamp(now), std::process::id());
+        Self {
+            dir: root.join(&id),
+            enabled,
+            manifest: Arc::new(Mutex::new(Manifest {
+                id,
+                entries: Vec::new(),
+            })),
+        }
+    }
+
+    /// Backup to be used by a task with `backup` option `task`.
+    pub fn for_task(backup: Option<&Self>, task: Option<bool>) -> Option<&Self> {
+        backup.filter(|backup| task.unwrap_or(backup.enabled))
+    }
+
+    /// Directory of this run.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of saved paths.
+    pub fn len(&self) -> usize {
+        self.manifest
+            .lock()
+            .map(|manifest| manifest.entries.len())
+            .unwrap_or_default()
+    }
+
+    /// Whether nothing is saved.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Save `path` (a directory with its descendants) before it is overwritten or deleted.
+    /// Missing paths and paths already saved by this run are skipped.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let meta = match fs::symlink_metadata(path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if !meta.is_file() && !meta.is_dir() && !meta.file_type().is_symlink() {
+            return Ok(());
+        }
+        let path = std::path::absolute(path)?;
+        let mut manifest = self
+            .manifest
+            .lock()
+            .map_err(|_| io::Error::other("backup manifest is poisoned"))?;
+        // the first content of a run is what restore brings back
+        if manifest
+            .entries
+            .iter()
+            .any(|entry| path.starts_with(&entry.path))
+        {
+            return Ok(());
+        }
+        copy_tree(&path, &stored_path(&self.dir, &path))?;
+        manifest.entries.push(Entry {
+            kind: if meta.file_type().is_symlink() {
+                Kind::Symlink
+            } else if meta.is_dir() {
+                Kind::Dir
+            } else {
+                Kind::File
+            },
+            mode: meta.permissions().mode() & 0o7777,
+            path,
+        });
+        let json = serde_json::to_vec_pretty(&*manifest).map_err(io::Error::other)?;
+        fs::write(self.dir.join(MANIFEST), json)
+    }
+}
+
+/// Load manifests of all runs under `root`, oldest first.
+pub fn list(root: &Path) -> io::Result<Vec<Manifest>> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut manifests = Vec::new();
+    for entry in entries {
+        let path = entry?.path().join(MANIFEST);
+        if let Ok(json) = fs::read(&path) {
+            manifests.push(
+                serde_json::from_slice::<Manifest>(&json).map_err(|e| {
+                    io::Error::other(format!("cannot parse {:?} due to {}", path, e))
+                })?,
+            );
+        }
+    }
+    manifests.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(manifests)
+}
+
+/// Restore saved `paths` (all of them if empty) of run `id`. Returns restored paths.
+pub fn restore(root: &Path, id: &str, paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let manifest = list(root)?
+        .into_iter()
+        .find(|manifest| manifest.id == id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no backup {}", id)))?;
+    let paths = paths
+        .iter()
+        .map(std::path::absolute)
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut targets = Vec::new();
+    // newer entries first, so that older content of the same path wins
+    for entry in manifest.entries.iter().rev() {
+        if paths.is_empty() || paths.iter().any(|path| entry.path.starts_with(path)) {
+            targets.push(entry.path.clone());
+        } else if let Some(path) = paths.iter().find(|path| path.starts_with(&entry.path)) {
+            targets.push(path.clone());
+        }
+    }
+    if targets.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("backup {} has none of given paths", id),
+        ));
+    }
+    let dir = root.join(id);
+    for target in &targets {
+        let stored = stored_path(&dir, target);
+        fs::symlink_metadata(&stored)?;
+        remove_existing(target)?;
+        copy_tree(&stored, target)?;
+    }
+    Ok(targets)
+}
+
+/// Remove runs except the newest `keep`. Returns removed run IDs.
+pub fn gc(root: &Path, keep: usize) -> io::Result<Vec<String>> {
+    let manifests = list(root)?;
+    let count = manifests.len().saturating_sub(keep);
+    let mut removed = Vec::new();
+    for manifest in manifests.into_iter().take(count) {
+        fs::remove_dir_all(root.join(&manifest.id))?;
+        removed.push(manifest.id);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backup() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-backup-{}", std::process::id()));
+        let root = dir.join("backups");
+        let home = dir.join("home");
+        fs::create_dir_all(home.join("nvim")).unwrap();
+        fs::write(home.join("gitconfig"), "old").unwrap();
+        fs::write(home.join("nvim/init.lua"), "init").unwrap();
+        assert_eq!(timestamp(1674203400), "20230120T083000");
+
+        let backup = Backup::new(&root, true);
+        assert!(Backup::for_task(Some(&backup), Some(false)).is_none());
+        assert!(Backup::for_task(Some(&backup), None).is_some());
+        backup.save(&home.join("gitconfig")).unwrap();
+        fs::write(home.join("gitconfig"), "new").unwrap();
+        // later content of the same path is not saved
+        backup.save(&home.join("gitconfig")).unwrap();
+        backup.save(&home.join("nvim")).unwrap();
+        backup.save(&home.join("nvim/init.lua")).unwrap();
+        backup.save(&home.join("missing")).unwrap();
+        assert_eq!(backup.len(), 2);
+        fs::remove_dir_all(home.join("nvim")).unwrap();
+
+        let manifests = list(&root).unwrap();
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(manifests[0].entries[1].kind, Kind::Dir);
+        let id = manifests[0].id.clone();
+        let restored = restore(&root, &id, &[home.join("nvim/init.lua")]).unwrap();
+        assert_eq!(restored, vec![home.join("nvim/init.lua")]);
+        assert_eq!(
+            fs::read_to_string(home.join("nvim/init.lua")).unwrap(),
+            "init"
+        );
+        restore(&root, &id, &[]).unwrap();
+        assert_eq!(fs::read_to_string(home.join("gitconfig")).unwrap(), "old");
+        assert!(restore(&root, &id, &[home.join("other")]).is_err());
+        assert!(restore(&root, "unknown", &[]).is_err());
+
+        assert!(gc(&root, 1).unwrap().is_empty());
+        assert_eq!(gc(&root, 0).unwrap(), vec![id]);
+        assert!(list(&root).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
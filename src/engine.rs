@@ -0,0 +1,412 @@
+//! Template engines rendering `dest`, `cp` templates, `sh.cmd` and so on.
+//!
+//! Liquid is the default. Jinja ([minijinja](https://docs.rs/minijinja)) is selected by
+//! `template_engine: jinja` of the playbook or `engine: jinja` of `cp.templates[]`.
+//! Both engines receive the same variables and share [partials](../partials/index.html).
+use liquid_core::model::{KString, KStringCow, KStringRef, ScalarCow, ValueCow};
+use liquid_core::parser::{Language, ParseBlock, ParseFilter, ParseTag};
+use liquid_core::partials::PartialCompiler;
+use liquid_core::runtime::{PartialStore, Registers, Renderable, Runtime, RuntimeBuilder};
+use liquid_lib::stdlib;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use crate::filters::{Filters, JinjaUsage, Usage};
+use crate::secret;
+use crate::Error;
+
+/// Template engine.
+pub trait TemplateEngine: Send + Sync {
+    /// Render `src` with `vars`, decrypting secrets which it refers.
+    fn render(
+        &self,
+        src: &str,
+        vars: &liquid::Object,
+        filters: &Filters,
+    ) -> Result<secret::Rendered, liquid::Error>;
+}
+
+/// Liquid with stdlib and [dotman filters](../filters/index.html).
+pub struct Liquid;
+
+impl TemplateEngine for Liquid {
+    fn render(
+        &self,
+        src: &str,
+        vars: &liquid::Object,
+        filters: &Filters,
+    ) -> Result<secret::Rendered, liquid::Error> {
+        let parser = crate::util::parser(filters)?;
+        let template = parser.parse(src)?;
+        let runtime = RuntimeBuilder::new()
+            .set_globals(vars)
+            .set_partials(parser.partials.as_ref())
+            .build();
+        let runtime = secret::Revealing::new(Deferred(runtime), filters.keyring());
+        let mut text = Vec::new();
+        let result = template.render_to(&mut text, &runtime).and_then(|()| {
+            match runtime.registers().get_mut::<Undefined>().0.take() {
+                Some(e) => Err(e),
+                None => Ok(String::from_utf8(text).expect("liquid renders UTF-8")),
+            }
+        });
+        let usage = *Usage::liquid(&runtime);
+        secret::rendered(result, runtime.failure(), usage)
+    }
+}
+
+/// Undefined variable error which a liquid render has not reported yet.
+#[derive(Default)]
+pub(crate) struct Undefined(pub(crate) Option<liquid::Error>);
+
+/// Liquid runtime deferring undefined variable errors, so that `default` filter can handle
+/// undefined input (e.g. `{{ env.XDG_CONFIG_HOME | default: "~/.config" }}`). Undefined
+/// variables are nil until the next lookup or the end of the render, which report the error
+/// unless `default` has taken it.
+struct Deferred<R>(R);
+
+impl<R: Runtime> Runtime for Deferred<R> {
+    fn partials(&self) -> &dyn PartialStore {
+        self.0.partials()
+    }
+
+    fn name(&self) -> Option<KStringRef<'_>> {
+        self.0.name()
+    }
+
+    fn roots(&self) -> BTreeSet<KStringCow<'_>> {
+        self.0.roots()
+    }
+
+    fn try_get(&self, path: &[ScalarCow<'_>]) -> Option<ValueCow<'_>> {
+        self.0.try_get(path)
+    }
+
+    fn get(&self, path: &[ScalarCow<'_>]) -> liquid_core::Result<ValueCow<'_>> {
+        if let Some(e) = self.registers().get_mut::<Undefined>().0.take() {
+            return Err(e);
+        }
+        self.0.get(path).or_else(|e| {
+            self.registers().get_mut::<Undefined>().0 = Some(e);
+            Ok(ValueCow::Owned(liquid::model::Value::Nil))
+        })
+    }
+
+    fn set_global(&self, name: KString, val: liquid::model::Value) -> Option<liquid::model::Value> {
+        self.0.set_global(name, val)
+    }
+
+    fn set_index(&self, name: KString, val: liquid::model::Value) -> Option<liquid::model::Value> {
+        self.0.set_index(name, val)
+    }
+
+    fn get_index<'a>(&'a self, name: &str) -> Option<ValueCow<'a>> {
+        self.0.get_index(name)
+    }
+
+    fn registers(&self) -> &Registers {
+        self.0.registers()
+    }
+}
+
+/// Liquid parser with stdlib, [dotman filters](../filters/index.html) and compiled
+/// [partials](../partials/index.html). Built once per run by [`crate::util::parser`].
+pub struct LiquidParser {
+    language: Arc<Language>,
+    partials: Box<dyn PartialStore + Send + Sync>,
+}
+
+impl LiquidParser {
+    pub(crate) fn new(filters: &Filters) -> Result<Self, liquid::Error> {
+        let mut language = Language::empty();
+        register_stdlib(&mut language);
+        filters.register(&mut language);
+        let language = Arc::new(language);
+        let partials = filters.partials().compiler().compile(language.clone())?;
+        Ok(Self { language, partials })
+    }
+
+    /// Parse template `src`.
+    pub fn parse(&self, src: &str) -> Result<liquid_core::Template, liquid::Error> {
+        liquid_core::parser::parse(src, &self.language).map(liquid_core::Template::new)
+    }
+}
+
+/// Register tags, blocks and filters of liquid stdlib, as `liquid::ParserBuilder::stdlib` does.
+fn register_stdlib(language: &mut Language) {
+    let tags: [Box<dyn ParseTag>; 7] = [
+        Box::new(stdlib::AssignTag),
+        Box::new(stdlib::BreakTag),
+        Box::new(stdlib::ContinueTag),
+        Box::new(stdlib::CycleTag),
+        Box::new(stdlib::IncludeTag),
+        Box::new(stdlib::IncrementTag),
+        Box::new(stdlib::DecrementTag),
+    ];
+    for tag in tags {
+        language
+            .tags
+            .register(tag.reflection().tag().to_owned(), tag);
+    }
+    let blocks: [Box<dyn ParseBlock>; 9] = [
+        Box::new(stdlib::RawBlock),
+        Box::new(stdlib::IfBlock),
+        Box::new(stdlib::UnlessBlock),
+        Box::new(stdlib::IfChangedBlock),
+        Box::new(stdlib::ForBlock),
+        Box::new(stdlib::TableRowBlock),
+        Box::new(stdlib::CommentBlock),
+        Box::new(stdlib::CaptureBlock),
+        Box::new(stdlib::CaseBlock),
+    ];
+    for block in blocks {
+        language
+            .blocks
+            .register(block.reflection().start_tag().to_owned(), block);
+    }
+    let filters: [Box<dyn ParseFilter>; 48] = [
+        Box::new(stdlib::Abs),
+        Box::new(stdlib::Append),
+        Box::new(stdlib::AtLeast),
+        Box::new(stdlib::AtMost),
+        Box::new(stdlib::Capitalize),
+        Box::new(stdlib::Ceil),
+        Box::new(stdlib::Compact),
+        Box::new(stdlib::Concat),
+        Box::new(stdlib::Date),
+        Box::new(stdlib::Default),
+        Box::new(stdlib::DividedBy),
+        Box::new(stdlib::Downcase),
+        Box::new(stdlib::Escape),
+        Box::new(stdlib::EscapeOnce),
+        Box::new(stdlib::First),
+        Box::new(stdlib::Floor),
+        Box::new(stdlib::Join),
+        Box::new(stdlib::Last),
+        Box::new(stdlib::Lstrip),
+        Box::new(stdlib::Map),
+        Box::new(stdlib::Minus),
+        Box::new(stdlib::Modulo),
+        Box::new(stdlib::NewlineToBr),
+        Box::new(stdlib::Plus),
+        Box::new(stdlib::Prepend),
+        Box::new(stdlib::Remove),
+        Box::new(stdlib::RemoveFirst),
+        Box::new(stdlib::Replace),
+        Box::new(stdlib::ReplaceFirst),
+        Box::new(stdlib::Reverse),
+        Box::new(stdlib::Round),
+        Box::new(stdlib::Rstrip),
+        Box::new(stdlib::Size),
+        Box::new(stdlib::Slice),
+        Box::new(stdlib::Sort),
+        Box::new(stdlib::SortNatural),
+        Box::new(stdlib::Split),
+        Box::new(stdlib::Strip),
+        Box::new(stdlib::StripHtml),
+        Box::new(stdlib::StripNewlines),
+        Box::new(stdlib::Times),
+        Box::new(stdlib::Truncate),
+        Box::new(stdlib::TruncateWords),
+        Box::new(stdlib::Uniq),
+        Box::new(stdlib::Upcase),
+        Box::new(stdlib::UrlDecode),
+        Box::new(stdlib::UrlEncode),
+        Box::new(stdlib::Where),
+    ];
+    for filter in filters {
+        language
+            .filters
+            .register(filter.reflection().name().to_owned(), filter);
+    }
+}
+
+/// Jinja with minijinja builtins and [dotman filters](../filters/index.html).
+/// Undefined variables are errors.
+pub struct Jinja;
+
+/// Environment shared by all Jinja templates rendered with `filters`.
+fn environment(filters: &Filters) -> minijinja::Environment<'static> {
+    let mut env = minijinja::Environment::new();
+    env.set_undefined_behavior(minijinja::UndefinedBehavior::Strict);
+    env.set_keep_trailing_newline(true);
+    filters.register_jinja(&mut env);
+    let partials = filters.partials().clone();
+    env.set_loader(move |name| Ok(partials.get(name)));
+    env
+}
+
+impl TemplateEngine for Jinja {
+    fn render(
+        &self,
+        src: &str,
+        vars: &liquid::Object,
+        filters: &Filters,
+    ) -> Result<secret::Rendered, liquid::Error> {
+        let env = filters.jinja_cell().get_or_init(|| environment(filters));
+        let usage = JinjaUsage::default();
+        let vars = secret::JinjaVars::new(vars.clone(), filters.keyring(), usage.clone());
+        let failure = vars.failure();
+        let result = env
+            .render_str(src, minijinja::Value::from_struct_object(vars))
+            .map_err(|e| liquid::Error::with_msg(e.to_string()));
+        let failure = failure.lock().expect("poisoned").take();
+        let usage = *usage.0.lock().expect("poisoned");
+        secret::rendered(result, failure, usage)
+    }
+}
+
+/// Selectable template engines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// [Liquid](struct.Liquid.html)
+    #[default]
+    Liquid,
+    /// [Jinja](struct.Jinja.html)
+    Jinja,
+}
+
+impl Engine {
+    /// Implementation of the engine.
+    pub fn get(self) -> &'static dyn TemplateEngine {
+        match self {
+            Self::Liquid => &Liquid,
+            Self::Jinja => &Jinja,
+        }
+    }
+}
+
+/// Parse engine name (`liquid` or `jinja`) at `key`.
+pub fn parse(yaml: &crate::ast::Value, key: &str) -> Result<Engine, Error> {
+    match yaml.as_str() {
+        Some("liquid") => Ok(Engine::Liquid),
+        Some("jinja") => Ok(Engine::Jinja),
+        _ => Err(Error::InvalidPlaybook(
+            format!("{} must be \"liquid\" or \"jinja\"", key),
+            yaml.to_owned(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_engines() {
+        let vars = liquid::object!({
+            "font": { "size": 11 },
+            "monitors": ["eDP-1", "DP-1"],
+        });
+        let filters = Filters::default();
+        let liquid = "{{ font.size }}{% for m in monitors %} {{ m }}{% endfor %}\n";
+        let jinja = "{{ font.size + 1 }}{% for m in monitors %} {{ m | lower }}{% endfor %}\n";
+        assert_eq!(
+            Engine::Liquid
+                .get()
+                .render(liquid, &vars, &filters)
+                .unwrap()
+                .text,
+            "11 eDP-1 DP-1\n"
+        );
+        assert_eq!(
+            Engine::Jinja
+                .get()
+                .render(jinja, &vars, &filters)
+                .unwrap()
+                .text,
+            "12 edp-1 dp-1\n"
+        );
+        assert!(Engine::Jinja
+            .get()
+            .render("{{ theme }}", &vars, &filters)
+            .is_err());
+        assert_eq!(
+            Engine::Jinja
+                .get()
+                .render("{{ theme | default('dark') }}", &vars, &filters)
+                .unwrap()
+                .text,
+            "dark"
+        );
+    }
+
+    #[test]
+    fn test_volatile() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-volatile-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("partials")).unwrap();
+        std::fs::write(dir.join("aliases"), "st = status").unwrap();
+        std::fs::write(
+            dir.join("partials/aliases.liquid"),
+            "{{ 'aliases' | read_file }}",
+        )
+        .unwrap();
+        let filters = Filters::new(dir.clone(), true, secret::Keyring::default())
+            .with_partials(crate::partials::Partials::load(&dir.join("partials"), true).unwrap());
+        let vars = liquid::object!({ "note": "read_file" });
+        for engine in [Engine::Liquid, Engine::Jinja] {
+            let rendered = engine
+                .get()
+                .render("{% include 'aliases' %}", &vars, &filters)
+                .unwrap();
+            assert_eq!(rendered.text, "st = status");
+            assert!(rendered.volatile);
+            // only filters which run make the text volatile
+            let rendered = engine
+                .get()
+                .render("read_file {{ note }}", &vars, &filters)
+                .unwrap();
+            assert!(!rendered.volatile);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_jinja_filters() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-engine-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("aliases"), "st = status").unwrap();
+        let filters = Filters::new(dir.clone(), true, secret::Keyring::default());
+        let vars = liquid::object!({
+            "font": "Hack Nerd Font",
+            "git": { "name": "foo", "size": 11 },
+        });
+        let render = |src: &str| {
+            Engine::Jinja
+                .get()
+                .render(src, &vars, &filters)
+                .unwrap()
+                .text
+        };
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            render("{{ '~/.config' | expand_home }}"),
+            format!("{}/.config", home)
+        );
+        assert_eq!(render("{{ font | shell_quote }}"), "'Hack Nerd Font'");
+        assert_eq!(
+            render("{{ 'abc' | sha256 }}"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(render("{{ 'abc' | base64 }}"), "YWJj");
+        assert_eq!(render("{{ git | to_json }}"), r#"{"name":"foo","size":11}"#);
+        assert_eq!(render("{{ git | to_yaml }}"), "name: foo\nsize: 11");
+        assert_eq!(render("{{ git | to_toml }}"), "name = \"foo\"\nsize = 11\n");
+        assert_eq!(
+            render("{{ 'DOTMAN_TEST_UNSET' | default_env('vi') }}"),
+            "vi"
+        );
+        assert_eq!(
+            render("{{ '/home' | path_join('foo', '.config') }}"),
+            "/home/foo/.config"
+        );
+        assert_eq!(render("{{ 'aliases' | read_file }}"), "st = status");
+        assert_eq!(
+            render("{{ 'echo hi' | command_output }}"),
+            "<output of echo hi>"
+        );
+        // the environment is built once
+        assert!(filters.jinja_cell().get().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
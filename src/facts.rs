@@ -0,0 +1,313 @@
+//! User-defined facts evaluated once before scenario matching.
+use kstring::KString;
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use crate::engine::Engine;
+use crate::partials::Partials;
+use crate::{ast, Error};
+
+#[derive(Debug)]
+enum Source {
+    Cmd(String, Vec<String>),
+    File(String),
+    Exists(String),
+}
+
+#[derive(Debug)]
+enum OutputParser {
+    Raw,
+    Json,
+    Regex(Regex),
+}
+
+/// Definition of a custom fact in `facts` section.
+#[derive(Debug)]
+pub struct FactDefinition {
+    name: String,
+    source: Source,
+    parser: OutputParser,
+}
+
+fn parse_output_parser(yaml: &ast::Value) -> Result<OutputParser, Error> {
+    match yaml {
+        ast::Value::Str(s) if s == "raw" => Ok(OutputParser::Raw),
+        ast::Value::Str(s) if s == "json" => Ok(OutputParser::Json),
+        ast::Value::Hash(hash) => {
+            ast::verify_hash(hash, &["regex"], Some("facts.parser"))?;
+            let src = hash
+                .get("regex")
+                .and_then(|re| re.as_str())
+                .ok_or_else(|| {
+                    Error::InvalidPlaybook(
+                        "facts.parser.regex must be string".to_owned(),
+                        yaml.to_owned(),
+                    )
+                })?;
+            let re = Regex::new(src).map_err(|e| {
+                Error::InvalidPlaybook(
+                    format!("cannot compile facts.parser.regex {} due to {:?}", src, e),
+                    yaml.to_owned(),
+                )
+            })?;
+            Ok(OutputParser::Regex(re))
+        }
+        _ => Err(Error::InvalidPlaybook(
+            "facts.parser must be \"raw\", \"json\" or {regex: <string>}".to_owned(),
+            yaml.to_owned(),
+        )),
+    }
+}
+
+fn parse_fact(name: &str, yaml: &ast::Value) -> Result<FactDefinition, Error> {
+    let obj = yaml.as_hash().ok_or_else(|| {
+        Error::InvalidPlaybook("children of facts must be hash".to_owned(), yaml.to_owned())
+    })?;
+    ast::verify_hash(obj, &["cmd", "file", "exists", "parser"], Some("facts"))?;
+    let source = match (obj.get("cmd"), obj.get("file"), obj.get("exists")) {
+        (Some(ast::Value::Array(cmd)), None, None) => {
+            let mut cmd = cmd
+                .iter()
+                .map(|s| {
+                    s.as_str().map(|s| s.to_owned()).ok_or_else(|| {
+                        Error::InvalidPlaybook(
+                            "facts.cmd must be array of string".to_owned(),
+                            yaml.to_owned(),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter();
+            let exe = cmd.next().ok_or_else(|| {
+                Error::InvalidPlaybook("facts.cmd must not be empty".to_owned(), yaml.to_owned())
+            })?;
+            Source::Cmd(exe, cmd.collect())
+        }
+        (None, Some(ast::Value::Str(path)), None) => Source::File(path.to_owned()),
+        (None, None, Some(ast::Value::Str(path))) => Source::Exists(path.to_owned()),
+        _ => {
+            return Err(Error::InvalidPlaybook(
+                "fact must have one of cmd: <string>[], file: <string> or exists: <string>"
+                    .to_owned(),
+                yaml.to_owned(),
+            ))
+        }
+    };
+    let parser = obj
+        .get("parser")
+        .map(parse_output_parser)
+        .unwrap_or(Ok(OutputParser::Raw))?;
+    Ok(FactDefinition {
+        name: name.to_owned(),
+        source,
+        parser,
+    })
+}
+
+/// parse `facts` section of playbook.
+pub fn parse(yaml: &ast::Value) -> Result<Vec<FactDefinition>, Error> {
+    let mut facts = yaml
+        .as_hash()
+        .ok_or_else(|| Error::InvalidPlaybook("facts must be hash".to_owned(), yaml.to_owned()))?
+        .iter()
+        .map(|(name, fact)| parse_fact(name, fact))
+        .collect::<Result<Vec<_>, _>>()?;
+    facts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(facts)
+}
+
+fn parse_output(
+    parser: &OutputParser,
+    output: &str,
+) -> anyhow::Result<Option<liquid::model::Value>> {
+    match parser {
+        OutputParser::Raw => Ok(Some(liquid::model::Value::scalar(output.trim().to_owned()))),
+        OutputParser::Json => {
+            let json: serde_json::Value = serde_json::from_str(output)?;
+            Ok(Some(liquid::model::to_value(&json)?))
+        }
+        OutputParser::Regex(re) => {
+            let captures = if let Some(captures) = re.captures(output) {
+                captures
+            } else {
+                return Ok(None);
+            };
+            let names = re.capture_names().flatten().collect::<Vec<_>>();
+            if !names.is_empty() {
+                let mut obj = liquid::Object::new();
+                for name in names {
+                    if let Some(m) = captures.name(name) {
+                        obj.insert(
+                            KString::from_ref(name),
+                            liquid::model::Value::scalar(m.as_str().to_owned()),
+                        );
+                    }
+                }
+                Ok(Some(liquid::model::Value::Object(obj)))
+            } else {
+                let m = captures.get(1).or_else(|| captures.get(0));
+                Ok(m.map(|m| liquid::model::Value::scalar(m.as_str().to_owned())))
+            }
+        }
+    }
+}
+
+fn resolve_path(
+    base: &Path,
+    path: &str,
+    partials: &Partials,
+    engine: Engine,
+) -> anyhow::Result<std::path::PathBuf> {
+    let path =
+        crate::util::resolve_liquid_template(path, &liquid::Object::new(), partials, engine)?;
+    Ok(base.join(path))
+}
+
+fn evaluate_fact(
+    fact: &FactDefinition,
+    base: &Path,
+    partials: &Partials,
+    engine: Engine,
+) -> anyhow::Result<Option<liquid::model::Value>> {
+    match &fact.source {
+        Source::Cmd(exe, args) => {
+            let mut cmd = process::Command::new(exe);
+            cmd.args(args);
+            // base of a playbook given by relative path (e.g. `dotfiles.yaml`) is empty
+            if !base.as_os_str().is_empty() {
+                cmd.current_dir(base);
+            }
+            let output = cmd.output()?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "{} exited with {}: {}",
+                    exe,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            parse_output(&fact.parser, &String::from_utf8_lossy(&output.stdout))
+        }
+        Source::File(path) => parse_output(
+            &fact.parser,
+            &fs::read_to_string(resolve_path(base, path, partials, engine)?)?,
+        ),
+        Source::Exists(path) => Ok(Some(liquid::model::Value::scalar(
+            fs::metadata(resolve_path(base, path, partials, engine)?).is_ok(),
+        ))),
+    }
+}
+
+/// Evaluate all facts. Facts which have no value (e.g. unmatched regex) are omitted.
+/// A fact which fails falls back on its value of the last run in `last`, if any.
+pub fn evaluate(
+    facts: &[FactDefinition],
+    base: &Path,
+    partials: &Partials,
+    engine: Engine,
+    last: &liquid::Object,
+) -> Result<liquid::Object, Error> {
+    let mut obj = liquid::Object::new();
+    for fact in facts {
+        let value = match evaluate_fact(fact, base, partials, engine) {
+            Ok(value) => value,
+            Err(e) => match last.get(fact.name.as_str()) {
+                Some(value) => {
+                    eprintln!(
+                        "[facts] cannot evaluate fact {} due to {:?}, using the value of the last run",
+                        fact.name, e
+                    );
+                    Some(value.clone())
+                }
+                None => {
+                    return Err(Error::CannotCollectNodeInformation(format!(
+                        "cannot evaluate fact {} due to {:?}",
+                        fact.name, e
+                    )))
+                }
+            },
+        };
+        if let Some(value) = value {
+            obj.insert(KString::from_string(fact.name.clone()), value);
+        }
+    }
+    Ok(obj)
+}
+
+/// Look up a dotted path such as `custom.gpu.vendor` in facts.
+pub fn lookup<'a>(facts: &'a liquid::Object, path: &str) -> Option<&'a liquid::model::Value> {
+    let mut keys = path.split('.');
+    let mut value = facts.get(keys.next()?)?;
+    for key in keys {
+        value = match value {
+            liquid::model::Value::Object(obj) => obj.get(key)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use liquid::model::ValueView;
+    use yaml_rust::YamlLoader;
+
+    fn load(src: &str) -> Vec<FactDefinition> {
+        let yaml = YamlLoader::load_from_str(src).unwrap();
+        parse(&ast::Value::from_yaml(yaml[0].clone()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_evaluate_facts() {
+        let facts = load(concat!(
+            "---\n",
+            "raw: { cmd: [\"echo\", \"hello\"] }\n",
+            "json: { cmd: [\"echo\", \"{\\\"gpu\\\": \\\"nvidia\\\"}\"], parser: json }\n",
+            "regex: { cmd: [\"echo\", \"VGA: NVIDIA\"], parser: { regex: \"VGA: (\\\\w+)\" } }\n",
+            "named: { cmd: [\"echo\", \"VGA: NVIDIA\"], parser: { regex: \"VGA: (?P<vendor>\\\\w+)\" } }\n",
+            "unmatched: { cmd: [\"echo\", \"none\"], parser: { regex: \"VGA\" } }\n",
+            "marker: { exists: \"/\" }\n",
+        ));
+        let obj = evaluate(
+            &facts,
+            Path::new("/"),
+            &Partials::default(),
+            Engine::default(),
+            &liquid::Object::new(),
+        )
+        .unwrap();
+        assert_eq!(lookup(&obj, "raw").unwrap().to_kstr(), "hello");
+        assert_eq!(lookup(&obj, "json.gpu").unwrap().to_kstr(), "nvidia");
+        assert_eq!(lookup(&obj, "regex").unwrap().to_kstr(), "NVIDIA");
+        assert_eq!(lookup(&obj, "named.vendor").unwrap().to_kstr(), "NVIDIA");
+        assert_eq!(lookup(&obj, "marker").unwrap().to_kstr(), "true");
+        assert!(lookup(&obj, "unmatched").is_none());
+    }
+
+    #[test]
+    fn test_failing_fact() {
+        let facts = load("---\nfailing: { cmd: [\"sh\", \"-c\", \"echo partial; exit 1\"] }\n");
+        let evaluate_with = |last: &liquid::Object| {
+            evaluate(
+                &facts,
+                Path::new("/"),
+                &Partials::default(),
+                Engine::default(),
+                last,
+            )
+        };
+        assert!(matches!(
+            evaluate_with(&liquid::Object::new()),
+            Err(Error::CannotCollectNodeInformation(_))
+        ));
+        // the value of the last run is used instead
+        let last = liquid::object!({ "failing": "cached", "removed": "stale" });
+        assert_eq!(
+            evaluate_with(&last).unwrap(),
+            liquid::object!({ "failing": "cached" })
+        );
+    }
+}
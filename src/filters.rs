@@ -0,0 +1,871 @@
+//! Dotman filters available in every template (`dest`, `cp` templates, `sh.cmd` and so on).
+//!
+//! | filter | example |
+//! |---|---|
+//! | `expand_home` | `{{ "~/.config" \| expand_home }}` |
+//! | `shell_quote` | `{{ font \| shell_quote }}` |
+//! | `sha256` | `{{ "text" \| sha256 }}` |
+//! | `base64` | `{{ "text" \| base64 }}` |
+//! | `to_json`, `to_yaml`, `to_toml` | `{{ monitors \| to_json }}` |
+//! | `read_file` | `{{ "pkgs/git/aliases" \| read_file }}` (relative to playbook) |
+//! | `command_output` | `{{ "git --version" \| command_output }}` (cached, not executed in dry-run) |
+//! | `path_join` | `{{ env.HOME \| path_join: ".config", "nvim" }}` |
+//! | `default_env` | `{{ "EDITOR" \| default_env: "vi" }}` |
+//! | `secret` | `{{ "github/token" \| secret }}` (see [secret](../secret/index.html)) |
+//!
+//! `default` of liquid stdlib is replaced with one which also takes undefined variables, as
+//! Jinja's does (e.g. `{{ env.XDG_CONFIG_HOME | default: "~/.config" }}`).
+//!
+//! Dotman adds no tags. Templates have the tags of liquid stdlib (or Jinja), and snippets shared
+//! among templates are [partials](../partials/index.html) included with `{% include %}`.
+use base64::Engine as _;
+use liquid::model::{State, Value, ValueView};
+use liquid_core::parser::{FilterArguments, Language, ParameterReflection};
+use liquid_core::runtime::{Expression, Runtime};
+use liquid_core::{
+    Display_filter, Filter, FilterParameters, FilterReflection, FromFilterParameters, ParseFilter,
+};
+use once_cell::sync::OnceCell;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use yaml_rust::{Yaml, YamlEmitter};
+
+use crate::engine::{Engine, Undefined};
+use crate::partials::Partials;
+use crate::secret;
+
+/// What a template did while rendered besides substituting plain variables. It is recorded on
+/// the render context of each engine by filters and variable lookups.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    /// Secrets were decrypted or looked up
+    pub sensitive: bool,
+    /// Output depends on more than variables (files, commands and environment)
+    pub volatile: bool,
+}
+
+impl Usage {
+    /// Usage recorded in the registers of a liquid render.
+    pub(crate) fn liquid(runtime: &dyn Runtime) -> std::cell::RefMut<'_, Usage> {
+        runtime.registers().get_mut::<Usage>()
+    }
+
+    /// Update the usage recorded in the context of a Jinja render.
+    pub(crate) fn jinja(state: &minijinja::State, update: impl FnOnce(&mut Usage)) {
+        if let Some(usage) = state.lookup(JinjaUsage::NAME) {
+            if let Some(usage) = usage.downcast_object_ref::<JinjaUsage>() {
+                update(&mut usage.0.lock().expect("poisoned"));
+            }
+        }
+    }
+}
+
+/// Usage of a Jinja render, put in its context as [`JinjaUsage::NAME`].
+#[derive(Debug, Default, Clone)]
+pub(crate) struct JinjaUsage(pub(crate) Arc<Mutex<Usage>>);
+
+impl JinjaUsage {
+    pub(crate) const NAME: &'static str = "__dotman_usage";
+}
+
+impl fmt::Display for JinjaUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("usage")
+    }
+}
+
+impl minijinja::value::Object for JinjaUsage {}
+
+/// State shared by dotman filters during a run. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct Filters {
+    base: PathBuf,
+    dryrun: bool,
+    keyring: secret::Keyring,
+    partials: Partials,
+    engine: Engine,
+    commands: Arc<Mutex<HashMap<String, String>>>,
+    parser: Arc<OnceCell<crate::engine::LiquidParser>>,
+    jinja: Arc<OnceCell<minijinja::Environment<'static>>>,
+}
+
+impl fmt::Debug for Filters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Filters")
+            .field("base", &self.base)
+            .field("dryrun", &self.dryrun)
+            .field("keyring", &self.keyring)
+            .field("partials", &self.partials)
+            .field("engine", &self.engine)
+            .finish()
+    }
+}
+
+impl Filters {
+    /// `base` is the directory `read_file` and `command_output` are resolved from.
+    pub fn new(base: PathBuf, dryrun: bool, keyring: secret::Keyring) -> Self {
+        Self {
+            base,
+            dryrun,
+            keyring,
+            partials: Partials::default(),
+            engine: Engine::default(),
+            commands: Arc::new(Mutex::new(HashMap::new())),
+            parser: Arc::new(OnceCell::new()),
+            jinja: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Make `partials` available to `{% include %}`.
+    pub fn with_partials(self, partials: Partials) -> Self {
+        Self {
+            partials,
+            parser: Arc::new(OnceCell::new()),
+            jinja: Arc::new(OnceCell::new()),
+            ..self
+        }
+    }
+
+    /// Render templates with `engine` unless overridden (e.g. by `cp.templates[].engine`).
+    pub fn with_engine(self, engine: Engine) -> Self {
+        Self { engine, ..self }
+    }
+
+    /// Default template engine.
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /// Partials available to `{% include %}`.
+    pub fn partials(&self) -> &Partials {
+        &self.partials
+    }
+
+    /// Parser built once per run by [`crate::util::parser`].
+    pub(crate) fn parser_cell(&self) -> &OnceCell<crate::engine::LiquidParser> {
+        &self.parser
+    }
+
+    /// Jinja environment built once per run by [`crate::engine::Jinja`].
+    pub(crate) fn jinja_cell(&self) -> &OnceCell<minijinja::Environment<'static>> {
+        &self.jinja
+    }
+
+    /// Keyring to decrypt secrets.
+    pub fn keyring(&self) -> &secret::Keyring {
+        &self.keyring
+    }
+
+    /// Register dotman filters to liquid `language`.
+    pub fn register(&self, language: &mut Language) {
+        let filters: [Box<dyn ParseFilter>; 13] = [
+            Box::new(ExpandHome),
+            Box::new(ShellQuote),
+            Box::new(Sha256),
+            Box::new(Base64),
+            Box::new(ToJson),
+            Box::new(ToYaml),
+            Box::new(ToToml),
+            Box::new(DefaultEnv),
+            Box::new(DefaultValue),
+            Box::new(PathJoinParser),
+            Box::new(ReadFileParser {
+                base: self.base.clone(),
+            }),
+            Box::new(CommandOutputParser {
+                filters: self.clone(),
+            }),
+            secret::filter(&self.keyring),
+        ];
+        for filter in filters {
+            language
+                .filters
+                .register(filter.reflection().name().to_owned(), filter);
+        }
+    }
+
+    /// Register dotman filters to Jinja `env`.
+    pub fn register_jinja(&self, env: &mut minijinja::Environment<'static>) {
+        fn failed<E: fmt::Display>(filter: &str, e: E) -> minijinja::Error {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("{}: {}", filter, e),
+            )
+        }
+        env.add_filter("expand_home", |input: String| {
+            expand_home(&input).map_err(|e| failed("expand_home", e))
+        });
+        env.add_filter("shell_quote", |input: String| shell_quote(&input));
+        env.add_filter("sha256", |input: String| sha256(&input));
+        env.add_filter("base64", |input: String| base64(&input));
+        env.add_filter("to_json", |input: minijinja::Value| {
+            to_json(&input).map_err(|e| failed("to_json", e))
+        });
+        env.add_filter("to_yaml", |input: minijinja::Value| {
+            to_yaml(&input).map_err(|e| failed("to_yaml", e))
+        });
+        env.add_filter("to_toml", |input: minijinja::Value| {
+            to_toml(&input).map_err(|e| failed("to_toml", e))
+        });
+        env.add_filter(
+            "default_env",
+            |state: &minijinja::State, name: String, default: minijinja::Value| {
+                Usage::jinja(state, |usage| usage.volatile = true);
+                match std::env::var(name) {
+                    Ok(value) => minijinja::Value::from(value),
+                    Err(_) => default,
+                }
+            },
+        );
+        env.add_filter(
+            "path_join",
+            |input: String, components: minijinja::value::Rest<String>| {
+                path_join(&input, components.iter())
+            },
+        );
+        let base = self.base.clone();
+        env.add_filter(
+            "read_file",
+            move |state: &minijinja::State, path: String| {
+                Usage::jinja(state, |usage| usage.volatile = true);
+                read_file(&base, &path).map_err(|e| failed("read_file", e))
+            },
+        );
+        let filters = self.clone();
+        env.add_filter(
+            "command_output",
+            move |state: &minijinja::State, cmd: String| {
+                Usage::jinja(state, |usage| usage.volatile = true);
+                filters
+                    .command_output(cmd)
+                    .map_err(|e| failed("command_output", e))
+            },
+        );
+        let keyring = self.keyring.clone();
+        env.add_filter("secret", move |state: &minijinja::State, name: String| {
+            Usage::jinja(state, |usage| usage.sensitive = true);
+            keyring.lookup(&name).map_err(|e| failed("secret", e))
+        });
+    }
+
+    /// Output of `cmd`, run once per run and never in dry-run.
+    fn command_output(&self, cmd: String) -> Result<String, String> {
+        if let Some(output) = self.commands.lock().expect("poisoned").get(&cmd) {
+            return Ok(output.clone());
+        }
+        // commands may have side effects
+        if self.dryrun {
+            return Ok(format!("<output of {}>", cmd));
+        }
+        let output = command_output(&self.base, &cmd)?;
+        self.commands
+            .lock()
+            .expect("poisoned")
+            .insert(cmd, output.clone());
+        Ok(output)
+    }
+}
+
+fn expand_home(input: &str) -> Result<String, std::env::VarError> {
+    match input.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            Ok(format!("{}{}", std::env::var("HOME")?, rest))
+        }
+        _ => Ok(input.to_owned()),
+    }
+}
+
+fn sha256(input: &str) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(input.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+fn base64(input: &str) -> String {
+    base64::engine::general_purpose::STANDARD.encode(input.as_bytes())
+}
+
+fn to_json<T: serde::Serialize>(input: &T) -> Result<String, serde_json::Error> {
+    serde_json::to_string(input)
+}
+
+fn to_yaml<T: serde::Serialize>(input: &T) -> Result<String, String> {
+    let json = serde_json::to_value(input).map_err(|e| e.to_string())?;
+    let mut out = String::new();
+    YamlEmitter::new(&mut out)
+        .dump(&json_to_yaml(json))
+        .map_err(|e| format!("{:?}", e))?;
+    Ok(out.strip_prefix("---\n").unwrap_or(&out).to_owned())
+}
+
+fn to_toml<T: serde::Serialize>(input: &T) -> Result<String, String> {
+    let json = serde_json::to_value(input).map_err(|e| e.to_string())?;
+    toml::to_string(&json).map_err(|e| e.to_string())
+}
+
+fn path_join<S: AsRef<str>>(input: &str, components: impl Iterator<Item = S>) -> String {
+    let mut path = PathBuf::from(input);
+    for component in components {
+        path.push(component.as_ref());
+    }
+    path.to_string_lossy().into_owned()
+}
+
+fn read_file(base: &Path, path: &str) -> Result<String, String> {
+    let path = base.join(path);
+    fs::read_to_string(&path).map_err(|e| format!("cannot read {:?} due to {}", path, e))
+}
+
+fn error<E: fmt::Display>(filter: &str, e: E) -> liquid::Error {
+    liquid::Error::with_msg(format!("{}: {}", filter, e))
+}
+
+fn no_arguments(filter: &str, mut arguments: FilterArguments) -> liquid_core::Result<()> {
+    if arguments.positional.next().is_some() || arguments.keyword.next().is_some() {
+        return Err(liquid::Error::with_msg(format!(
+            "{} filter takes no arguments",
+            filter
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "expand_home",
+    description = "Replaces leading `~` with $HOME.",
+    parsed(ExpandHomeFilter)
+)]
+struct ExpandHome;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "expand_home"]
+struct ExpandHomeFilter;
+
+impl Filter for ExpandHomeFilter {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        _runtime: &dyn Runtime,
+    ) -> liquid_core::Result<Value> {
+        expand_home(&input.to_kstr())
+            .map(Value::scalar)
+            .map_err(|e| error("expand_home", e))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "shell_quote",
+    description = "Quotes the input for POSIX shell.",
+    parsed(ShellQuoteFilter)
+)]
+struct ShellQuote;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "shell_quote"]
+struct ShellQuoteFilter;
+
+fn shell_quote(src: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !src.is_empty() && src.chars().all(safe) {
+        src.to_owned()
+    } else {
+        format!("'{}'", src.replace('\'', r"'\''"))
+    }
+}
+
+impl Filter for ShellQuoteFilter {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        _runtime: &dyn Runtime,
+    ) -> liquid_core::Result<Value> {
+        Ok(Value::scalar(shell_quote(&input.to_kstr())))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "sha256",
+    description = "Hex encoded SHA-256 digest of the input.",
+    parsed(Sha256Filter)
+)]
+struct Sha256;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "sha256"]
+struct Sha256Filter;
+
+impl Filter for Sha256Filter {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        _runtime: &dyn Runtime,
+    ) -> liquid_core::Result<Value> {
+        Ok(Value::scalar(sha256(&input.to_kstr())))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "base64",
+    description = "Base64 encodes the input.",
+    parsed(Base64Filter)
+)]
+struct Base64;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "base64"]
+struct Base64Filter;
+
+impl Filter for Base64Filter {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        _runtime: &dyn Runtime,
+    ) -> liquid_core::Result<Value> {
+        Ok(Value::scalar(base64(&input.to_kstr())))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "to_json",
+    description = "Serializes the input as JSON.",
+    parsed(ToJsonFilter)
+)]
+struct ToJson;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "to_json"]
+struct ToJsonFilter;
+
+impl Filter for ToJsonFilter {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        _runtime: &dyn Runtime,
+    ) -> liquid_core::Result<Value> {
+        to_json(&input.to_value())
+            .map(Value::scalar)
+            .map_err(|e| error("to_json", e))
+    }
+}
+
+fn json_to_yaml(json: serde_json::Value) -> Yaml {
+    match json {
+        serde_json::Value::Null => Yaml::Null,
+        serde_json::Value::Bool(b) => Yaml::Boolean(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Yaml::Integer(i),
+            None => Yaml::Real(n.to_string()),
+        },
+        serde_json::Value::String(s) => Yaml::String(s),
+        serde_json::Value::Array(arr) => Yaml::Array(arr.into_iter().map(json_to_yaml).collect()),
+        serde_json::Value::Object(obj) => Yaml::Hash(
+            obj.into_iter()
+                .map(|(key, value)| (Yaml::String(key), json_to_yaml(value)))
+                .collect(),
+        ),
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "to_yaml",
+    description = "Serializes the input as YAML.",
+    parsed(ToYamlFilter)
+)]
+struct ToYaml;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "to_yaml"]
+struct ToYamlFilter;
+
+impl Filter for ToYamlFilter {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        _runtime: &dyn Runtime,
+    ) -> liquid_core::Result<Value> {
+        to_yaml(&input.to_value())
+            .map(Value::scalar)
+            .map_err(|e| error("to_yaml", e))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "to_toml",
+    description = "Serializes the input (must be an object) as TOML.",
+    parsed(ToTomlFilter)
+)]
+struct ToToml;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "to_toml"]
+struct ToTomlFilter;
+
+impl Filter for ToTomlFilter {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        _runtime: &dyn Runtime,
+    ) -> liquid_core::Result<Value> {
+        to_toml(&input.to_value())
+            .map(Value::scalar)
+            .map_err(|e| error("to_toml", e))
+    }
+}
+
+#[derive(Debug, FilterParameters)]
+struct DefaultEnvArgs {
+    #[parameter(description = "The value used when the variable is not set.")]
+    default: Expression,
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "default_env",
+    description = "Looks up the environment variable named by the input, or returns the default.",
+    parameters(DefaultEnvArgs),
+    parsed(DefaultEnvFilter)
+)]
+struct DefaultEnv;
+
+#[derive(Debug, FromFilterParameters, Display_filter)]
+#[name = "default_env"]
+struct DefaultEnvFilter {
+    #[parameters]
+    args: DefaultEnvArgs,
+}
+
+impl Filter for DefaultEnvFilter {
+    fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> liquid_core::Result<Value> {
+        Usage::liquid(runtime).volatile = true;
+        let args = self.args.evaluate(runtime)?;
+        match std::env::var(input.to_kstr().as_str()) {
+            Ok(value) => Ok(Value::scalar(value)),
+            Err(_) => Ok(args.default.to_value()),
+        }
+    }
+}
+
+#[derive(Debug, FilterParameters)]
+struct DefaultValueArgs {
+    #[parameter(description = "The default value.")]
+    default: Expression,
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "default",
+    description = "Sets a default value for the given input, which may be undefined.",
+    parameters(DefaultValueArgs),
+    parsed(DefaultValueFilter)
+)]
+struct DefaultValue;
+
+#[derive(Debug, FromFilterParameters, Display_filter)]
+#[name = "default"]
+struct DefaultValueFilter {
+    #[parameters]
+    args: DefaultValueArgs,
+}
+
+impl Filter for DefaultValueFilter {
+    fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> liquid_core::Result<Value> {
+        if !input.query_state(State::DefaultValue) {
+            return Ok(input.to_value());
+        }
+        // the input falls back, so it is fine to be undefined
+        runtime.registers().get_mut::<Undefined>().0 = None;
+        Ok(self.args.evaluate(runtime)?.default.to_value())
+    }
+}
+
+#[derive(Clone)]
+struct PathJoinParser;
+
+impl FilterReflection for PathJoinParser {
+    fn name(&self) -> &str {
+        "path_join"
+    }
+
+    fn description(&self) -> &str {
+        "Joins path components to the input."
+    }
+
+    fn positional_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+
+    fn keyword_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+}
+
+impl ParseFilter for PathJoinParser {
+    fn parse(&self, mut arguments: FilterArguments) -> liquid_core::Result<Box<dyn Filter>> {
+        if arguments.keyword.next().is_some() {
+            return Err(liquid::Error::with_msg(
+                "path_join filter takes no keyword arguments",
+            ));
+        }
+        Ok(Box::new(PathJoinFilter {
+            components: arguments.positional.collect(),
+        }))
+    }
+
+    fn reflection(&self) -> &dyn FilterReflection {
+        self
+    }
+}
+
+#[derive(Debug)]
+struct PathJoinFilter {
+    components: Vec<Expression>,
+}
+
+impl fmt::Display for PathJoinFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("path_join")
+    }
+}
+
+impl Filter for PathJoinFilter {
+    fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> liquid_core::Result<Value> {
+        let components = self
+            .components
+            .iter()
+            .map(|component| Ok(component.evaluate(runtime)?.to_kstr().into_owned()))
+            .collect::<liquid_core::Result<Vec<_>>>()?;
+        Ok(Value::scalar(path_join(
+            &input.to_kstr(),
+            components.iter(),
+        )))
+    }
+}
+
+#[derive(Clone)]
+struct ReadFileParser {
+    base: PathBuf,
+}
+
+impl FilterReflection for ReadFileParser {
+    fn name(&self) -> &str {
+        "read_file"
+    }
+
+    fn description(&self) -> &str {
+        "Reads the file at the input path relative to the playbook."
+    }
+
+    fn positional_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+
+    fn keyword_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+}
+
+impl ParseFilter for ReadFileParser {
+    fn parse(&self, arguments: FilterArguments) -> liquid_core::Result<Box<dyn Filter>> {
+        no_arguments("read_file", arguments)?;
+        Ok(Box::new(ReadFileFilter {
+            base: self.base.clone(),
+        }))
+    }
+
+    fn reflection(&self) -> &dyn FilterReflection {
+        self
+    }
+}
+
+#[derive(Debug)]
+struct ReadFileFilter {
+    base: PathBuf,
+}
+
+impl fmt::Display for ReadFileFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("read_file")
+    }
+}
+
+impl Filter for ReadFileFilter {
+    fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> liquid_core::Result<Value> {
+        Usage::liquid(runtime).volatile = true;
+        read_file(&self.base, &input.to_kstr())
+            .map(Value::scalar)
+            .map_err(|e| error("read_file", e))
+    }
+}
+
+#[derive(Clone)]
+struct CommandOutputParser {
+    filters: Filters,
+}
+
+impl FilterReflection for CommandOutputParser {
+    fn name(&self) -> &str {
+        "command_output"
+    }
+
+    fn description(&self) -> &str {
+        "Stdout of the input command run by sh. Not executed in dry-run."
+    }
+
+    fn positional_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+
+    fn keyword_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+}
+
+impl ParseFilter for CommandOutputParser {
+    fn parse(&self, arguments: FilterArguments) -> liquid_core::Result<Box<dyn Filter>> {
+        no_arguments("command_output", arguments)?;
+        Ok(Box::new(CommandOutputFilter {
+            filters: self.filters.clone(),
+        }))
+    }
+
+    fn reflection(&self) -> &dyn FilterReflection {
+        self
+    }
+}
+
+#[derive(Debug)]
+struct CommandOutputFilter {
+    filters: Filters,
+}
+
+impl fmt::Display for CommandOutputFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("command_output")
+    }
+}
+
+fn command_output(base: &Path, cmd: &str) -> Result<String, String> {
+    let mut command = process::Command::new("sh");
+    command.arg("-c").arg(cmd).stdin(process::Stdio::null());
+    if !base.as_os_str().is_empty() {
+        command.current_dir(base);
+    }
+    let output = command
+        .output()
+        .map_err(|e| format!("cannot run {} due to {}", cmd, e))?;
+    if !output.status.success() {
+        return Err(format!("{} exited with {}", cmd, output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches('\n')
+        .to_owned())
+}
+
+impl Filter for CommandOutputFilter {
+    fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> liquid_core::Result<Value> {
+        Usage::liquid(runtime).volatile = true;
+        self.filters
+            .command_output(input.to_kstr().to_string())
+            .map(Value::scalar)
+            .map_err(|e| error("command_output", e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::TemplateEngine;
+
+    fn render(filters: &Filters, src: &str, vars: &liquid::Object) -> String {
+        crate::engine::Liquid
+            .render(src, vars, filters)
+            .unwrap()
+            .text
+    }
+
+    #[test]
+    fn test_filters() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-filters-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("aliases"), "st = status").unwrap();
+        let filters = Filters::new(dir.clone(), false, secret::Keyring::default());
+        let vars = liquid::object!({
+            "font": "Hack Nerd Font",
+            "monitors": ["DP-1", "HDMI-1"],
+            "git": { "name": "foo", "size": 11 },
+        });
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            render(&filters, r#"{{ "~/.config" | expand_home }}"#, &vars),
+            format!("{}/.config", home)
+        );
+        assert_eq!(
+            render(&filters, "{{ font | shell_quote }}", &vars),
+            "'Hack Nerd Font'"
+        );
+        assert_eq!(
+            render(&filters, r#"{{ "it's" | shell_quote }}"#, &vars),
+            r"'it'\''s'"
+        );
+        assert_eq!(
+            render(&filters, r#"{{ "abc" | sha256 }}"#, &vars),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(render(&filters, r#"{{ "abc" | base64 }}"#, &vars), "YWJj");
+        assert_eq!(
+            render(&filters, "{{ monitors | to_json }}", &vars),
+            r#"["DP-1","HDMI-1"]"#
+        );
+        assert_eq!(
+            render(&filters, "{{ git | to_yaml }}", &vars),
+            "name: foo\nsize: 11"
+        );
+        assert_eq!(
+            render(&filters, "{{ git | to_toml }}", &vars),
+            "name = \"foo\"\nsize = 11\n"
+        );
+        assert_eq!(
+            render(&filters, r#"{{ "aliases" | read_file }}"#, &vars),
+            "st = status"
+        );
+        assert_eq!(
+            render(
+                &filters,
+                r#"{{ "/etc" | path_join: "dotman", "key" }}"#,
+                &vars
+            ),
+            "/etc/dotman/key"
+        );
+        assert_eq!(
+            render(
+                &filters,
+                r#"{{ "DOTMAN_TEST_UNSET_VAR" | default_env: "vi" }}"#,
+                &vars
+            ),
+            "vi"
+        );
+        assert_eq!(
+            render(&filters, r#"{{ "echo hello" | command_output }}"#, &vars),
+            "hello"
+        );
+        let dryrun = Filters::new(dir.clone(), true, secret::Keyring::default());
+        assert_eq!(
+            render(&dryrun, r#"{{ "touch ran" | command_output }}"#, &vars),
+            "<output of touch ran>"
+        );
+        assert!(!dir.join("ran").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+//! Host inventory mapping machines to scenarios and variables.
+//!
+//! ```yaml
+//! hosts:
+//!   sakanainu:
+//!     aliases: [sakanainu.local]
+//!     machine_id: 0123456789abcdef0123456789abcdef
+//!     groups: [laptop, wayland]
+//!     vars: { font_size: 12 }
+//!     scenarios: [sakanainu, sakanainu-root]
+//! ```
+use std::fmt;
+use std::path::Path;
+
+use crate::{ast, vars, Error};
+
+/// Entry of inventory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Host {
+    name: String,
+    aliases: Vec<String>,
+    machine_id: Option<String>,
+    groups: Vec<String>,
+    vars: vars::Vars,
+    scenarios: Vec<String>,
+}
+
+/// How the host entry was selected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchedBy {
+    HostName,
+    Alias(String),
+    MachineId,
+}
+
+impl fmt::Display for MatchedBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchedBy::HostName => f.write_str("hostname"),
+            MatchedBy::Alias(alias) => write!(f, "alias {}", alias),
+            MatchedBy::MachineId => f.write_str("machine-id"),
+        }
+    }
+}
+
+/// Compiled inventory.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Inventory {
+    hosts: Vec<Host>,
+}
+
+impl Host {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    pub fn vars(&self) -> &vars::Vars {
+        &self.vars
+    }
+
+    pub fn scenarios(&self) -> &[String] {
+        &self.scenarios
+    }
+
+    /// Build `inventory` object exposed to templates.
+    pub fn to_liquid(&self) -> liquid::Object {
+        let mut obj = liquid::Object::new();
+        obj.insert(
+            "name".into(),
+            liquid::model::Value::scalar(self.name.clone()),
+        );
+        obj.insert(
+            "groups".into(),
+            liquid::model::Value::Array(
+                self.groups
+                    .iter()
+                    .map(|group| liquid::model::Value::scalar(group.clone()))
+                    .collect(),
+            ),
+        );
+        obj
+    }
+}
+
+fn parse_strings(yaml: Option<&ast::Value>, prefix: &str) -> Result<Vec<String>, Error> {
+    match yaml {
+        None => Ok(Vec::new()),
+        Some(ast::Value::Array(arr)) => arr
+            .iter()
+            .map(|s| {
+                s.as_str().map(|s| s.to_owned()).ok_or_else(|| {
+                    Error::InvalidPlaybook(
+                        format!("{} must be array of string", prefix),
+                        s.to_owned(),
+                    )
+                })
+            })
+            .collect(),
+        Some(invalid) => Err(Error::InvalidPlaybook(
+            format!("{} must be array of string", prefix),
+            invalid.to_owned(),
+        )),
+    }
+}
+
+fn parse_host(name: &str, yaml: &ast::Value) -> Result<Host, Error> {
+    let obj = yaml.as_hash().ok_or_else(|| {
+        Error::InvalidPlaybook("inventory.hosts.* must be hash".to_owned(), yaml.to_owned())
+    })?;
+    ast::verify_hash(
+        obj,
+        &["aliases", "machine_id", "groups", "vars", "scenarios"],
+        Some("inventory.hosts"),
+    )?;
+    let machine_id = obj
+        .get("machine_id")
+        .map(|id| {
+            id.as_str().map(|s| s.to_owned()).ok_or_else(|| {
+                Error::InvalidPlaybook(
+                    "inventory.hosts.machine_id must be string".to_owned(),
+                    id.to_owned(),
+                )
+            })
+        })
+        .transpose()?;
+    let vars = obj
+        .get("vars")
+        .map(|vars| vars::parse(vars, "inventory.hosts.vars"))
+        .transpose()?
+        .unwrap_or_default();
+    Ok(Host {
+        name: name.to_owned(),
+        aliases: parse_strings(obj.get("aliases"), "inventory.hosts.aliases")?,
+        machine_id,
+        groups: parse_strings(obj.get("groups"), "inventory.hosts.groups")?,
+        vars,
+        scenarios: parse_strings(obj.get("scenarios"), "inventory.hosts.scenarios")?,
+    })
+}
+
+/// parse inventory document.
+pub fn parse(yaml: &ast::Value) -> Result<Inventory, Error> {
+    let obj = yaml.as_hash().ok_or_else(|| {
+        Error::InvalidPlaybook("inventory must be hash".to_owned(), yaml.to_owned())
+    })?;
+    ast::verify_hash(obj, &["hosts"], Some("inventory"))?;
+    let mut hosts = obj
+        .get("hosts")
+        .map(|hosts| {
+            hosts.as_hash().ok_or_else(|| {
+                Error::InvalidPlaybook("inventory.hosts must be hash".to_owned(), hosts.to_owned())
+            })
+        })
+        .transpose()?
+        .map(|hosts| {
+            hosts
+                .iter()
+                .map(|(name, host)| parse_host(name, host))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    hosts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Inventory { hosts })
+}
+
+/// Load inventory file (yaml, json or toml).
+pub fn load(path: &Path) -> Result<Inventory, Error> {
+    parse(&vars::load_document(path)?)
+}
+
+impl Inventory {
+    /// Find the host entry by hostname, its aliases or `/etc/machine-id`.
+    pub fn find(&self, hostname: &str, machine_id: Option<&str>) -> Option<(&Host, MatchedBy)> {
+        self.hosts.iter().find_map(|host| {
+            if host.name == hostname {
+                Some((host, MatchedBy::HostName))
+            } else if let Some(alias) = host.aliases.iter().find(|alias| *alias == hostname) {
+                Some((host, MatchedBy::Alias(alias.to_owned())))
+            } else if machine_id.is_some() && host.machine_id.as_deref() == machine_id {
+                Some((host, MatchedBy::MachineId))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_find_host() {
+        let src = concat!(
+            "---\n",
+            "hosts:\n",
+            "  sakanainu:\n",
+            "    aliases: [sakanainu.local]\n",
+            "    groups: [laptop]\n",
+            "    scenarios: [sakanainu, sakanainu-root]\n",
+            "  yatsugatake:\n",
+            "    machine_id: 0123abcd\n",
+            "    vars: { font_size: 12 }\n",
+        );
+        let yaml = YamlLoader::load_from_str(src).unwrap();
+        let inventory = parse(&ast::Value::from_yaml(yaml[0].clone()).unwrap()).unwrap();
+        let (host, matched_by) = inventory.find("sakanainu", None).unwrap();
+        assert_eq!(host.name(), "sakanainu");
+        assert_eq!(matched_by, MatchedBy::HostName);
+        assert_eq!(host.scenarios(), &["sakanainu", "sakanainu-root"]);
+        let (host, matched_by) = inventory.find("sakanainu.local", None).unwrap();
+        assert_eq!(host.name(), "sakanainu");
+        assert_eq!(matched_by, MatchedBy::Alias("sakanainu.local".to_owned()));
+        let (host, matched_by) = inventory.find("localhost", Some("0123abcd")).unwrap();
+        assert_eq!(host.name(), "yatsugatake");
+        assert_eq!(matched_by, MatchedBy::MachineId);
+        assert_eq!(host.vars().get("font_size"), Some(&ast::Value::Int(12)));
+        assert!(inventory.find("localhost", None).is_none());
+    }
+}
@@ -1,4 +1,5 @@
 use futures::stream::StreamExt;
+use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::ffi::OsString;
 use std::path::PathBuf;
@@ -10,11 +11,24 @@ use tokio::sync::{Mutex, RwLock};
 use yaml_rust::YamlLoader;
 
 pub mod ast;
+pub mod backup;
+pub mod engine;
+pub mod facts;
+pub mod filters;
+pub mod inventory;
+pub mod partials;
+pub mod secret;
+pub mod state;
 pub mod tasks;
 pub mod util;
+pub mod vars;
 
 use thiserror::Error;
 
+// file modes, ownership, symlinks and secrets written with mode 0600 rely on Unix APIs
+#[cfg(not(unix))]
+compile_error!("dotman supports only Unix-like systems");
+
 #[async_trait::async_trait]
 /// The trait of Task
 pub trait Task {
@@ -22,12 +36,80 @@ pub trait Task {
     fn name(&self) -> String;
     /// execute with context.
     async fn execute(&self, ctx: &TaskContext) -> TaskResult;
+    /// Render source file `path` as `execute` would deploy it, without deploying.
+    /// `None` if this task does not deploy `path`.
+    async fn preview(
+        &self,
+        _ctx: &TaskContext,
+        _path: &Path,
+    ) -> Option<Result<Preview, TaskError>> {
+        None
+    }
+}
+
+/// Content a task would deploy, reported by `dotman render`
+#[derive(Debug)]
+pub struct Preview {
+    /// Destination of the content
+    pub dest: PathBuf,
+    /// Rendered (and decrypted) content
+    pub content: Vec<u8>,
+    /// Whether the content contains secrets
+    pub sensitive: bool,
+}
+
+impl Preview {
+    /// Text to show: the content, or its difference from the current destination by `diff -u`
+    /// if `diff`. Sensitive content is redacted unless `show_secrets`.
+    pub fn output(&self, diff: bool, show_secrets: bool) -> Result<Vec<u8>, Error> {
+        let redact = self.sensitive && !show_secrets;
+        if !diff {
+            return Ok(if redact {
+                secret::redact(&self.content)
+            } else {
+                self.content.clone()
+            });
+        }
+        let failed =
+            |e: std::io::Error| Error::CannotRender(format!("cannot run diff due to {:?}", e));
+        let dest = if self.dest.exists() {
+            self.dest.clone()
+        } else {
+            PathBuf::from("/dev/null")
+        };
+        let mut child = std::process::Command::new("diff")
+            .arg("-u")
+            .arg("--label")
+            .arg(self.dest.display().to_string())
+            .arg("--label")
+            .arg("rendered")
+            .arg(&dest)
+            .arg("-")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .map_err(failed)?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // feed the content while reading the difference, which may be as large as the content
+        let output = std::thread::scope(|scope| {
+            let writer = scope.spawn(move || std::io::Write::write_all(&mut stdin, &self.content));
+            let output = child.wait_with_output();
+            writer.join().expect("writer does not panic").and(output)
+        })
+        .map_err(failed)?;
+        Ok(if redact {
+            secret::redact_diff(&output.stdout)
+        } else {
+            output.stdout
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 enum TargetMatcher {
     HostName(String, Regex),
     Root(bool),
+    Fact(String, String, Regex),
 }
 
 impl PartialEq for TargetMatcher {
@@ -35,6 +117,7 @@ impl PartialEq for TargetMatcher {
         match (self, other) {
             (Self::HostName(x, _), Self::HostName(y, _)) => x == y,
             (Self::Root(x), Self::Root(y)) => x == y,
+            (Self::Fact(x_path, x, _), Self::Fact(y_path, y, _)) => x_path == y_path && x == y,
             _ => false,
         }
     }
@@ -45,6 +128,8 @@ struct Scenario {
     name: String,
     tasks: Vec<String>,
     matches: Vec<TargetMatcher>,
+    vars: vars::Vars,
+    vars_files: Vec<vars::VarsFile>,
 }
 
 pub enum TaskEntity {
@@ -82,10 +167,32 @@ impl Task for TaskEntity {
             Self::Brew(task) => task.execute(ctx).await,
         }
     }
+
+    async fn preview(&self, ctx: &TaskContext, path: &Path) -> Option<Result<Preview, TaskError>> {
+        match self {
+            Self::Cargo(task) => task.preview(ctx, path).await,
+            Self::Cp(task) => task.preview(ctx, path).await,
+            Self::Env(task) => task.preview(ctx, path).await,
+            Self::Link(task) => task.preview(ctx, path).await,
+            Self::Sh(task) => task.preview(ctx, path).await,
+            Self::Wget(task) => task.preview(ctx, path).await,
+            Self::Brew(task) => task.preview(ctx, path).await,
+        }
+    }
+}
+
+/// `when` matchers of a task, all of which must match for the task to run
+#[derive(Debug, Default)]
+pub struct Condition(Vec<TargetMatcher>);
+
+impl Condition {
+    fn matches(&self, node_info: &NodeInformation, facts: &liquid::Object) -> bool {
+        matchers_match(&self.0, node_info, facts, true)
+    }
 }
 
-pub type TaskGroups = HashMap<String, Vec<(String, TaskEntity)>>;
-pub type ScheduledTasks<'a> = Vec<(&'a str, &'a [(String, TaskEntity)])>;
+pub type TaskGroups = HashMap<String, Vec<(String, TaskEntity, Condition)>>;
+pub type ScheduledTasks<'a> = Vec<(&'a str, Vec<&'a (String, TaskEntity, Condition)>)>;
 
 /// Compiled configuration
 pub struct PlayBook {
@@ -94,6 +201,22 @@ pub struct PlayBook {
     task_ids: Vec<String>,
     serialize_ids: Vec<String>,
     scenarios: Vec<Scenario>,
+    facts: Vec<facts::FactDefinition>,
+    /// Facts evaluated by the last run, read from the task cache
+    last_facts: liquid::Object,
+    /// Node information including facts, collected once per run
+    node_info: OnceCell<NodeInformation>,
+    vars: vars::Vars,
+    vars_files: Vec<vars::VarsFile>,
+    taskgroup_vars: HashMap<String, vars::Vars>,
+    extra_vars: vars::Vars,
+    inventory: inventory::Inventory,
+    keyring: secret::Keyring,
+    partials: partials::Partials,
+    engine: engine::Engine,
+    strict: bool,
+    backup: bool,
+    full: bool,
 }
 
 impl fmt::Debug for PlayBook {
@@ -109,7 +232,7 @@ impl fmt::Debug for PlayBook {
                             key,
                             tasks
                                 .iter()
-                                .map(|(_, task)| task.name())
+                                .map(|(_, task, _)| task.name())
                                 .collect::<Vec<_>>(),
                         )
                     })
@@ -120,6 +243,25 @@ impl fmt::Debug for PlayBook {
     }
 }
 
+/// Result of scenario selection
+struct Selection<'a> {
+    scenario: &'a Scenario,
+    host: Option<(&'a inventory::Host, inventory::MatchedBy)>,
+}
+
+/// Description of how the scenario is selected, reported by `dotman explain`
+#[derive(Debug)]
+pub struct Explanation {
+    /// Collected facts
+    pub facts: liquid::Object,
+    /// Matched inventory entry and how it was matched
+    pub inventory: Option<(String, inventory::MatchedBy)>,
+    /// Selected scenario
+    pub scenario: String,
+    /// Taskgroups of selected scenario
+    pub taskgroups: Vec<String>,
+}
+
 /// execution context to pass to Task
 #[derive(Debug)]
 pub struct TaskContext<'a> {
@@ -127,10 +269,24 @@ pub struct TaskContext<'a> {
     pub base: PathBuf,
     /// Dry-run flag
     pub dryrun: bool,
+    /// Render every template even if dry-run would skip it, to report undefined variables early
+    pub strict: bool,
     /// Selected deploy scenario
     pub scenario: String,
     /// Cache shared between same task type
     pub cache: &'a RwLock<Option<Vec<u8>>>,
+    /// Variables exposed to templates (e.g. `facts` and merged `vars`)
+    pub vars: &'a liquid::Object,
+    /// Variables given by command line which take precedence over any other variables
+    pub overrides: &'a liquid::Object,
+    /// Dotman filters and key to decrypt secrets referred from templates
+    pub filters: &'a filters::Filters,
+    /// Details of changes reported under the task (e.g. metadata-only changes)
+    pub notes: &'a std::sync::Mutex<Vec<String>>,
+    /// Where to save files before overwriting or deleting them (`None` in dry-run)
+    pub backup: Option<&'a backup::Backup>,
+    /// Record of the last deploy to skip unchanged files (`None` if unavailable)
+    pub state: Option<&'a state::State>,
 }
 
 /// Critical errors
@@ -157,6 +313,12 @@ pub enum Error {
     CannotCollectNodeInformation(String),
     /// Failed to load cache
     CannotLoadCache(String),
+    /// Failed to encrypt or decrypt secret
+    CannotProcessSecret(String),
+    /// Failed to render a file with `dotman render`
+    CannotRender(String),
+    /// Failed to list, restore or remove backups
+    CannotProcessBackup(String),
 }
 
 type TaskResult = Result<bool, TaskError>;
@@ -177,13 +339,30 @@ impl From<anyhow::Error> for TaskError {
     }
 }
 
-fn parse_task<T: TaskBuilder>(yaml: &ast::Value) -> Result<(String, TaskEntity), Error> {
-    let obj = yaml
+fn parse_task<T: TaskBuilder>(yaml: &ast::Value) -> Result<(String, TaskEntity, Condition), Error> {
+    let mut obj = yaml
         .as_hash()
-        .ok_or_else(|| Error::InvalidPlaybook("task must be hash".to_owned(), yaml.to_owned()))?;
+        .ok_or_else(|| Error::InvalidPlaybook("task must be hash".to_owned(), yaml.to_owned()))?
+        .clone();
+    // `when` is common to all tasks, so task parsers never see it
+    let condition = match obj.remove("when") {
+        Some(ast::Value::Array(matchers)) => Condition(
+            matchers
+                .iter()
+                .map(parse_matcher)
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        Some(when) => {
+            return Err(Error::InvalidPlaybook(
+                "task.when must be array of matchers".to_owned(),
+                when,
+            ))
+        }
+        None => Condition::default(),
+    };
     if let Some(ast::Value::Str(key)) = obj.get("type") {
-        if let Some(task) = T::parse(key.as_str(), obj) {
-            Ok((key.to_owned(), task?))
+        if let Some(task) = T::parse(key.as_str(), &obj) {
+            Ok((key.to_owned(), task?, condition))
         } else {
             Err(Error::InvalidPlaybook(
                 format!("unsupported task \"{}\"", key.as_str()),
@@ -198,26 +377,51 @@ fn parse_task<T: TaskBuilder>(yaml: &ast::Value) -> Result<(String, TaskEntity),
     }
 }
 
-fn parse_taskgroups<T: TaskBuilder>(yaml: &ast::Value) -> Result<TaskGroups, Error> {
-    yaml.as_hash()
+fn parse_taskgroups<T: TaskBuilder>(
+    yaml: &ast::Value,
+) -> Result<(TaskGroups, HashMap<String, vars::Vars>), Error> {
+    let mut taskgroup_vars = HashMap::new();
+    let taskgroups = yaml
+        .as_hash()
         .ok_or_else(|| {
             Error::InvalidPlaybook("taskgroups must be hash".to_owned(), yaml.to_owned())
         })?
         .iter()
-        .map(|(name, tasks)| match (name, tasks) {
-            (name, ast::Value::Array(tasks)) => Ok((
+        .map(|(name, tasks)| {
+            let tasks = match tasks {
+                ast::Value::Array(tasks) => tasks,
+                ast::Value::Hash(taskgroup) => {
+                    ast::verify_hash(taskgroup, &["vars", "tasks"], Some("taskgroups"))?;
+                    if let Some(vars) = taskgroup.get("vars") {
+                        taskgroup_vars
+                            .insert(name.to_owned(), vars::parse(vars, "taskgroups.vars")?);
+                    }
+                    taskgroup
+                        .get("tasks")
+                        .and_then(|tasks| tasks.as_array())
+                        .ok_or_else(|| {
+                            Error::InvalidPlaybook(
+                                "taskgroups.tasks must be array".to_owned(),
+                                tasks.to_owned(),
+                            )
+                        })?
+                }
+                _ => return Err(Error::InvalidPlaybook(
+                    "children of taskgropus must be [string]: <task>[] or [string]: {vars, tasks}"
+                        .to_owned(),
+                    yaml.to_owned(),
+                )),
+            };
+            Ok((
                 name.to_owned(),
                 tasks
                     .iter()
                     .map(|src| parse_task::<T>(src))
                     .collect::<Result<Vec<_>, Error>>()?,
-            )),
-            _ => Err(Error::InvalidPlaybook(
-                "children of taskgropus must be [string]: <task>[]".to_owned(),
-                yaml.to_owned(),
-            )),
+            ))
         })
-        .collect::<Result<HashMap<_, _>, Error>>()
+        .collect::<Result<HashMap<_, _>, Error>>()?;
+    Ok((taskgroups, taskgroup_vars))
 }
 
 fn parse_matcher(yaml: &ast::Value) -> Result<TargetMatcher, Error> {
@@ -257,6 +461,32 @@ fn parse_matcher(yaml: &ast::Value) -> Result<TargetMatcher, Error> {
                     )
                 })?))
             }
+            "fact" => {
+                ast::verify_hash(obj, &["fact"], Some("matcher"))?;
+                let fact = val.as_hash().ok_or_else(|| {
+                    Error::InvalidPlaybook("matcher.fact must be hash".to_owned(), val.to_owned())
+                })?;
+                ast::verify_hash(fact, &["path", "regex"], Some("matcher.fact"))?;
+                if let (Some(ast::Value::Str(path)), Some(ast::Value::Str(re_src))) =
+                    (fact.get("path"), fact.get("regex"))
+                {
+                    let re = regex::Regex::new(re_src).map_err(|e| {
+                        Error::InvalidPlaybook(
+                            format!(
+                                "cannot compile matcher.fact.regex {} due to {:?}",
+                                re_src, e
+                            ),
+                            val.to_owned(),
+                        )
+                    })?;
+                    Ok(TargetMatcher::Fact(path.to_owned(), re_src.to_owned(), re))
+                } else {
+                    Err(Error::InvalidPlaybook(
+                        "matcher.fact.path and matcher.fact.regex must be string".to_owned(),
+                        val.to_owned(),
+                    ))
+                }
+            }
             matcher_name => Err(Error::InvalidPlaybook(
                 format!("unsupported matcher \"{}\"", matcher_name),
                 yaml.to_owned(),
@@ -275,7 +505,11 @@ fn parse_scenario(yaml: &ast::Value) -> Result<Scenario, Error> {
         Error::InvalidPlaybook("scenario mast be hash".to_owned(), yaml.to_owned())
     })?;
 
-    ast::verify_hash(obj, &["name", "match", "tasks"], Some("scenario"))?;
+    ast::verify_hash(
+        obj,
+        &["name", "match", "tasks", "vars", "vars_files"],
+        Some("scenario"),
+    )?;
     if let (
         Some(ast::Value::Str(name)),
         Some(ast::Value::Array(matchers)),
@@ -303,10 +537,20 @@ fn parse_scenario(yaml: &ast::Value) -> Result<Scenario, Error> {
                 })
             })
             .collect::<Result<Vec<String>, Error>>()?;
+        let vars = obj
+            .get("vars")
+            .map(|vars| vars::parse(vars, "scenario.vars"))
+            .unwrap_or_else(|| Ok(HashMap::new()))?;
+        let vars_files = obj
+            .get("vars_files")
+            .map(|files| vars::parse_files(files, "scenario.vars_files"))
+            .unwrap_or_else(|| Ok(Vec::new()))?;
         Ok(Scenario {
             tasks,
             matches,
             name: name.to_owned(),
+            vars,
+            vars_files,
         })
     } else {
         Err(Error::InvalidPlaybook(
@@ -341,7 +585,9 @@ mod test_parsers {
                     TargetMatcher::HostName("hoge".to_owned(), Regex::new(r"hoge").unwrap()),
                     TargetMatcher::Root(false)
                 ],
-                name: "test_scenario".to_owned()
+                name: "test_scenario".to_owned(),
+                vars: HashMap::new(),
+                vars_files: Vec::new(),
             }
         );
     }
@@ -350,32 +596,107 @@ mod test_parsers {
 struct NodeInformation {
     root: bool,
     hostname: OsString,
+    machine_id: Option<String>,
+    custom: liquid::Object,
 }
 
 impl NodeInformation {
-    fn collect() -> anyhow::Result<Self> {
+    fn collect(
+        facts: &[facts::FactDefinition],
+        base: &Path,
+        partials: &partials::Partials,
+        engine: engine::Engine,
+        last_facts: &liquid::Object,
+    ) -> Result<Self, Error> {
         Ok(Self {
             #[cfg(target_family = "unix")]
             root: unsafe { libc::getuid() == 0 },
             #[cfg(target_family = "windows")]
             root: false,
-            hostname: hostname::get()?,
+            hostname: hostname::get()
+                .map_err(|e| Error::CannotCollectNodeInformation(format!("{:?}", e)))?,
+            machine_id: fs::read_to_string("/etc/machine-id")
+                .ok()
+                .map(|id| id.trim().to_owned()),
+            custom: facts::evaluate(facts, base, partials, engine, last_facts)?,
         })
     }
+
+    /// Build `facts` object exposed to templates and matchers.
+    fn to_liquid(&self) -> liquid::Object {
+        let mut obj = liquid::Object::new();
+        obj.insert(
+            "hostname".into(),
+            liquid::model::Value::scalar(self.hostname.to_string_lossy().into_owned()),
+        );
+        obj.insert("root".into(), liquid::model::Value::scalar(self.root));
+        if let Some(machine_id) = &self.machine_id {
+            obj.insert(
+                "machine_id".into(),
+                liquid::model::Value::scalar(machine_id.clone()),
+            );
+        }
+        obj.insert(
+            "custom".into(),
+            liquid::model::Value::Object(self.custom.clone()),
+        );
+        obj
+    }
+}
+
+fn scenario_matches(
+    scenario: &Scenario,
+    node_info: &NodeInformation,
+    facts: &liquid::Object,
+    check_hostname: bool,
+) -> bool {
+    matchers_match(&scenario.matches, node_info, facts, check_hostname)
+}
+
+fn matchers_match(
+    matchers: &[TargetMatcher],
+    node_info: &NodeInformation,
+    facts: &liquid::Object,
+    check_hostname: bool,
+) -> bool {
+    matchers.iter().all(|matcher| match matcher {
+        TargetMatcher::HostName(_, hostname_re) => {
+            !check_hostname || hostname_re.is_match(&node_info.hostname.to_string_lossy())
+        }
+        TargetMatcher::Root(is_root) => *is_root == node_info.root,
+        TargetMatcher::Fact(path, _, re) => facts::lookup(facts, path)
+            .map(|value| {
+                use liquid::model::ValueView;
+                re.is_match(value.to_kstr().as_str())
+            })
+            .unwrap_or(false),
+    })
 }
 
 fn match_scenario<'a>(
     scenarios: &'a [Scenario],
     node_info: &NodeInformation,
 ) -> Option<&'a Scenario> {
-    scenarios.iter().find(|&scenario| {
-        scenario.matches.iter().all(|matcher| match matcher {
-            TargetMatcher::HostName(_, hostname_re) => {
-                hostname_re.is_match(&node_info.hostname.to_string_lossy())
-            }
-            TargetMatcher::Root(is_root) => *is_root == node_info.root,
-        })
+    let facts = node_info.to_liquid();
+    scenarios
+        .iter()
+        .find(|&scenario| scenario_matches(scenario, node_info, &facts, true))
+}
+
+/// Scenarios assigned by inventory are tried first ignoring hostname matchers.
+fn match_scenario_with_inventory<'a>(
+    scenarios: &'a [Scenario],
+    host: Option<&inventory::Host>,
+    node_info: &NodeInformation,
+) -> Option<&'a Scenario> {
+    let facts = node_info.to_liquid();
+    host.and_then(|host| {
+        host.scenarios()
+            .iter()
+            .filter_map(|name| scenarios.iter().find(|scenario| &scenario.name == name))
+            .find(|scenario| scenario_matches(scenario, node_info, &facts, false))
     })
+    .or_else(|| match_scenario(scenarios, node_info))
 }
 
 #[cfg(test)]
@@ -449,6 +770,8 @@ mod test_matcher {
                 &NodeInformation {
                     hostname: OsString::from("hoge".to_owned()),
                     root: false,
+                    machine_id: None,
+                    custom: liquid::Object::new(),
                 }
             ),
             Some(&nonroot1)
@@ -459,6 +782,8 @@ mod test_matcher {
                 &NodeInformation {
                     hostname: OsString::from("fuga".to_owned()),
                     root: false,
+                    machine_id: None,
+                    custom: liquid::Object::new(),
                 }
             ),
             Some(&nonroot2)
@@ -469,6 +794,8 @@ mod test_matcher {
                 &NodeInformation {
                     hostname: OsString::from("hoge".to_owned()),
                     root: true,
+                    machine_id: None,
+                    custom: liquid::Object::new(),
                 }
             ),
             Some(&root)
@@ -479,23 +806,215 @@ mod test_matcher {
                 &NodeInformation {
                     hostname: OsString::from("bar".to_owned()),
                     root: true,
+                    machine_id: None,
+                    custom: liquid::Object::new(),
                 }
             ),
             None
         );
     }
+
+    #[test]
+    fn test_match_scenario_by_fact() {
+        let src = concat!(
+            "---\n",
+            "name: nvidia\n",
+            "match:\n",
+            "- fact: { path: custom.gpu, regex: ^nvidia$ }\n",
+            "tasks:\n",
+            "- task1\n"
+        );
+        let scenario = parse_scenario(
+            &ast::Value::from_yaml(
+                YamlLoader::load_from_str(src)
+                    .unwrap()
+                    .into_iter()
+                    .next()
+                    .unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let scenarios = vec![scenario.clone()];
+        let mut custom = liquid::Object::new();
+        custom.insert("gpu".into(), liquid::model::Value::scalar("nvidia"));
+        assert_eq!(
+            match_scenario(
+                scenarios.as_slice(),
+                &NodeInformation {
+                    hostname: OsString::from("hoge".to_owned()),
+                    root: false,
+                    machine_id: None,
+                    custom,
+                }
+            ),
+            Some(&scenario)
+        );
+        assert_eq!(
+            match_scenario(
+                scenarios.as_slice(),
+                &NodeInformation {
+                    hostname: OsString::from("hoge".to_owned()),
+                    root: false,
+                    machine_id: None,
+                    custom: liquid::Object::new(),
+                }
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_enlist_tasks_by_when() {
+        struct Builder;
+        impl TaskBuilder for Builder {
+            fn parse(
+                key: &str,
+                hash: &HashMap<String, ast::Value>,
+            ) -> Option<Result<TaskEntity, Error>> {
+                (key == "env").then(|| tasks::env::parse(hash))
+            }
+            fn ids(&self) -> &[&str] {
+                &[]
+            }
+            fn serialize_ids(&self) -> &[&str] {
+                &[]
+            }
+            fn cache(&self, _: &str) -> Option<Vec<u8>> {
+                None
+            }
+        }
+        let src = concat!(
+            "---\n",
+            "common:\n",
+            "- { type: env, envs: { ALWAYS: \"1\" } }\n",
+            "- type: env\n",
+            "  envs: { GPU: nvidia }\n",
+            "  when:\n",
+            "  - fact: { path: custom.gpu, regex: ^nvidia$ }\n",
+        );
+        let yaml = YamlLoader::load_from_str(src).unwrap();
+        let (taskgroups, _) =
+            parse_taskgroups::<Builder>(&ast::Value::from_yaml(yaml[0].clone()).unwrap()).unwrap();
+        let names = vec!["common".to_owned()];
+        let mut custom = liquid::Object::new();
+        custom.insert("gpu".into(), liquid::model::Value::scalar("nvidia"));
+        let mut node_info = NodeInformation {
+            hostname: OsString::from("hoge".to_owned()),
+            root: false,
+            machine_id: None,
+            custom,
+        };
+        assert_eq!(
+            enlist_taskgroups(&taskgroups, &names, &node_info).unwrap()[0]
+                .1
+                .len(),
+            2
+        );
+        node_info.custom = liquid::Object::new();
+        assert_eq!(
+            enlist_taskgroups(&taskgroups, &names, &node_info).unwrap()[0]
+                .1
+                .len(),
+            1
+        );
+
+        let src = "---\ncommon:\n- { type: env, envs: {}, when: true }\n";
+        let yaml = YamlLoader::load_from_str(src).unwrap();
+        assert!(
+            parse_taskgroups::<Builder>(&ast::Value::from_yaml(yaml[0].clone()).unwrap()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_match_scenario_with_inventory() {
+        let parse = |src: &str| {
+            parse_scenario(
+                &ast::Value::from_yaml(
+                    YamlLoader::load_from_str(src)
+                        .unwrap()
+                        .into_iter()
+                        .next()
+                        .unwrap(),
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        let by_hostname = parse(concat!(
+            "---\n",
+            "name: by_hostname\n",
+            "match:\n",
+            "- hostname: ^hoge$\n",
+            "tasks: []\n",
+        ));
+        let user = parse(concat!(
+            "---\n",
+            "name: user\n",
+            "match:\n",
+            "- hostname: ^never$\n",
+            "tasks: []\n",
+        ));
+        let root = parse(concat!(
+            "---\n",
+            "name: root\n",
+            "match:\n",
+            "- root: true\n",
+            "tasks: []\n",
+        ));
+        let inventory = inventory::parse(
+            &ast::Value::from_yaml(
+                YamlLoader::load_from_str("hosts: { hoge: { scenarios: [user, root] } }")
+                    .unwrap()
+                    .into_iter()
+                    .next()
+                    .unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let scenarios = vec![by_hostname.clone(), user.clone(), root.clone()];
+        let node_info = |root| NodeInformation {
+            hostname: OsString::from("hoge".to_owned()),
+            root,
+            machine_id: None,
+            custom: liquid::Object::new(),
+        };
+        let host = inventory.find("hoge", None).map(|(host, _)| host);
+        assert_eq!(
+            match_scenario_with_inventory(&scenarios, host, &node_info(false)),
+            Some(&user)
+        );
+        assert_eq!(
+            match_scenario_with_inventory(&scenarios, host, &node_info(true)),
+            Some(&root)
+        );
+        assert_eq!(
+            match_scenario_with_inventory(&scenarios, None, &node_info(false)),
+            Some(&by_hostname)
+        );
+    }
 }
 
+/// Tasks of `taskgroup_names` whose `when` matches this node.
 fn enlist_taskgroups<'a>(
     taskgroups: &'a TaskGroups,
     taskgroup_names: &'a [String],
+    node_info: &NodeInformation,
 ) -> Result<ScheduledTasks<'a>, Error> {
+    let facts = node_info.to_liquid();
     taskgroup_names
         .iter()
         .map(|taskgroup_name| {
             taskgroups
                 .get(taskgroup_name)
-                .map(|task| (taskgroup_name.as_str(), task.as_slice()))
+                .map(|tasks| {
+                    let tasks = tasks
+                        .iter()
+                        .filter(|(_, _, condition)| condition.matches(node_info, &facts))
+                        .collect();
+                    (taskgroup_name.as_str(), tasks)
+                })
                 .ok_or_else(|| Error::TaskGroupNotFound(taskgroup_name.to_owned()))
         })
         .collect::<Result<Vec<_>, Error>>()
@@ -547,7 +1066,7 @@ impl PlayBook {
                 .map_err(|_| {
                     Error::PlaybookLoadFailed(format!("playbook {} has invalid syntax", config))
                 })?
-                .get(0)
+                .first()
                 .ok_or_else(|| Error::PlaybookLoadFailed(format!("playbook {} is empty", config)))?
                 .clone(),
         )
@@ -555,14 +1074,31 @@ impl PlayBook {
         .as_hash()
         .ok_or_else(|| Error::PlaybookLoadFailed("invalid playbook".to_owned()))?
         .clone();
-        ast::verify_hash(&playbook_ast, &["taskgroups", "scenarios"], None)?;
+        ast::verify_hash(
+            &playbook_ast,
+            &[
+                "taskgroups",
+                "scenarios",
+                "facts",
+                "vars",
+                "vars_files",
+                "inventory",
+                "key_file",
+                "age_identity",
+                "secret_provider",
+                "partials",
+                "template_engine",
+                "backup",
+            ],
+            None,
+        )?;
         let taskgroups = playbook_ast
             .get("taskgroups")
             .ok_or_else(|| Error::PlaybookLoadFailed("taskgroups is not found".to_owned()))?;
         let scenarios = playbook_ast
             .get("scenarios")
             .ok_or_else(|| Error::PlaybookLoadFailed("scenarios is not found".to_owned()))?;
-        let taskgroups = parse_taskgroups::<T>(taskgroups)?;
+        let (taskgroups, taskgroup_vars) = parse_taskgroups::<T>(taskgroups)?;
         let scenarios = scenarios
             .as_array()
             .ok_or_else(|| {
@@ -571,6 +1107,90 @@ impl PlayBook {
             .iter()
             .map(parse_scenario)
             .collect::<Result<Vec<Scenario>, Error>>()?;
+        let facts = playbook_ast
+            .get("facts")
+            .map(facts::parse)
+            .unwrap_or_else(|| Ok(Vec::new()))?;
+        let vars = playbook_ast
+            .get("vars")
+            .map(|vars| vars::parse(vars, "vars"))
+            .unwrap_or_else(|| Ok(HashMap::new()))?;
+        let vars_files = playbook_ast
+            .get("vars_files")
+            .map(|files| vars::parse_files(files, "vars_files"))
+            .unwrap_or_else(|| Ok(Vec::new()))?;
+        let base = Path::new(config)
+            .parent()
+            .ok_or_else(|| Error::PlaybookLoadFailed(format!("cannot take parent of {}", config)))?
+            .to_owned();
+        let inventory = playbook_ast
+            .get("inventory")
+            .map(|path| {
+                path.as_str()
+                    .map(|path| inventory::load(&base.join(path)))
+                    .unwrap_or_else(|| {
+                        Err(Error::InvalidPlaybook(
+                            "inventory must be string".to_owned(),
+                            path.to_owned(),
+                        ))
+                    })
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let key_file = playbook_ast
+            .get("key_file")
+            .map(|path| {
+                path.as_str().map(|path| base.join(path)).ok_or_else(|| {
+                    Error::InvalidPlaybook("key_file must be string".to_owned(), path.to_owned())
+                })
+            })
+            .transpose()?;
+        let keyring = secret::Keyring::new(key_file);
+        let keyring = if let Some(provider) = playbook_ast.get("secret_provider") {
+            keyring.with_provider(secret::parse_provider(provider, &base)?)
+        } else {
+            keyring
+        };
+        let keyring = match playbook_ast.get("age_identity") {
+            Some(path) => keyring.with_age_identity(base.join(path.as_str().ok_or_else(|| {
+                Error::InvalidPlaybook("age_identity must be string".to_owned(), path.to_owned())
+            })?)),
+            None => keyring,
+        };
+        let partials = match playbook_ast.get("partials") {
+            Some(dir) => {
+                let dir = dir.as_str().ok_or_else(|| {
+                    Error::InvalidPlaybook("partials must be string".to_owned(), dir.to_owned())
+                })?;
+                partials::Partials::load(&base.join(dir), true)?
+            }
+            None => partials::Partials::load(&base.join(partials::DEFAULT_DIR), false)?,
+        };
+        partials.verify(&playbook_src)?;
+        let template_sources = taskgroups
+            .values()
+            .flatten()
+            .filter_map(|(_, task, _)| match task {
+                TaskEntity::Cp(cp) => Some(cp.template_sources(&base)),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        partials.verify_files(&template_sources)?;
+        let engine = playbook_ast
+            .get("template_engine")
+            .map(|engine| engine::parse(engine, "template_engine"))
+            .transpose()?
+            .unwrap_or_default();
+        let backup = playbook_ast
+            .get("backup")
+            .map(|backup| {
+                backup.as_bool().ok_or_else(|| {
+                    Error::InvalidPlaybook("backup must be boolean".to_owned(), backup.to_owned())
+                })
+            })
+            .transpose()?
+            .unwrap_or(true);
         Ok(PlayBook {
             taskgroups,
             task_ids: taskbuilders
@@ -578,42 +1198,230 @@ impl PlayBook {
                 .iter()
                 .map(|s| (*s).to_owned())
                 .collect::<Vec<_>>(),
-            base: Path::new(config)
-                .parent()
-                .ok_or_else(|| {
-                    Error::PlaybookLoadFailed(format!("cannot take parent of {}", config))
-                })?
-                .to_owned(),
+            base,
             serialize_ids: taskbuilders
                 .serialize_ids()
                 .iter()
                 .map(|s| (*s).to_owned())
                 .collect::<Vec<_>>(),
             scenarios,
+            facts,
+            last_facts: taskbuilders
+                .cache("facts")
+                .and_then(|cache| rmp_serde::from_slice(&cache).ok())
+                .unwrap_or_default(),
+            node_info: OnceCell::new(),
+            vars,
+            vars_files,
+            taskgroup_vars,
+            extra_vars: HashMap::new(),
+            inventory,
+            keyring,
+            partials,
+            engine,
+            strict: false,
+            backup,
+            full: false,
+        })
+    }
+
+    /// Set variables given by command line (e.g. `--var` and `--vars-file`).
+    /// These take precedence over any other variables.
+    pub fn set_extra_vars(&mut self, vars: vars::Vars) {
+        self.extra_vars = vars;
+    }
+
+    /// Render every template reachable from the selected scenario, even if dry-run skips it.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Compare every file, ignoring the record of the last deploy.
+    pub fn set_full(&mut self, full: bool) {
+        self.full = full;
+    }
+
+    /// Information of this node. Facts are evaluated at the first call.
+    fn node_info(&self) -> Result<&NodeInformation, Error> {
+        self.node_info.get_or_try_init(|| {
+            NodeInformation::collect(
+                &self.facts,
+                &self.base,
+                &self.partials,
+                self.engine,
+                &self.last_facts,
+            )
         })
     }
 
     /// Enlist selected tasks by scenario.
-    pub fn deploys(&self, scenario: Option<&str>) -> Result<(String, ScheduledTasks), Error> {
+    pub fn deploys(&self, scenario: Option<&str>) -> Result<(String, ScheduledTasks<'_>), Error> {
+        let node_info = self.node_info()?;
+        self.deploys_on(scenario, node_info)
+    }
+
+    fn select_scenario(
+        &self,
+        scenario: Option<&str>,
+        node_info: &NodeInformation,
+    ) -> Result<Selection<'_>, Error> {
+        let host = self.inventory.find(
+            &node_info.hostname.to_string_lossy(),
+            node_info.machine_id.as_deref(),
+        );
         let scenario = if let Some(scenario) = scenario {
             self.scenarios
                 .iter()
                 .find(|s| s.name == scenario)
                 .ok_or(Error::AnyScenarioDoesNotMatch)?
         } else {
-            match_scenario(
+            match_scenario_with_inventory(
                 &self.scenarios,
-                &NodeInformation::collect()
-                    .map_err(|e| Error::CannotCollectNodeInformation(format!("{:?}", e)))?,
+                host.as_ref().map(|(host, _)| *host),
+                node_info,
             )
             .ok_or(Error::AnyScenarioDoesNotMatch)?
         };
+        Ok(Selection { scenario, host })
+    }
+
+    /// Explain which inventory entry and scenario are selected on this node.
+    pub fn explain(&self, scenario: Option<&str>) -> Result<Explanation, Error> {
+        let node_info = self.node_info()?;
+        let selection = self.select_scenario(scenario, node_info)?;
+        Ok(Explanation {
+            facts: node_info.to_liquid(),
+            inventory: selection
+                .host
+                .map(|(host, matched_by)| (host.name().to_owned(), matched_by)),
+            scenario: selection.scenario.name.to_owned(),
+            taskgroups: selection.scenario.tasks.clone(),
+        })
+    }
+
+    fn deploys_on(
+        &self,
+        scenario: Option<&str>,
+        node_info: &NodeInformation,
+    ) -> Result<(String, ScheduledTasks<'_>), Error> {
+        let scenario = self.select_scenario(scenario, node_info)?.scenario;
         Ok((
             scenario.name.to_owned(),
-            enlist_taskgroups(&self.taskgroups, scenario.tasks.as_slice())?,
+            enlist_taskgroups(&self.taskgroups, scenario.tasks.as_slice(), node_info)?,
         ))
     }
 
+    /// Merge variables exposed to tasks of each taskgroup.
+    fn group_vars<'a>(
+        &self,
+        selection: &Selection<'_>,
+        facts: liquid::Object,
+        taskgroups: &ScheduledTasks<'a>,
+    ) -> Result<HashMap<&'a str, liquid::Object>, Error> {
+        let mut globals = liquid::Object::new();
+        globals.insert("facts".into(), liquid::model::Value::Object(facts));
+        if let Some((host, _)) = &selection.host {
+            globals.insert(
+                "inventory".into(),
+                liquid::model::Value::Object(host.to_liquid()),
+            );
+        }
+        let host_vars = selection
+            .host
+            .as_ref()
+            .map(|(host, _)| host.vars().clone())
+            .unwrap_or_default();
+        let playbook_files = vars::load_files(
+            &self.vars_files,
+            &self.base,
+            &vars::merge(&globals, &[&self.vars]),
+            &self.partials,
+            self.engine,
+        )?;
+        let scenario_files = vars::load_files(
+            &selection.scenario.vars_files,
+            &self.base,
+            &vars::merge(
+                &globals,
+                &[&self.vars, &playbook_files, &selection.scenario.vars],
+            ),
+            &self.partials,
+            self.engine,
+        )?;
+        let no_vars = HashMap::new();
+        Ok(taskgroups
+            .iter()
+            .map(|(group, _)| {
+                let vars = vars::merge(
+                    &globals,
+                    &[
+                        &self.vars,
+                        &playbook_files,
+                        &selection.scenario.vars,
+                        &scenario_files,
+                        &host_vars,
+                        self.taskgroup_vars.get(*group).unwrap_or(&no_vars),
+                        &self.extra_vars,
+                    ],
+                );
+                (*group, vars)
+            })
+            .collect::<HashMap<_, _>>())
+    }
+
+    /// Render source file `path` with the first task of the selected scenario which deploys it.
+    /// Returns the name of the task and the rendered content. Like dry-run, `command_output`
+    /// runs nothing unless `run_commands`.
+    pub async fn render(
+        &self,
+        scenario: Option<&str>,
+        path: &Path,
+        run_commands: bool,
+    ) -> Result<Option<(String, Preview)>, Error> {
+        let node_info = self.node_info()?;
+        let selection = self.select_scenario(scenario, node_info)?;
+        let taskgroups = enlist_taskgroups(
+            &self.taskgroups,
+            selection.scenario.tasks.as_slice(),
+            node_info,
+        )?;
+        let group_vars = self.group_vars(&selection, node_info.to_liquid(), &taskgroups)?;
+        let overrides = vars::merge(&liquid::Object::new(), &[&self.extra_vars]);
+        let filters = filters::Filters::new(self.base.clone(), !run_commands, self.keyring.clone())
+            .with_partials(self.partials.clone())
+            .with_engine(self.engine);
+        let cache = RwLock::new(None);
+        let notes = std::sync::Mutex::new(Vec::new());
+        for (group, tasks) in &taskgroups {
+            let ctx = TaskContext {
+                base: self.base.clone(),
+                dryrun: true,
+                strict: true,
+                scenario: selection.scenario.name.to_owned(),
+                cache: &cache,
+                vars: group_vars.get(group).expect("already merged"),
+                overrides: &overrides,
+                filters: &filters,
+                notes: &notes,
+                backup: None,
+                state: None,
+            };
+            for (_, task, _) in tasks.iter() {
+                match task.preview(&ctx, path).await {
+                    Some(Ok(preview)) => return Ok(Some((task.name(), preview))),
+                    Some(Err(TaskError::WellKnown(msg))) => {
+                        return Err(Error::CannotRender(format!("{}: {}", task.name(), msg)))
+                    }
+                    Some(Err(TaskError::Unknown(e))) => {
+                        return Err(Error::CannotRender(format!("{}: {}", task.name(), e)))
+                    }
+                    None => (),
+                }
+            }
+        }
+        Ok(None)
+    }
+
     /// Utility to execute playbook graphicaly
     pub async fn execute_graphicaly(
         &self,
@@ -621,11 +1429,26 @@ impl PlayBook {
         scenario: Option<&str>,
         verbose_level: &VerboseLevel,
     ) -> Result<HashMap<String, Vec<u8>>, Error> {
-        let (scenario, taskgroups) = self.deploys(scenario)?;
+        let node_info = self.node_info()?;
+        let selection = self.select_scenario(scenario, node_info)?;
+        let selected = selection.scenario;
+        let scenario = selected.name.to_owned();
+        let taskgroups = enlist_taskgroups(&self.taskgroups, selected.tasks.as_slice(), node_info)?;
+        let facts = node_info.to_liquid();
         let mut caches = HashMap::new();
         for task in &self.task_ids {
-            caches.insert(task, Arc::new(RwLock::new(None)));
+            caches.insert(task.to_owned(), Arc::new(RwLock::new(None)));
         }
+        // read back by the next run if a fact fails to evaluate
+        caches.insert(
+            "facts".to_owned(),
+            Arc::new(RwLock::new(rmp_serde::to_vec(&node_info.custom).ok())),
+        );
+        let group_vars = self.group_vars(&selection, facts, &taskgroups)?;
+        let overrides = vars::merge(&liquid::Object::new(), &[&self.extra_vars]);
+        let filters = filters::Filters::new(self.base.clone(), dryrun, self.keyring.clone())
+            .with_partials(self.partials.clone())
+            .with_engine(self.engine);
         let serialize_lock = Arc::new(
             self.serialize_ids
                 .iter()
@@ -635,13 +1458,18 @@ impl PlayBook {
 
         let change_count = Arc::new(RwLock::new(0));
         let skip_count = Arc::new(RwLock::new(0));
+        let fail_count = Arc::new(RwLock::new(0));
+        let backup = backup::root()
+            .filter(|_| !dryrun)
+            .map(|root| backup::Backup::new(&root, self.backup));
+        let state = state::path().map(|path| state::State::load(&path, self.full));
 
         let tasks = taskgroups
             .iter()
             .map(|(group, tasks)| {
                 tasks
                     .iter()
-                    .map(|(id, task)| (*group, id, task))
+                    .map(|(id, task, _)| (*group, id, task))
                     .collect::<Vec<_>>()
             })
             .collect::<Vec<_>>()
@@ -652,7 +1480,13 @@ impl PlayBook {
                 let caches = caches.clone();
                 let change_count = change_count.clone();
                 let skip_count = skip_count.clone();
+                let fail_count = fail_count.clone();
                 let serialize_lock = serialize_lock.clone();
+                let vars = group_vars.get(group).expect("already merged");
+                let overrides = &overrides;
+                let filters = &filters;
+                let backup = backup.as_ref();
+                let state = state.as_ref();
                 async move {
                     let _guard = if let Some(lock) = serialize_lock.get(id) {
                         Some(lock.lock().await)
@@ -660,13 +1494,22 @@ impl PlayBook {
                         None
                     };
                     let task_name = task.name();
+                    let notes = std::sync::Mutex::new(Vec::new());
                     let ctx = TaskContext {
                         dryrun,
+                        strict: self.strict,
                         scenario: scenario.clone(),
                         base: self.base.clone(),
-                        cache: caches.get(&id).expect("already registered"),
+                        cache: caches.get(id).expect("already registered"),
+                        vars,
+                        overrides,
+                        filters,
+                        notes: &notes,
+                        backup,
+                        state,
                     };
                     let result = task.execute(&ctx).await;
+                    let notes = notes.into_inner().unwrap_or_default();
                     match (result, verbose_level) {
                         (Ok(true), VerboseLevel::Compact) => {
                             *change_count.write().await += 1;
@@ -677,6 +1520,9 @@ impl PlayBook {
                                 color::Fg(color::White),
                                 task_name
                             );
+                            for note in &notes {
+                                println!("  * {}", note);
+                            }
                         }
                         (Ok(false), VerboseLevel::Compact) => {
                             *skip_count.write().await += 1;
@@ -689,6 +1535,9 @@ impl PlayBook {
                                 color::Fg(color::White),
                                 task_name
                             );
+                            for note in &notes {
+                                println!("  * {}", note);
+                            }
                         }
                         (Ok(false), VerboseLevel::ShowAllTask) => {
                             println!("[{}]", group);
@@ -700,6 +1549,7 @@ impl PlayBook {
                             );
                         }
                         (Err(TaskError::WellKnown(msg)), _) => {
+                            *fail_count.write().await += 1;
                             println!("[{}]", group);
                             println!(
                                 "{}[Failed]  {}{}",
@@ -710,6 +1560,7 @@ impl PlayBook {
                             println!("  -> {}", msg);
                         }
                         (Err(TaskError::Unknown(e)), _) => {
+                            *fail_count.write().await += 1;
                             println!("[{}]", group);
                             println!(
                                 "{}[Failed]  {}{}",
@@ -741,6 +1592,23 @@ impl PlayBook {
                 );
             }
         }
+        if let Some(backup) = backup.filter(|backup| !backup.is_empty()) {
+            println!(
+                "{}[Backup] {}{} paths to {:?}",
+                color::Fg(color::Cyan),
+                color::Fg(color::White),
+                backup.len(),
+                backup.dir()
+            );
+        }
+        // a failed run may have left files which its records do not describe
+        if let Some(state) = state.filter(|_| !dryrun) {
+            if *fail_count.read().await == 0 {
+                state.save().await.map_err(|e| {
+                    Error::CannotLoadCache(format!("cannot save deploy state due to {:?}", e))
+                })?;
+            }
+        }
         Ok(futures::stream::iter(caches)
             .filter_map(|(k, v)| async move {
                 v.read()
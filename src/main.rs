@@ -3,7 +3,9 @@ use clap_complete::Shell;
 use dotman::VerboseLevel;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::{fs, io, process};
 use termion::color;
 #[derive(Parser)]
@@ -21,6 +23,16 @@ enum Subcommand {
     DryRun(DryRunOpts),
     #[clap(override_help = "generate shell completion")]
     Completion(CompletionOpts),
+    #[clap(override_help = "explain which inventory entry and scenario are selected")]
+    Explain(ExplainOpts),
+    #[clap(override_help = "encrypt, decrypt or edit secret variables")]
+    Secret(SecretOpts),
+    #[clap(override_help = "encrypt file to be decrypted by cp")]
+    Encrypt(EncryptOpts),
+    #[clap(override_help = "render a source file of cp as it would be deployed")]
+    Render(RenderOpts),
+    #[clap(override_help = "list, restore or remove backups of overwritten files")]
+    Backups(BackupsOpts),
 }
 
 #[derive(Parser)]
@@ -40,6 +52,15 @@ struct DeployOpts {
     scenario: Option<String>,
     #[clap(short = 'V', long)]
     verbose: bool,
+    #[clap(long = "var", help = "set template variable e.g. \"font_size=11\"")]
+    vars: Vec<String>,
+    #[clap(long = "vars-file", help = "load template variables from file")]
+    vars_file: Option<String>,
+    #[clap(
+        long,
+        help = "compare every file ignoring the record of the last deploy"
+    )]
+    full: bool,
 }
 
 #[derive(Parser)]
@@ -48,15 +69,158 @@ struct DryRunOpts {
     config: String,
     #[clap(long = "no-cache", help = "dry-run without cache")]
     no_cache: bool,
+    #[clap(
+        long = "no-strict",
+        help = "skip rendering templates which dry-run does not compare"
+    )]
+    no_strict: bool,
     #[clap(short, long, help = "specify scenario with no auto scenario detection")]
     scenario: Option<String>,
     #[clap(short = 'V', long)]
     verbose: bool,
+    #[clap(long = "var", help = "set template variable e.g. \"font_size=11\"")]
+    vars: Vec<String>,
+    #[clap(long = "vars-file", help = "load template variables from file")]
+    vars_file: Option<String>,
+    #[clap(
+        long,
+        help = "compare every file ignoring the record of the last deploy"
+    )]
+    full: bool,
+}
+
+#[derive(Parser)]
+struct ExplainOpts {
+    #[clap(index = 1, help = "specify configuration file e.g. \"dotfiles.yaml\"")]
+    config: String,
+    #[clap(short, long, help = "specify scenario with no auto scenario detection")]
+    scenario: Option<String>,
+}
+
+#[derive(Parser)]
+struct RenderOpts {
+    #[clap(index = 1, help = "specify configuration file e.g. \"dotfiles.yaml\"")]
+    config: String,
+    #[clap(index = 2, help = "source file to render e.g. \"pkgs/sway/config\"")]
+    path: String,
+    #[clap(short, long, help = "specify scenario with no auto scenario detection")]
+    scenario: Option<String>,
+    #[clap(long = "var", help = "set template variable e.g. \"font_size=11\"")]
+    vars: Vec<String>,
+    #[clap(long = "vars-file", help = "load template variables from file")]
+    vars_file: Option<String>,
+    #[clap(long, help = "show difference from the current destination")]
+    diff: bool,
+    #[clap(
+        long,
+        help = "show content of files containing secrets instead of redacting it"
+    )]
+    show_secrets: bool,
+    #[clap(
+        long,
+        help = "run commands of command_output filter instead of showing placeholders"
+    )]
+    run_commands: bool,
+}
+
+#[derive(Parser)]
+struct SecretOpts {
+    #[clap(long = "key-file", help = "specify key file to encrypt secrets")]
+    key_file: Option<String>,
+    #[clap(subcommand)]
+    subcmd: SecretSubcommand,
+}
+
+#[derive(Parser)]
+enum SecretSubcommand {
+    #[clap(override_help = "encrypt value given by argument or stdin")]
+    Encrypt(SecretValueOpts),
+    #[clap(override_help = "decrypt value given by argument or stdin")]
+    Decrypt(SecretValueOpts),
+    #[clap(override_help = "edit secret variable in vars file with $EDITOR")]
+    Edit(SecretEditOpts),
+}
+
+#[derive(Parser)]
+struct SecretValueOpts {
+    #[clap(index = 1, help = "value to process (read from stdin if omitted)")]
+    value: Option<String>,
+}
+
+#[derive(Parser)]
+struct SecretEditOpts {
+    #[clap(index = 1, help = "specify vars file e.g. \"vars/secrets.yaml\"")]
+    vars_file: String,
+    #[clap(index = 2, help = "name of secret variable")]
+    key: String,
+}
+
+#[derive(Parser)]
+struct BackupsOpts {
+    #[clap(
+        long,
+        help = "specify backup directory (default $XDG_STATE_HOME/dotman/backups)"
+    )]
+    dir: Option<String>,
+    #[clap(subcommand)]
+    subcmd: BackupsSubcommand,
+}
+
+#[derive(Parser)]
+enum BackupsSubcommand {
+    #[clap(override_help = "list backups, or files of a backup")]
+    List(BackupsListOpts),
+    #[clap(override_help = "restore files of a backup")]
+    Restore(BackupsRestoreOpts),
+    #[clap(override_help = "remove old backups")]
+    Gc(BackupsGcOpts),
+}
+
+#[derive(Parser)]
+struct BackupsListOpts {
+    #[clap(index = 1, help = "backup ID to list files of")]
+    id: Option<String>,
+}
+
+#[derive(Parser)]
+struct BackupsRestoreOpts {
+    #[clap(index = 1, help = "backup ID e.g. \"20230120T083000-1234\"")]
+    id: String,
+    #[clap(
+        index = 2,
+        help = "paths to restore (all files of the backup if omitted)"
+    )]
+    paths: Vec<String>,
+}
+
+#[derive(Parser)]
+struct BackupsGcOpts {
+    #[clap(
+        long,
+        default_value_t = 10,
+        help = "number of the newest backups to keep"
+    )]
+    keep: usize,
+}
+
+#[derive(Parser)]
+struct EncryptOpts {
+    #[clap(index = 1, help = "specify file to encrypt e.g. \"pkgs/ssh/config\"")]
+    path: String,
+    #[clap(long = "key-file", help = "specify key file to encrypt secrets")]
+    key_file: Option<String>,
+    #[clap(
+        long = "in-place",
+        help = "overwrite the file instead of writing <path>.enc (for cp.decrypt)"
+    )]
+    in_place: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Cache {
     cargo: Option<dotman::tasks::cargo::Cache>,
+    #[serde(default)]
+    facts: Option<liquid::Object>,
 }
 
 struct TaskBuilder {
@@ -85,8 +249,17 @@ impl dotman::TaskBuilder for TaskBuilder {
     }
 
     fn cache(&self, key: &str) -> Option<Vec<u8>> {
-        match (key, &self.cache.cargo) {
-            ("cargo", Some(cache)) => Some(rmp_serde::to_vec(cache).unwrap()),
+        match key {
+            "cargo" => self
+                .cache
+                .cargo
+                .as_ref()
+                .map(|cache| rmp_serde::to_vec(cache).unwrap()),
+            "facts" => self
+                .cache
+                .facts
+                .as_ref()
+                .map(|facts| rmp_serde::to_vec(facts).unwrap()),
             _ => None,
         }
     }
@@ -123,13 +296,182 @@ impl TaskBuilder {
             }
         };
         Self {
-            cache: Cache { cargo: None },
+            cache: Cache {
+                cargo: None,
+                facts: None,
+            },
             ids,
             serialize_ids,
         }
     }
 }
 
+fn extra_vars(
+    vars: &[String],
+    vars_file: Option<&str>,
+) -> Result<dotman::vars::Vars, dotman::Error> {
+    let mut extra_vars = if let Some(vars_file) = vars_file {
+        dotman::vars::load_file(Path::new(vars_file))?
+    } else {
+        HashMap::new()
+    };
+    for var in vars {
+        let (key, val) = dotman::vars::parse_assignment(var)?;
+        extra_vars.insert(key, val);
+    }
+    Ok(extra_vars)
+}
+
+fn read_value(value: Option<String>) -> Result<String, dotman::Error> {
+    if let Some(value) = value {
+        return Ok(value);
+    }
+    let mut buf = String::new();
+    io::stdin().read_to_string(&mut buf).map_err(|e| {
+        dotman::Error::CannotProcessSecret(format!("cannot read stdin due to {:?}", e))
+    })?;
+    Ok(buf.trim_end_matches('\n').to_owned())
+}
+
+async fn edit_secret(
+    keyring: &dotman::secret::Keyring,
+    vars_file: &str,
+    key: &str,
+) -> Result<(), dotman::Error> {
+    let path = Path::new(vars_file);
+    let src = fs::read_to_string(path).map_err(|e| {
+        dotman::Error::PlaybookLoadFailed(format!("cannot read {:?} due to {:?}", path, e))
+    })?;
+    let old = dotman::vars::load_file(path)?
+        .get(key)
+        .map(|value| {
+            value
+                .as_hash()
+                .filter(|hash| hash.len() == 1)
+                .and_then(|hash| hash.get("encrypted"))
+                .and_then(|ciphertext| ciphertext.as_str())
+                .map(|ciphertext| ciphertext.to_owned())
+                .ok_or_else(|| {
+                    dotman::Error::CannotProcessSecret(format!(
+                        "{} is not an encrypted variable",
+                        key
+                    ))
+                })
+        })
+        .transpose()?;
+    let plaintext = old
+        .as_deref()
+        .map(|ciphertext| keyring.decrypt(ciphertext))
+        .transpose()
+        .map_err(|e| dotman::Error::CannotProcessSecret(e.to_string()))?
+        .unwrap_or_default();
+    // editors may leave swap and backup files next to the file, which go away with the dir
+    let dir = dotman::util::private_temp_dir("dotman-secret").map_err(|e| {
+        dotman::Error::CannotProcessSecret(format!("cannot create temporary dir due to {:?}", e))
+    })?;
+    let tmp = dir.join("secret");
+    let edited = (|| {
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        f.write_all(plaintext.as_bytes())?;
+        drop(f);
+        let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_owned());
+        // run through shell like git so that $EDITOR can have arguments
+        let status = process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", editor))
+            .arg(&editor)
+            .arg(&tmp)
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other("editor exited with error"));
+        }
+        fs::read_to_string(&tmp)
+    })();
+    let _ = fs::remove_dir_all(&dir);
+    let edited = edited.map_err(|e| {
+        dotman::Error::CannotProcessSecret(format!("cannot edit secret due to {:?}", e))
+    })?;
+    let ciphertext = keyring
+        .encrypt(edited.trim_end_matches('\n'))
+        .map_err(|e| dotman::Error::CannotProcessSecret(e.to_string()))?;
+    let updated = match (old, path.extension().and_then(|ext| ext.to_str())) {
+        (Some(old), _) => dotman::secret::replace_ciphertext(path, &src, key, &old, &ciphertext)?,
+        (None, Some("yaml" | "yml")) => {
+            let sep = if src.is_empty() || src.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            format!(
+                "{}{}{}: {{ encrypted: \"{}\" }}\n",
+                src, sep, key, ciphertext
+            )
+        }
+        (None, _) => {
+            return Err(dotman::Error::CannotProcessSecret(format!(
+                "{} is not found in {:?} (new secrets can be added only to yaml)",
+                key, path
+            )))
+        }
+    };
+    dotman::util::write_atomic(path, updated.as_bytes(), None)
+        .await
+        .map_err(|e| {
+            dotman::Error::CannotProcessSecret(format!("cannot write {:?} due to {:?}", path, e))
+        })
+}
+
+fn run_backups(opts: BackupsOpts) -> Result<(), dotman::Error> {
+    let root = opts
+        .dir
+        .map(PathBuf::from)
+        .or_else(dotman::backup::root)
+        .ok_or_else(|| {
+            dotman::Error::CannotProcessBackup("cannot find backup directory".to_owned())
+        })?;
+    let error = |e: io::Error| dotman::Error::CannotProcessBackup(e.to_string());
+    match opts.subcmd {
+        BackupsSubcommand::List(list_opts) => {
+            let manifests = dotman::backup::list(&root).map_err(error)?;
+            if let Some(id) = list_opts.id {
+                let manifest = manifests
+                    .into_iter()
+                    .find(|manifest| manifest.id == id)
+                    .ok_or_else(|| {
+                        dotman::Error::CannotProcessBackup(format!("no backup {}", id))
+                    })?;
+                for entry in manifest.entries {
+                    println!("{:04o} {:?}", entry.mode, entry.path);
+                }
+            } else {
+                for manifest in manifests {
+                    println!("{} ({} paths)", manifest.id, manifest.entries.len());
+                }
+            }
+        }
+        BackupsSubcommand::Restore(restore_opts) => {
+            let paths = restore_opts
+                .paths
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>();
+            for path in dotman::backup::restore(&root, &restore_opts.id, &paths).map_err(error)? {
+                println!("restored {:?}", path);
+            }
+        }
+        BackupsSubcommand::Gc(gc_opts) => {
+            for id in dotman::backup::gc(&root, gc_opts.keep).map_err(error)? {
+                println!("removed {}", id);
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn run(opts: Opts) -> Result<(), dotman::Error> {
     let cache_path = format!(
         "{}/.dotfiles.cache.json",
@@ -143,7 +485,9 @@ async fn run(opts: Opts) -> Result<(), dotman::Error> {
             } else {
                 Some(std::path::Path::new(&cache_path))
             });
-            let playbook = dotman::PlayBook::load_config(&opts.config, &task_builder)?;
+            let mut playbook = dotman::PlayBook::load_config(&opts.config, &task_builder)?;
+            playbook.set_extra_vars(extra_vars(&opts.vars, opts.vars_file.as_deref())?);
+            playbook.set_full(opts.full);
             let verbose_lebel = if opts.verbose {
                 VerboseLevel::ShowAllTask
             } else {
@@ -161,9 +505,16 @@ async fn run(opts: Opts) -> Result<(), dotman::Error> {
             let mut f = fs::File::create(cache_path).map_err(|e| {
                 dotman::Error::CannotLoadCache(format!("cannot write cache due to {:?}", e))
             })?;
-            let cargo_cache =
-                rmp_serde::from_read(io::Cursor::new(&cache.get("cargo").unwrap())).unwrap();
-            let cache = Cache { cargo: cargo_cache };
+            let cargo_cache = cache
+                .get("cargo")
+                .and_then(|cache| rmp_serde::from_read(io::Cursor::new(cache)).ok());
+            let facts = cache
+                .get("facts")
+                .and_then(|cache| rmp_serde::from_slice(cache).ok());
+            let cache = Cache {
+                cargo: cargo_cache,
+                facts,
+            };
             let writer = io::BufWriter::new(&mut f);
             serde_json::to_writer_pretty(writer, &cache).map_err(|e| {
                 dotman::Error::CannotLoadCache(format!("cannot write cache due to {:?}", e))
@@ -176,7 +527,10 @@ async fn run(opts: Opts) -> Result<(), dotman::Error> {
             } else {
                 Some(std::path::Path::new(&cache_path))
             });
-            let playbook = dotman::PlayBook::load_config(&opts.config, &task_builder)?;
+            let mut playbook = dotman::PlayBook::load_config(&opts.config, &task_builder)?;
+            playbook.set_extra_vars(extra_vars(&opts.vars, opts.vars_file.as_deref())?);
+            playbook.set_strict(!opts.no_strict);
+            playbook.set_full(opts.full);
             let verbose_lebel = if opts.verbose {
                 VerboseLevel::ShowAllTask
             } else {
@@ -193,6 +547,99 @@ async fn run(opts: Opts) -> Result<(), dotman::Error> {
             }?;
             Ok(())
         }
+        Subcommand::Explain(opts) => {
+            let task_builder = TaskBuilder::from_cache_path(None::<&Path>);
+            let playbook = dotman::PlayBook::load_config(&opts.config, &task_builder)?;
+            let explanation = playbook.explain(opts.scenario.as_deref())?;
+            println!("[facts]");
+            for (name, value) in &explanation.facts {
+                println!(
+                    "  {} = {}",
+                    name,
+                    serde_json::to_string(value).unwrap_or_default()
+                );
+            }
+            if let Some((host, matched_by)) = &explanation.inventory {
+                println!("[inventory] {} (matched by {})", host, matched_by);
+            } else {
+                println!("[inventory] no entry matched");
+            }
+            println!("[scenario] {}", explanation.scenario);
+            println!("[taskgroups] {}", explanation.taskgroups.join(", "));
+            Ok(())
+        }
+        Subcommand::Render(opts) => {
+            let task_builder = TaskBuilder::from_cache_path(None::<&Path>);
+            let mut playbook = dotman::PlayBook::load_config(&opts.config, &task_builder)?;
+            playbook.set_extra_vars(extra_vars(&opts.vars, opts.vars_file.as_deref())?);
+            let (task, preview) = playbook
+                .render(
+                    opts.scenario.as_deref(),
+                    Path::new(&opts.path),
+                    opts.run_commands,
+                )
+                .await?
+                .ok_or_else(|| {
+                    dotman::Error::CannotRender(format!(
+                        "{} is not deployed by the selected scenario",
+                        opts.path
+                    ))
+                })?;
+            eprintln!("[{}] {} => {:?}", task, opts.path, preview.dest);
+            let output = preview.output(opts.diff, opts.show_secrets)?;
+            io::stdout().write_all(&output).map_err(|e| {
+                dotman::Error::CannotRender(format!("cannot write output due to {:?}", e))
+            })
+        }
+        Subcommand::Secret(opts) => {
+            let keyring = dotman::secret::Keyring::new(opts.key_file.map(PathBuf::from));
+            match opts.subcmd {
+                SecretSubcommand::Encrypt(value_opts) => {
+                    let ciphertext = keyring
+                        .encrypt(&read_value(value_opts.value)?)
+                        .map_err(|e| dotman::Error::CannotProcessSecret(e.to_string()))?;
+                    println!("{}", ciphertext);
+                }
+                SecretSubcommand::Decrypt(value_opts) => {
+                    let plaintext = keyring
+                        .decrypt(&read_value(value_opts.value)?)
+                        .map_err(|e| dotman::Error::CannotProcessSecret(e.to_string()))?;
+                    println!("{}", plaintext);
+                }
+                SecretSubcommand::Edit(edit_opts) => {
+                    edit_secret(&keyring, &edit_opts.vars_file, &edit_opts.key).await?;
+                }
+            }
+            Ok(())
+        }
+        Subcommand::Encrypt(opts) => {
+            let keyring = dotman::secret::Keyring::new(opts.key_file.as_ref().map(PathBuf::from));
+            let plaintext = fs::read(&opts.path).map_err(|e| {
+                dotman::Error::CannotProcessSecret(format!(
+                    "cannot read {} due to {:?}",
+                    opts.path, e
+                ))
+            })?;
+            let ciphertext = keyring
+                .encrypt_bytes(&plaintext)
+                .map_err(|e| dotman::Error::CannotProcessSecret(e.to_string()))?;
+            let dest = if opts.in_place {
+                opts.path.clone()
+            } else {
+                format!("{}.enc", opts.path)
+            };
+            fs::write(&dest, format!("{}\n", ciphertext)).map_err(|e| {
+                dotman::Error::CannotProcessSecret(format!("cannot write {} due to {:?}", dest, e))
+            })?;
+            if !opts.in_place {
+                println!(
+                    "encrypted {} => {} (remove {} before commit)",
+                    opts.path, dest, opts.path
+                );
+            }
+            Ok(())
+        }
+        Subcommand::Backups(opts) => run_backups(opts),
         Subcommand::Completion(completion_opts) => {
             let generator = completion_opts.shell;
             let mut cmd = Opts::command();
@@ -291,5 +738,32 @@ async fn main() {
                 msg
             );
         }
+        Err(dotman::Error::CannotRender(msg)) => {
+            eprintln!(
+                "{}[Error] {}cannot render due to {}",
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                msg
+            );
+            process::exit(-1);
+        }
+        Err(dotman::Error::CannotProcessBackup(msg)) => {
+            eprintln!(
+                "{}[Error] {}{}",
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                msg
+            );
+            process::exit(-1);
+        }
+        Err(dotman::Error::CannotProcessSecret(msg)) => {
+            eprintln!(
+                "{}[Error] {}{}",
+                color::Fg(color::Red),
+                color::Fg(color::Reset),
+                msg
+            );
+            process::exit(-1);
+        }
     }
 }
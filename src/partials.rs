@@ -0,0 +1,222 @@
+//! Partial templates shared by all templates.
+//!
+//! Files under the `partials` directory of the playbook (default `templates/partials`) are read
+//! once when the playbook is loaded and can be included from any template by their path relative
+//! to the directory, with or without `.liquid` suffix (e.g. `{% include 'colors' %}`).
+use liquid_core::partials::{EagerCompiler, InMemorySource, PartialSource};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::Error;
+
+/// Default directory of partials relative to the playbook.
+pub const DEFAULT_DIR: &str = "templates/partials";
+
+static INCLUDE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"\{%-?\s*include\s+(?:'([^']*)'|"([^"]*)")"#).unwrap());
+
+/// Partials loaded from a directory. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct Partials {
+    dir: Option<PathBuf>,
+    source: Arc<InMemorySource>,
+}
+
+fn collect(root: &Path, dir: &Path, source: &mut InMemorySource) -> Result<(), Error> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        Error::PlaybookLoadFailed(format!("cannot read partials {:?} due to {:?}", dir, e))
+    })?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| {
+                Error::PlaybookLoadFailed(format!("cannot read partials {:?} due to {:?}", dir, e))
+            })?
+            .path();
+        if path.is_dir() {
+            collect(root, &path, source)?;
+            continue;
+        }
+        let content = fs::read_to_string(&path).map_err(|e| {
+            Error::PlaybookLoadFailed(format!("cannot read partial {:?} due to {:?}", path, e))
+        })?;
+        let name = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned();
+        if let Some(stem) = name.strip_suffix(".liquid") {
+            source.add(stem.to_owned(), content.clone());
+        }
+        source.add(name, content);
+    }
+    Ok(())
+}
+
+impl Partials {
+    /// Load partials under `dir`. A missing directory is an error only if `required`.
+    pub fn load(dir: &Path, required: bool) -> Result<Self, Error> {
+        if !dir.is_dir() {
+            if required {
+                return Err(Error::PlaybookLoadFailed(format!(
+                    "partials directory {:?} is not found",
+                    dir
+                )));
+            }
+            return Ok(Self::default());
+        }
+        let mut source = InMemorySource::new();
+        collect(dir, dir, &mut source)?;
+        let partials = Self {
+            dir: Some(dir.to_owned()),
+            source: Arc::new(source),
+        };
+        // report syntax errors of partials here rather than on every render
+        liquid::ParserBuilder::with_stdlib()
+            .partials(partials.compiler())
+            .build()
+            .map_err(|e| {
+                Error::PlaybookLoadFailed(format!("cannot compile partials due to {}", e))
+            })?;
+        Ok(partials)
+    }
+
+    /// Directory partials are loaded from.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Whether partial `name` exists.
+    pub fn contains(&self, name: &str) -> bool {
+        self.source.contains(name)
+    }
+
+    /// Source of partial `name`.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.source.try_get(name).map(|source| source.into_owned())
+    }
+
+    /// Hash of names and sources of all partials, which changes when any of them changes.
+    pub fn fingerprint(&self) -> u128 {
+        let mut names = self.source.names();
+        names.sort_unstable();
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        for name in names {
+            hasher.update(name.as_bytes());
+            hasher.update(&[0]);
+            if let Some(source) = self.source.try_get(name) {
+                hasher.update(source.as_bytes());
+            }
+            hasher.update(&[0]);
+        }
+        hasher.digest128()
+    }
+
+    /// Names of partials which `src` includes but do not exist.
+    pub fn missing(&self, src: &str) -> Vec<String> {
+        INCLUDE_RE
+            .captures_iter(src)
+            .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+            .map(|name| name.as_str())
+            .filter(|name| !self.contains(name))
+            .map(|name| name.to_owned())
+            .collect()
+    }
+
+    /// Check that `src` and all partials include only existing partials.
+    pub fn verify(&self, src: &str) -> Result<(), Error> {
+        let mut missing = self.missing(src);
+        for name in self.source.names() {
+            if let Some(partial) = self.source.try_get(name) {
+                missing.extend(self.missing(&partial));
+            }
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            missing.sort_unstable();
+            missing.dedup();
+            Err(Error::PlaybookLoadFailed(format!(
+                "partials {} are not found",
+                missing.join(", ")
+            )))
+        }
+    }
+
+    /// Check that template files at `paths` include only existing partials.
+    pub fn verify_files(&self, paths: &[PathBuf]) -> Result<(), Error> {
+        let mut missing = Vec::new();
+        for path in paths {
+            // unreadable or binary files fail when rendered
+            if let Ok(src) = fs::read_to_string(path) {
+                missing.extend(
+                    self.missing(&src)
+                        .into_iter()
+                        .map(|name| format!("{} (included by {:?})", name, path)),
+                );
+            }
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            missing.sort_unstable();
+            missing.dedup();
+            Err(Error::PlaybookLoadFailed(format!(
+                "partials {} are not found",
+                missing.join(", ")
+            )))
+        }
+    }
+
+    /// Compiler to register to liquid parser.
+    pub(crate) fn compiler(&self) -> EagerCompiler<InMemorySource> {
+        EagerCompiler::new(self.source.as_ref().clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_partials() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-partials-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sway")).unwrap();
+        fs::write(dir.join("colors.liquid"), "bg={{ bg }}").unwrap();
+        fs::write(
+            dir.join("sway/bar"),
+            "{% include 'colors' %} height={{ height }}",
+        )
+        .unwrap();
+        let partials = Partials::load(&dir, true).unwrap();
+        assert!(partials.contains("colors"));
+        assert!(partials.contains("colors.liquid"));
+        assert!(partials.contains("sway/bar"));
+        let mut vars = liquid::Object::new();
+        vars.insert("bg".into(), liquid::model::Value::scalar("#000000"));
+        vars.insert("height".into(), liquid::model::Value::scalar(24));
+        assert_eq!(
+            crate::util::resolve_liquid_template(
+                "[{% include 'sway/bar' %}]",
+                &vars,
+                &partials,
+                Default::default(),
+            )
+            .unwrap(),
+            "[bg=#000000 height=24]"
+        );
+        assert!(partials.verify("{% include 'colors' %}").is_ok());
+        assert_eq!(
+            partials.missing("{% include 'colors' %}{%- include \"fonts\" %}"),
+            vec!["fonts".to_owned()]
+        );
+        assert!(partials.verify("{% include 'fonts' %}").is_err());
+        assert!(Partials::load(&dir.join("missing"), true).is_err());
+        assert!(!Partials::load(&dir.join("missing"), false)
+            .unwrap()
+            .contains("colors"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
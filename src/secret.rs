@@ -0,0 +1,922 @@
+//! Encrypted secret variables.
+//!
+//! A secret is a variable whose value is `{encrypted: "dotman:v1:..."}`.
+//! It is decrypted lazily, only when a template or a partial looks the variable up.
+//!
+//! ```yaml
+//! vars:
+//!   github_token: { encrypted: "dotman:v1:..." }
+//! ```
+//!
+//! The ciphertext is XChaCha20-Poly1305 keyed with PBKDF2-HMAC-SHA256 of the key file.
+//! The key file is looked up in the following order.
+//!
+//! 1. `key_file` of playbook (or `--key-file` of `dotman secret`)
+//! 2. `$DOTMAN_KEY_FILE`
+//! 3. `$HOME/.config/dotman/key`
+//!
+//! The key file is not an age identity. `.age` files deployed by `cp` are decrypted with
+//! `age_identity` of playbook instead.
+//!
+//! Secrets which live outside of the repository are looked up with `secret` filter
+//! (e.g. `{{ "github/token" | secret }}`) from `secret_provider` of playbook.
+//!
+//! ```yaml
+//! secret_provider: { cmd: ["pass", "show"] } # name is appended to cmd
+//! # secret_provider: { env: DOTMAN_SECRET_ } # github/token => $DOTMAN_SECRET_GITHUB_TOKEN
+//! # secret_provider: { dir: secrets }        # github/token => secrets/github/token
+//! ```
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hmac::Hmac;
+use liquid::model::ValueView;
+use liquid_core::model::{KString, KStringCow, KStringRef, ScalarCow, ValueCow};
+use liquid_core::parser::{FilterArguments, ParameterReflection};
+use liquid_core::runtime::{PartialStore, Registers, Runtime};
+use liquid_core::{Filter, FilterReflection, ParseFilter};
+use once_cell::sync::OnceCell;
+use sha2::Sha256;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use crate::filters::{JinjaUsage, Usage};
+use crate::{ast, Error};
+
+/// Prefix of ciphertext.
+pub const PREFIX: &str = "dotman:v1:";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const ROUNDS: u32 = 100_000;
+
+/// Error of encryption and decryption. It never contains plaintext.
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum SecretError {
+    /// Key file cannot be read
+    #[error("cannot read key file {0:?}")]
+    KeyNotFound(PathBuf),
+    /// Key file location cannot be determined
+    #[error("key file is not specified and $HOME is not set")]
+    KeyFileUnknown,
+    /// Ciphertext is not formed as `dotman:v1:<base64>`
+    #[error("secret is not formed as {}<base64>", PREFIX)]
+    InvalidFormat,
+    /// Wrong key or broken ciphertext
+    #[error("cannot decrypt secret (wrong key or broken ciphertext)")]
+    DecryptionFailed,
+    /// Failed to encrypt
+    #[error("cannot encrypt secret")]
+    EncryptionFailed,
+    /// `secret` filter is used without `secret_provider`
+    #[error("secret_provider is not configured")]
+    NoProvider,
+    /// Secret provider failed to look up the secret
+    #[error("cannot look up secret {0} due to {1}")]
+    ProviderFailed(String, String),
+    /// `.age` file is deployed without `age_identity`
+    #[error("age_identity is not configured (the key file is not an age identity)")]
+    NoAgeIdentity,
+}
+
+/// Backend of `secret` filter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Provider {
+    /// Run command with the name appended and take its stdout
+    Command(String, Vec<String>),
+    /// Read `$<prefix><NAME>` (non-alphanumeric characters are replaced with `_`)
+    Env(String),
+    /// Read `<dir>/<name>`, where `name` is a relative path without `..`
+    File(PathBuf),
+}
+
+impl Provider {
+    fn fetch(&self, name: &str) -> Result<String, SecretError> {
+        let failed = |reason: String| SecretError::ProviderFailed(name.to_owned(), reason);
+        match self {
+            Provider::Command(exe, args) => {
+                let output = process::Command::new(exe)
+                    .args(args)
+                    .arg(name)
+                    .stdin(process::Stdio::null())
+                    .stderr(process::Stdio::null())
+                    .output()
+                    .map_err(|e| failed(format!("{:?}", e.kind())))?;
+                if !output.status.success() {
+                    return Err(failed(format!("{} exited with {}", exe, output.status)));
+                }
+                String::from_utf8(output.stdout)
+                    .map(|value| value.trim_end_matches('\n').to_owned())
+                    .map_err(|_| failed("non utf-8 output".to_owned()))
+            }
+            Provider::Env(prefix) => {
+                let var = format!(
+                    "{}{}",
+                    prefix,
+                    name.chars()
+                        .map(|c| if c.is_ascii_alphanumeric() {
+                            c.to_ascii_uppercase()
+                        } else {
+                            '_'
+                        })
+                        .collect::<String>()
+                );
+                std::env::var(&var).map_err(|_| failed(format!("${} is not set", var)))
+            }
+            Provider::File(dir) => {
+                // names must not reach files outside of the store
+                let name = Path::new(name);
+                if !name
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+                {
+                    return Err(failed("name must be relative path inside dir".to_owned()));
+                }
+                fs::read_to_string(dir.join(name))
+                    .map(|value| value.trim_end_matches('\n').to_owned())
+                    .map_err(|e| failed(format!("{:?}", e.kind())))
+            }
+        }
+    }
+}
+
+/// parse `secret_provider` section. `dir` is resolved relative to `base`.
+pub fn parse_provider(yaml: &ast::Value, base: &Path) -> Result<Provider, Error> {
+    let obj = yaml.as_hash().ok_or_else(|| {
+        Error::InvalidPlaybook("secret_provider must be hash".to_owned(), yaml.to_owned())
+    })?;
+    ast::verify_hash(obj, &["cmd", "env", "dir"], Some("secret_provider"))?;
+    match (obj.get("cmd"), obj.get("env"), obj.get("dir")) {
+        (Some(ast::Value::Array(cmd)), None, None) => {
+            let mut cmd = cmd
+                .iter()
+                .map(|s| {
+                    s.as_str().map(|s| s.to_owned()).ok_or_else(|| {
+                        Error::InvalidPlaybook(
+                            "secret_provider.cmd must be array of string".to_owned(),
+                            yaml.to_owned(),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter();
+            let exe = cmd.next().ok_or_else(|| {
+                Error::InvalidPlaybook(
+                    "secret_provider.cmd must not be empty".to_owned(),
+                    yaml.to_owned(),
+                )
+            })?;
+            Ok(Provider::Command(exe, cmd.collect()))
+        }
+        (None, Some(ast::Value::Str(prefix)), None) => Ok(Provider::Env(prefix.to_owned())),
+        (None, None, Some(ast::Value::Str(dir))) => Ok(Provider::File(base.join(dir))),
+        _ => Err(Error::InvalidPlaybook(
+            "secret_provider must have one of cmd: <string>[], env: <string> or dir: <string>"
+                .to_owned(),
+            yaml.to_owned(),
+        )),
+    }
+}
+
+/// Lazily loaded key to encrypt and decrypt secrets and provider of `secret` filter.
+/// Cheap to clone, and secrets looked up from the provider are cached among clones.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    path: Option<PathBuf>,
+    passphrase: Arc<OnceCell<Vec<u8>>>,
+    provider: Option<Arc<Provider>>,
+    age_identity: Option<PathBuf>,
+    cache: Arc<Mutex<HashMap<String, String>>>,
+    /// Decrypted secret variables by ciphertext
+    plaintexts: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("path", &self.path)
+            .field("provider", &self.provider)
+            .field("age_identity", &self.age_identity)
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Create keyring. If `path` is `None`, `$DOTMAN_KEY_FILE` or `$HOME/.config/dotman/key` is used.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            ..Default::default()
+        }
+    }
+
+    /// Create keyring from passphrase directly.
+    pub fn from_passphrase(passphrase: &[u8]) -> Self {
+        Self {
+            passphrase: Arc::new(OnceCell::with_value(passphrase.to_vec())),
+            ..Default::default()
+        }
+    }
+
+    /// Set provider of `secret` filter.
+    pub fn with_provider(self, provider: Provider) -> Self {
+        Self {
+            provider: Some(Arc::new(provider)),
+            ..self
+        }
+    }
+
+    /// Set identity file which `age` decrypts `.age` files with.
+    pub fn with_age_identity(self, path: PathBuf) -> Self {
+        Self {
+            age_identity: Some(path),
+            ..self
+        }
+    }
+
+    /// Identity file of `age`.
+    pub fn age_identity(&self) -> Result<&Path, SecretError> {
+        self.age_identity
+            .as_deref()
+            .ok_or(SecretError::NoAgeIdentity)
+    }
+
+    /// Look up secret from provider. The result is cached during the run.
+    pub fn lookup(&self, name: &str) -> Result<String, SecretError> {
+        if let Some(value) = self.cache.lock().expect("poisoned").get(name) {
+            return Ok(value.clone());
+        }
+        let value = self
+            .provider
+            .as_ref()
+            .ok_or(SecretError::NoProvider)?
+            .fetch(name)?;
+        self.cache
+            .lock()
+            .expect("poisoned")
+            .insert(name.to_owned(), value.clone());
+        Ok(value)
+    }
+
+    /// Decrypt secret variable `ciphertext`. The plaintext is cached during the run, since the
+    /// key derivation is deliberately slow and templates may refer a secret many times.
+    fn reveal(&self, ciphertext: &str) -> Result<String, SecretError> {
+        if let Some(plaintext) = self.plaintexts.lock().expect("poisoned").get(ciphertext) {
+            return Ok(plaintext.clone());
+        }
+        let plaintext = self.decrypt(ciphertext)?;
+        self.plaintexts
+            .lock()
+            .expect("poisoned")
+            .insert(ciphertext.to_owned(), plaintext.clone());
+        Ok(plaintext)
+    }
+
+    /// Location of key file.
+    pub fn path(&self) -> Result<PathBuf, SecretError> {
+        if let Some(path) = &self.path {
+            return Ok(path.clone());
+        }
+        if let Ok(path) = std::env::var("DOTMAN_KEY_FILE") {
+            return Ok(PathBuf::from(path));
+        }
+        std::env::var("HOME")
+            .map(|home| PathBuf::from(home).join(".config/dotman/key"))
+            .map_err(|_| SecretError::KeyFileUnknown)
+    }
+
+    fn passphrase(&self) -> Result<&[u8], SecretError> {
+        self.passphrase
+            .get_or_try_init(|| {
+                let path = self.path()?;
+                let mut key = fs::read(&path).map_err(|_| SecretError::KeyNotFound(path))?;
+                while key.last().map(|c| c.is_ascii_whitespace()) == Some(true) {
+                    key.pop();
+                }
+                Ok(key)
+            })
+            .map(|key| key.as_slice())
+    }
+
+    fn cipher(&self, salt: &[u8]) -> Result<XChaCha20Poly1305, SecretError> {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(self.passphrase()?, salt, ROUNDS, &mut key);
+        Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    /// Encrypt `plaintext` into `dotman:v1:<base64>`.
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, SecretError> {
+        self.encrypt_bytes(plaintext.as_bytes())
+    }
+
+    /// Encrypt binary `plaintext` (e.g. file content) into `dotman:v1:<base64>`.
+    pub fn encrypt_bytes(&self, plaintext: &[u8]) -> Result<String, SecretError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(&salt)?
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| SecretError::EncryptionFailed)?;
+        let mut buf = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+        buf.extend_from_slice(&salt);
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&ciphertext);
+        Ok(format!(
+            "{}{}",
+            PREFIX,
+            base64::engine::general_purpose::STANDARD.encode(buf)
+        ))
+    }
+
+    /// Decrypt `dotman:v1:<base64>`.
+    pub fn decrypt(&self, src: &str) -> Result<String, SecretError> {
+        String::from_utf8(self.decrypt_bytes(src)?).map_err(|_| SecretError::DecryptionFailed)
+    }
+
+    /// Decrypt `dotman:v1:<base64>` into binary.
+    pub fn decrypt_bytes(&self, src: &str) -> Result<Vec<u8>, SecretError> {
+        let buf = src
+            .trim()
+            .strip_prefix(PREFIX)
+            .and_then(|src| base64::engine::general_purpose::STANDARD.decode(src).ok())
+            .filter(|buf| buf.len() > SALT_LEN + NONCE_LEN)
+            .ok_or(SecretError::InvalidFormat)?;
+        let (salt, rest) = buf.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        self.cipher(salt)?
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| SecretError::DecryptionFailed)
+    }
+}
+
+/// Return ciphertext if `value` is `{encrypted: <string>}`.
+pub fn as_secret(value: &dyn ValueView) -> Option<String> {
+    let obj = value.as_object().filter(|obj| obj.size() == 1)?;
+    obj.get("encrypted")?
+        .as_scalar()
+        .map(|ciphertext| ciphertext.to_kstr().to_string())
+}
+
+fn contains_secret(value: &dyn ValueView) -> bool {
+    as_secret(value).is_some()
+        || value
+            .as_object()
+            .is_some_and(|obj| obj.values().any(contains_secret))
+        || value
+            .as_array()
+            .is_some_and(|arr| arr.values().any(contains_secret))
+}
+
+fn decrypt_value(
+    value: &dyn ValueView,
+    keyring: &Keyring,
+) -> Result<liquid::model::Value, SecretError> {
+    if let Some(ciphertext) = as_secret(value) {
+        return Ok(liquid::model::Value::scalar(keyring.reveal(&ciphertext)?));
+    }
+    if let Some(obj) = value.as_object() {
+        Ok(liquid::model::Value::Object(
+            obj.iter()
+                .map(|(key, value)| Ok((key.into_owned(), decrypt_value(value, keyring)?)))
+                .collect::<Result<_, SecretError>>()?,
+        ))
+    } else if let Some(arr) = value.as_array() {
+        Ok(liquid::model::Value::Array(
+            arr.values()
+                .map(|value| decrypt_value(value, keyring))
+                .collect::<Result<_, _>>()?,
+        ))
+    } else {
+        Ok(value.to_value())
+    }
+}
+
+/// Copy of `value` looked up by a template with its secrets decrypted.
+/// Return `None` if `value` contains no secret.
+pub fn reveal(
+    value: &dyn ValueView,
+    keyring: &Keyring,
+) -> Result<Option<liquid::model::Value>, SecretError> {
+    if contains_secret(value) {
+        decrypt_value(value, keyring).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Liquid runtime revealing secret variables as templates look them up, including from
+/// partials, loops and conditions. Revealed secrets mark the render sensitive.
+pub(crate) struct Revealing<'k, R> {
+    inner: R,
+    keyring: &'k Keyring,
+}
+
+/// Decryption failure of a lookup during a liquid render.
+#[derive(Default)]
+struct Failure(Option<SecretError>);
+
+impl<'k, R: Runtime> Revealing<'k, R> {
+    pub(crate) fn new(inner: R, keyring: &'k Keyring) -> Self {
+        Self { inner, keyring }
+    }
+
+    /// Decryption failure of the render, if any. Lookups in conditions cannot fail by
+    /// themselves, so failures are kept until the render ends.
+    pub(crate) fn failure(&self) -> Option<SecretError> {
+        self.registers().get_mut::<Failure>().0.take()
+    }
+
+    fn reveal(&self, value: &dyn ValueView) -> Option<liquid::model::Value> {
+        match reveal(value, self.keyring) {
+            Ok(revealed) => {
+                if revealed.is_some() {
+                    Usage::liquid(self).sensitive = true;
+                }
+                revealed
+            }
+            Err(e) => {
+                self.registers().get_mut::<Failure>().0 = Some(e);
+                None
+            }
+        }
+    }
+}
+
+impl<R: Runtime> Runtime for Revealing<'_, R> {
+    fn partials(&self) -> &dyn PartialStore {
+        self.inner.partials()
+    }
+
+    fn name(&self) -> Option<KStringRef<'_>> {
+        self.inner.name()
+    }
+
+    fn roots(&self) -> BTreeSet<KStringCow<'_>> {
+        self.inner.roots()
+    }
+
+    fn try_get(&self, path: &[ScalarCow<'_>]) -> Option<ValueCow<'_>> {
+        let value = self.inner.try_get(path)?;
+        let revealed = self.reveal(value.as_view());
+        Some(revealed.map_or(value, ValueCow::Owned))
+    }
+
+    fn get(&self, path: &[ScalarCow<'_>]) -> liquid_core::Result<ValueCow<'_>> {
+        let value = self.inner.get(path)?;
+        let revealed = self.reveal(value.as_view());
+        if let Some(e) = &self.registers().get_mut::<Failure>().0 {
+            return Err(liquid::Error::with_msg(e.to_string()));
+        }
+        Ok(revealed.map_or(value, ValueCow::Owned))
+    }
+
+    fn set_global(&self, name: KString, val: liquid::model::Value) -> Option<liquid::model::Value> {
+        self.inner.set_global(name, val)
+    }
+
+    fn set_index(&self, name: KString, val: liquid::model::Value) -> Option<liquid::model::Value> {
+        self.inner.set_index(name, val)
+    }
+
+    fn get_index<'a>(&'a self, name: &str) -> Option<ValueCow<'a>> {
+        self.inner.get_index(name)
+    }
+
+    fn registers(&self) -> &Registers {
+        self.inner.registers()
+    }
+}
+
+/// Variables of a Jinja render revealing secrets as templates look them up. Revealed secrets
+/// mark the render sensitive in `usage`, which is also looked up by filters.
+#[derive(Debug)]
+pub(crate) struct JinjaVars {
+    vars: liquid::Object,
+    keyring: Keyring,
+    usage: JinjaUsage,
+    failure: Arc<Mutex<Option<SecretError>>>,
+}
+
+impl JinjaVars {
+    pub(crate) fn new(vars: liquid::Object, keyring: &Keyring, usage: JinjaUsage) -> Self {
+        Self {
+            vars,
+            keyring: keyring.clone(),
+            usage,
+            failure: Default::default(),
+        }
+    }
+
+    /// Decryption failure of the render, looked up variables being undefined.
+    pub(crate) fn failure(&self) -> Arc<Mutex<Option<SecretError>>> {
+        self.failure.clone()
+    }
+}
+
+impl minijinja::value::StructObject for JinjaVars {
+    fn get_field(&self, name: &str) -> Option<minijinja::Value> {
+        if name == JinjaUsage::NAME {
+            return Some(minijinja::Value::from_object(self.usage.clone()));
+        }
+        let value = self.vars.get(name)?;
+        match reveal(value, &self.keyring) {
+            Ok(Some(revealed)) => {
+                self.usage.0.lock().expect("poisoned").sensitive = true;
+                Some(minijinja::Value::from_serialize(&revealed))
+            }
+            Ok(None) => Some(minijinja::Value::from_serialize(value)),
+            Err(e) => {
+                *self.failure.lock().expect("poisoned") = Some(e);
+                None
+            }
+        }
+    }
+
+    fn fields(&self) -> Vec<Arc<str>> {
+        self.vars
+            .keys()
+            .map(|name| Arc::from(name.as_str()))
+            .collect()
+    }
+}
+
+/// Replace ciphertext `old` of variable `key` with `new` in `src` of vars file `path`.
+/// Other variables are kept intact even if they hold the same ciphertext.
+pub fn replace_ciphertext(
+    path: &Path,
+    src: &str,
+    key: &str,
+    old: &str,
+    new: &str,
+) -> Result<String, Error> {
+    let load =
+        |src: &str| crate::vars::parse(&crate::vars::parse_file_content(path, src)?, "vars file");
+    let vars = load(src)?;
+    src.match_indices(old)
+        .map(|(pos, _)| format!("{}{}{}", &src[..pos], new, &src[pos + old.len()..]))
+        .find(|updated| {
+            load(updated).is_ok_and(|updated| {
+                updated.len() == vars.len()
+                    && updated
+                        .iter()
+                        .all(|(name, value)| (name == key) != (vars.get(name) == Some(value)))
+            })
+        })
+        .ok_or_else(|| {
+            Error::CannotProcessSecret(format!("cannot find ciphertext of {} in {:?}", key, path))
+        })
+}
+
+#[derive(Clone)]
+struct SecretFilterParser {
+    keyring: Keyring,
+}
+
+impl FilterReflection for SecretFilterParser {
+    fn name(&self) -> &str {
+        "secret"
+    }
+
+    fn description(&self) -> &str {
+        "Look up secret by name from secret_provider."
+    }
+
+    fn positional_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+
+    fn keyword_parameters(&self) -> &'static [ParameterReflection] {
+        &[]
+    }
+}
+
+impl ParseFilter for SecretFilterParser {
+    fn parse(&self, mut arguments: FilterArguments) -> liquid_core::Result<Box<dyn Filter>> {
+        if arguments.positional.next().is_some() || arguments.keyword.next().is_some() {
+            return Err(liquid::Error::with_msg("secret filter takes no arguments"));
+        }
+        Ok(Box::new(SecretFilter {
+            keyring: self.keyring.clone(),
+        }))
+    }
+
+    fn reflection(&self) -> &dyn FilterReflection {
+        self
+    }
+}
+
+#[derive(Debug)]
+struct SecretFilter {
+    keyring: Keyring,
+}
+
+impl fmt::Display for SecretFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("secret")
+    }
+}
+
+impl Filter for SecretFilter {
+    fn evaluate(
+        &self,
+        input: &dyn ValueView,
+        runtime: &dyn Runtime,
+    ) -> liquid_core::Result<liquid::model::Value> {
+        Usage::liquid(runtime).sensitive = true;
+        self.keyring
+            .lookup(&input.to_kstr())
+            .map(liquid::model::Value::scalar)
+            .map_err(|e| liquid::Error::with_msg(e.to_string()))
+    }
+}
+
+/// `secret` filter backed by `keyring`.
+pub(crate) fn filter(keyring: &Keyring) -> Box<dyn ParseFilter> {
+    Box::new(SecretFilterParser {
+        keyring: keyring.clone(),
+    })
+}
+
+/// Rendered template.
+#[derive(Debug)]
+pub struct Rendered {
+    /// Rendered text
+    pub text: String,
+    /// Whether the text contains secrets. Such text must not be shown and should be
+    /// written with restrictive permissions.
+    pub sensitive: bool,
+    /// Whether the text depends on more than variables, such as files read by the template
+    pub volatile: bool,
+}
+
+/// Rendered template from `result` of a render with `usage`. A decryption `failure`
+/// is reported as is, and other render errors of templates referring secrets are replaced
+/// with a generic message not to leak them.
+pub(crate) fn rendered(
+    result: Result<String, liquid::Error>,
+    failure: Option<SecretError>,
+    usage: Usage,
+) -> Result<Rendered, liquid::Error> {
+    if let Some(e) = failure {
+        return Err(liquid::Error::with_msg(e.to_string()));
+    }
+    let sensitive = usage.sensitive;
+    let text = result.map_err(|e| {
+        if sensitive {
+            liquid::Error::with_msg("cannot render template referring secrets")
+        } else {
+            e
+        }
+    })?;
+    Ok(Rendered {
+        text,
+        sensitive,
+        volatile: usage.volatile,
+    })
+}
+
+const REDACTED: &[u8] = b"[redacted]";
+
+/// Replace every line of sensitive `content`, showing its shape but not its text.
+pub fn redact(content: &[u8]) -> Vec<u8> {
+    content
+        .split_inclusive(|&b| b == b'\n')
+        .flat_map(|line| {
+            let newline = if line.ends_with(b"\n") {
+                &b"\n"[..]
+            } else {
+                b""
+            };
+            [REDACTED, newline].concat()
+        })
+        .collect()
+}
+
+/// Replace lines of unified diff `diff` of sensitive content, keeping file headers, hunk ranges
+/// and whether each line is kept, added or removed. Kept lines are replaced as well, since a
+/// secret may be unchanged.
+pub fn redact_diff(diff: &[u8]) -> Vec<u8> {
+    let mut in_hunk = false;
+    diff.split_inclusive(|&b| b == b'\n')
+        .flat_map(|line| {
+            in_hunk |= line.starts_with(b"@@");
+            if !in_hunk || line.starts_with(b"@@") || line.starts_with(b"\\") {
+                line.to_vec()
+            } else {
+                [&line[..1], REDACTED, b"\n"].concat()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::filters::Filters;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let keyring = Keyring::from_passphrase(b"passphrase");
+        let ciphertext = keyring.encrypt("hunter2").unwrap();
+        assert!(ciphertext.starts_with(PREFIX));
+        assert!(!ciphertext.contains("hunter2"));
+        assert_eq!(keyring.decrypt(&ciphertext).unwrap(), "hunter2");
+        let wrong = Keyring::from_passphrase(b"wrong");
+        assert!(matches!(
+            wrong.decrypt(&ciphertext),
+            Err(SecretError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            keyring.decrypt("hunter2"),
+            Err(SecretError::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn test_render_lazily() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-reveal-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("netrc.liquid"), "password {{ token }}").unwrap();
+        let keyring = Keyring::from_passphrase(b"passphrase");
+        let mut secret = liquid::Object::new();
+        secret.insert(
+            "encrypted".into(),
+            liquid::model::Value::scalar(keyring.encrypt("hunter2").unwrap()),
+        );
+        let mut vars = liquid::Object::new();
+        vars.insert("token".into(), liquid::model::Value::Object(secret.clone()));
+        vars.insert(
+            "broken".into(),
+            liquid::model::Value::Object(liquid::object!({ "encrypted": "dotman:v1:AAAA" })),
+        );
+        vars.insert("note".into(), liquid::model::Value::scalar("token"));
+        let filters = Filters::new(PathBuf::new(), false, keyring)
+            .with_partials(crate::partials::Partials::load(&dir, true).unwrap());
+        for engine in [Engine::Liquid, Engine::Jinja] {
+            let render = |src: &str| engine.get().render(src, &vars, &filters);
+            let rendered = render("token = {{ token }}").unwrap();
+            assert_eq!(rendered.text, "token = hunter2");
+            assert!(rendered.sensitive);
+            // secrets are revealed when partials look them up
+            let rendered = render("{% include 'netrc' %}").unwrap();
+            assert_eq!(rendered.text, "password hunter2");
+            assert!(rendered.sensitive);
+            // broken secret is not decrypted since it is not looked up
+            let rendered = render("plain").unwrap();
+            assert_eq!(rendered.text, "plain");
+            assert!(!rendered.sensitive);
+            // mentioning the name of a secret does not make the text sensitive
+            let rendered = render("{{ note }}").unwrap();
+            assert_eq!(rendered.text, "token");
+            assert!(!rendered.sensitive);
+            let e = render("{{ broken }}").unwrap_err().to_string();
+            let reason = filters.keyring().decrypt("dotman:v1:AAAA").unwrap_err();
+            assert!(e.contains(&reason.to_string()), "{}", e);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replace_ciphertext() {
+        let path = Path::new("secrets.yaml");
+        let src = concat!(
+            "# copied\n",
+            "gitlab_token: { encrypted: \"dotman:v1:AAAA\" }\n",
+            "github_token: { encrypted: \"dotman:v1:AAAA\" }\n",
+        );
+        assert_eq!(
+            replace_ciphertext(
+                path,
+                src,
+                "github_token",
+                "dotman:v1:AAAA",
+                "dotman:v1:BBBB"
+            )
+            .unwrap(),
+            concat!(
+                "# copied\n",
+                "gitlab_token: { encrypted: \"dotman:v1:AAAA\" }\n",
+                "github_token: { encrypted: \"dotman:v1:BBBB\" }\n",
+            )
+        );
+        assert!(replace_ciphertext(
+            path,
+            src,
+            "github_token",
+            "dotman:v1:CCCC",
+            "dotman:v1:BBBB"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact(b"token = s3cr3t\nuser\nlast"),
+            b"[redacted]\n[redacted]\n[redacted]"
+        );
+        let diff = concat!(
+            "--- dest\n",
+            "+++ rendered\n",
+            "@@ -1,2 +1,2 @@\n",
+            " token = s3cr3t\n",
+            "--- s3cr3t\n",
+            "+user\n",
+            "\\ No newline at end of file\n",
+        );
+        assert_eq!(
+            String::from_utf8(redact_diff(diff.as_bytes())).unwrap(),
+            concat!(
+                "--- dest\n",
+                "+++ rendered\n",
+                "@@ -1,2 +1,2 @@\n",
+                " [redacted]\n",
+                "-[redacted]\n",
+                "+[redacted]\n",
+                "\\ No newline at end of file\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_secret_filter() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-secret-{}", process::id()));
+        fs::create_dir_all(dir.join("github")).unwrap();
+        let stub = dir.join("stub.sh");
+        fs::write(
+            &stub,
+            "#!/bin/sh\necho \"stub:$1\"\necho called >> \"$0.log\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("github/token"), "from-file\n").unwrap();
+        std::env::set_var("DOTMAN_TEST_SECRET_GITHUB_TOKEN", "from-env");
+        let src = r#"{{ "github/token" | secret }}"#;
+        let vars = liquid::Object::new();
+        let render_with = |provider: Provider| {
+            let keyring = Keyring::default().with_provider(provider);
+            let filters = Filters::new(PathBuf::new(), false, keyring);
+            let first = Engine::Liquid.get().render(src, &vars, &filters).unwrap();
+            let second = Engine::Liquid.get().render(src, &vars, &filters).unwrap();
+            assert!(first.sensitive);
+            assert_eq!(first.text, second.text);
+            first.text
+        };
+        assert_eq!(
+            render_with(Provider::Command(
+                "sh".to_owned(),
+                vec![stub.to_str().unwrap().to_owned()]
+            )),
+            "stub:github/token"
+        );
+        // cached per run
+        assert_eq!(
+            fs::read_to_string(dir.join("stub.sh.log")).unwrap(),
+            "called\n"
+        );
+        assert_eq!(
+            render_with(Provider::Env("DOTMAN_TEST_SECRET_".to_owned())),
+            "from-env"
+        );
+        assert_eq!(render_with(Provider::File(dir.clone())), "from-file");
+        // secrets looked up by partials make the text sensitive
+        fs::create_dir_all(dir.join("partials")).unwrap();
+        fs::write(dir.join("partials/token"), src).unwrap();
+        let keyring =
+            Keyring::default().with_provider(Provider::Env("DOTMAN_TEST_SECRET_".to_owned()));
+        let filters = Filters::new(PathBuf::new(), false, keyring)
+            .with_partials(crate::partials::Partials::load(&dir.join("partials"), true).unwrap());
+        for engine in [Engine::Liquid, Engine::Jinja] {
+            let rendered = engine
+                .get()
+                .render("{% include 'token' %}", &vars, &filters)
+                .unwrap();
+            assert_eq!(rendered.text, "from-env");
+            assert!(rendered.sensitive);
+        }
+        let store = Provider::File(dir.join("github"));
+        fs::write(dir.join("outside"), "outside").unwrap();
+        for name in [
+            "../outside",
+            "/etc/passwd",
+            "token/../../outside",
+            "",
+            "./token",
+        ] {
+            assert!(matches!(
+                store.fetch(name),
+                Err(SecretError::ProviderFailed(..))
+            ));
+        }
+        assert!(Engine::Liquid
+            .get()
+            .render(src, &vars, &Filters::default())
+            .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+//! Record of the last deployed state, which lets later runs skip files that did not change.
+//!
+//! After a successful deploy, `$XDG_STATE_HOME/dotman/state.json` (`~/.local/state` if unset)
+//! holds what `cp` and `link` produced for each destination: stat and hash of the source, hash
+//! of the output, hash of the variables it was rendered with and stat of the destination.
+//! A file whose source, variables and destination still match its record is not read again.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const STATE: &str = "state.json";
+
+/// Identity of a file which changes whenever the file is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stat {
+    /// Size in bytes
+    pub size: u64,
+    /// Modification time in seconds
+    pub mtime: i64,
+    /// Nanoseconds of the modification time
+    pub mtime_nsec: i64,
+    /// Status change time in seconds, updated by any write even if mtime is restored
+    pub ctime: i64,
+    /// Nanoseconds of the status change time
+    pub ctime_nsec: i64,
+    /// Inode number
+    pub ino: u64,
+}
+
+impl Stat {
+    /// Stat of a file whose metadata is `meta`.
+    pub fn of(meta: &std::fs::Metadata) -> Self {
+        Self {
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            ino: meta.ino(),
+        }
+    }
+}
+
+/// What a task produced at a destination.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Source file (or target of a symlink)
+    pub src: PathBuf,
+    /// Stat of the source file (`None` for symlinks)
+    pub src_stat: Option<Stat>,
+    /// Hash of the source content
+    pub src_hash: Option<String>,
+    /// Hash of the variables, engine and partials the output was rendered with
+    pub inputs: Option<String>,
+    /// Hash of the deployed content
+    pub output_hash: Option<String>,
+    /// Stat of the destination (of the symlink itself for symlinks)
+    pub dest: Stat,
+}
+
+/// Format `hash` to be recorded.
+pub fn hex(hash: u128) -> String {
+    format!("{:032x}", hash)
+}
+
+/// Default path of the record.
+pub fn path() -> Option<PathBuf> {
+    crate::util::state_dir().map(|dir| dir.join(STATE))
+}
+
+/// Records of the last run and of this run, shared by tasks.
+#[derive(Debug, Clone)]
+pub struct State {
+    path: PathBuf,
+    /// Ignore records of the last run (`--full`)
+    full: bool,
+    last: Arc<HashMap<PathBuf, Record>>,
+    /// Records of this run. `None` forgets the record of the last run.
+    current: Arc<Mutex<HashMap<PathBuf, Option<Record>>>>,
+}
+
+impl State {
+    /// Load the record at `path`. Missing or broken records are treated as empty, since they
+    /// only save time. Records are not consulted but still updated if `full`.
+    pub fn load(path: &Path, full: bool) -> Self {
+        let last = std::fs::read(path)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default();
+        Self {
+            path: path.to_owned(),
+            full,
+            last: Arc::new(last),
+            current: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Record of `dest` from the last run.
+    pub fn get(&self, dest: &Path) -> Option<&Record> {
+        if self.full {
+            None
+        } else {
+            self.last.get(dest)
+        }
+    }
+
+    /// Record what is deployed at `dest`.
+    pub fn record(&self, dest: &Path, record: Record) {
+        if let Ok(mut current) = self.current.lock() {
+            current.insert(dest.to_owned(), Some(record));
+        }
+    }
+
+    /// Forget records of `dest` and its descendants, which are removed.
+    pub fn forget(&self, dest: &Path) {
+        if let Ok(mut current) = self.current.lock() {
+            for path in self.last.keys().filter(|path| path.starts_with(dest)) {
+                current.insert(path.clone(), None);
+            }
+            for (_, record) in current
+                .iter_mut()
+                .filter(|(path, _)| path.starts_with(dest))
+            {
+                *record = None;
+            }
+        }
+    }
+
+    /// Write records of the last run updated by this run. Destinations this run did not touch
+    /// (e.g. of other scenarios) keep their records.
+    pub async fn save(&self) -> io::Result<()> {
+        let mut records = self.last.as_ref().clone();
+        {
+            let current = self
+                .current
+                .lock()
+                .map_err(|_| io::Error::other("state is poisoned"))?;
+            for (path, record) in current.iter() {
+                match record {
+                    Some(record) => records.insert(path.clone(), record.clone()),
+                    None => records.remove(path),
+                };
+            }
+        }
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let json = serde_json::to_vec(&records).map_err(io::Error::other)?;
+        crate::util::write_atomic(&self.path, &json, Some(0o600)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_state() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-state-{}", std::process::id()));
+        let path = dir.join("state.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a"), "a").unwrap();
+        let stat = Stat::of(&std::fs::metadata(dir.join("a")).unwrap());
+        let record = Record {
+            src: dir.join("src"),
+            src_stat: Some(stat),
+            src_hash: Some(hex(1)),
+            inputs: None,
+            output_hash: Some(hex(1)),
+            dest: stat,
+        };
+
+        let state = State::load(&path, false);
+        assert!(state.get(&dir.join("a")).is_none());
+        state.record(&dir.join("a"), record.clone());
+        state.record(&dir.join("b/c"), record.clone());
+        state.save().await.unwrap();
+
+        let state = State::load(&path, false);
+        assert_eq!(state.get(&dir.join("a")), Some(&record));
+        assert!(State::load(&path, true).get(&dir.join("a")).is_none());
+        state.forget(&dir.join("b"));
+        state.save().await.unwrap();
+        let state = State::load(&path, false);
+        assert!(state.get(&dir.join("b/c")).is_none());
+        // untouched records are kept
+        assert_eq!(state.get(&dir.join("a")), Some(&record));
+
+        std::fs::write(&path, "broken").unwrap();
+        assert!(State::load(&path, false).get(&dir.join("a")).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
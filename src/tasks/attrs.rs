@@ -0,0 +1,342 @@
+//! Attributes (`mode`, `dir_mode`, `owner` and `group`) of deployed files.
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use crate::ast::Value;
+use crate::Error;
+
+/// Attributes to set on deployed files and directories. `None` keeps the current value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// Permission bits of files
+    pub mode: Option<u32>,
+    /// Permission bits of directories
+    pub dir_mode: Option<u32>,
+    /// User name or uid
+    pub owner: Option<String>,
+    /// Group name or gid
+    pub group: Option<String>,
+}
+
+impl Attributes {
+    /// Whether no attribute is managed.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Whether attributes of files are managed.
+    pub fn manages_file(&self) -> bool {
+        self.mode.is_some() || self.owner.is_some() || self.group.is_some()
+    }
+
+    /// Whether attributes of directories are managed.
+    pub fn manages_dir(&self) -> bool {
+        self.dir_mode.is_some() || self.owner.is_some() || self.group.is_some()
+    }
+
+    /// Attributes of `self` overridden by `other`.
+    fn overlay(&self, other: &Self) -> Self {
+        Self {
+            mode: other.mode.or(self.mode),
+            dir_mode: other.dir_mode.or(self.dir_mode),
+            owner: other.owner.clone().or_else(|| self.owner.clone()),
+            group: other.group.clone().or_else(|| self.group.clone()),
+        }
+    }
+}
+
+/// Attributes of a task with per-path overrides (`attributes`).
+#[derive(Debug, Clone)]
+pub struct AttributeRules {
+    base: Attributes,
+    targets: GlobSet,
+    /// Index of the override which owns each pattern of `targets`
+    owners: Vec<usize>,
+    overrides: Vec<Attributes>,
+}
+
+impl Default for AttributeRules {
+    fn default() -> Self {
+        Self {
+            base: Attributes::default(),
+            targets: GlobSet::empty(),
+            owners: Vec::new(),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+impl AttributeRules {
+    /// Whether any file gets attributes set, by the task or by an override.
+    pub fn manages_files(&self) -> bool {
+        self.base.manages_file() || self.overrides.iter().any(Attributes::manages_file)
+    }
+
+    /// Attributes of `target` (relative to playbook). The first matched override wins.
+    pub fn of(&self, target: &Path) -> Attributes {
+        self.targets
+            .matches(target)
+            .into_iter()
+            .map(|idx| self.owners[idx])
+            .min()
+            .map(|owner| self.base.overlay(&self.overrides[owner]))
+            .unwrap_or_else(|| self.base.clone())
+    }
+}
+
+fn parse_mode(yaml: &Value, key: &str) -> Result<u32, Error> {
+    // Unquoted modes are rejected since YAML has already converted them to integers,
+    // `0600` as decimal 600 and `0o600` as 384, and what was written cannot be told.
+    let digits = match yaml {
+        Value::Str(mode) => mode.strip_prefix("0o").unwrap_or(mode),
+        _ => "",
+    };
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| {
+            Error::InvalidPlaybook(
+                format!("{} must be quoted octal permission bits e.g. \"0644\"", key),
+                yaml.to_owned(),
+            )
+        })
+}
+
+fn parse_name(yaml: &Value, key: &str) -> Result<String, Error> {
+    match yaml {
+        Value::Str(name) => Ok(name.to_owned()),
+        Value::Int(id) if *id >= 0 => Ok(id.to_string()),
+        _ => Err(Error::InvalidPlaybook(
+            format!("{} must be name or id", key),
+            yaml.to_owned(),
+        )),
+    }
+}
+
+fn parse_attributes(obj: &HashMap<String, Value>, prefix: &str) -> Result<Attributes, Error> {
+    Ok(Attributes {
+        mode: obj
+            .get("mode")
+            .map(|mode| parse_mode(mode, &format!("{}.mode", prefix)))
+            .transpose()?,
+        dir_mode: obj
+            .get("dir_mode")
+            .map(|mode| parse_mode(mode, &format!("{}.dir_mode", prefix)))
+            .transpose()?,
+        owner: obj
+            .get("owner")
+            .map(|owner| parse_name(owner, &format!("{}.owner", prefix)))
+            .transpose()?,
+        group: obj
+            .get("group")
+            .map(|group| parse_name(group, &format!("{}.group", prefix)))
+            .transpose()?,
+    })
+}
+
+/// Parse `mode`, `dir_mode`, `owner`, `group` and `attributes` of task `obj`.
+pub fn parse(obj: &HashMap<String, Value>, prefix: &str) -> Result<AttributeRules, Error> {
+    let base = parse_attributes(obj, prefix)?;
+    let mut targets = GlobSetBuilder::new();
+    let mut owners = Vec::new();
+    let mut overrides = Vec::new();
+    let entries = match obj.get("attributes") {
+        Some(Value::Array(entries)) => entries.as_slice(),
+        Some(invalid) => {
+            return Err(Error::InvalidPlaybook(
+                format!("{}.attributes must be array", prefix),
+                invalid.to_owned(),
+            ))
+        }
+        None => &[],
+    };
+    let entry_prefix = format!("{}.attributes", prefix);
+    for (owner, entry) in entries.iter().enumerate() {
+        let hash = entry.as_hash().ok_or_else(|| {
+            Error::InvalidPlaybook(format!("{} must be hash", entry_prefix), entry.to_owned())
+        })?;
+        crate::ast::verify_hash(
+            hash,
+            &["target", "mode", "dir_mode", "owner", "group"],
+            Some(&entry_prefix),
+        )?;
+        let patterns = match hash.get("target") {
+            Some(Value::Str(target)) => vec![target.as_str()],
+            Some(Value::Array(patterns)) => patterns
+                .iter()
+                .map(|pattern| {
+                    pattern.as_str().ok_or_else(|| {
+                        Error::InvalidPlaybook(
+                            format!("{}.target must be string or array of string", entry_prefix),
+                            pattern.to_owned(),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => {
+                return Err(Error::InvalidPlaybook(
+                    format!("{} must have \"target\"", entry_prefix),
+                    entry.to_owned(),
+                ))
+            }
+        };
+        for pattern in patterns {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| {
+                    Error::InvalidPlaybook(
+                        format!("invalid {}.target {} due to {}", entry_prefix, pattern, e),
+                        Value::Str(pattern.to_owned()),
+                    )
+                })?;
+            targets.add(glob);
+            owners.push(owner);
+        }
+        overrides.push(parse_attributes(hash, &entry_prefix)?);
+    }
+    Ok(AttributeRules {
+        base,
+        targets: targets.build().map_err(|e| {
+            Error::PlaybookLoadFailed(format!("cannot compile {} due to {}", entry_prefix, e))
+        })?,
+        owners,
+        overrides,
+    })
+}
+
+fn uid_of(owner: &str) -> anyhow::Result<u32> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(owner)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16384];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        anyhow::bail!("user {} is not found", owner);
+    }
+    Ok(passwd.pw_uid)
+}
+
+fn gid_of(group: &str) -> anyhow::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16384];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        anyhow::bail!("group {} is not found", group);
+    }
+    Ok(entry.gr_gid)
+}
+
+/// Compare attributes of `path` with `attrs` and set them unless `dryrun`.
+/// Returns descriptions of differences.
+pub fn apply(path: &Path, attrs: &Attributes, dryrun: bool) -> anyhow::Result<Vec<String>> {
+    let meta = std::fs::metadata(path)?;
+    let mut diffs = Vec::new();
+    let uid = attrs.owner.as_deref().map(uid_of).transpose()?;
+    let gid = attrs.group.as_deref().map(gid_of).transpose()?;
+    let uid = uid.filter(|uid| *uid != meta.uid());
+    let gid = gid.filter(|gid| *gid != meta.gid());
+    if let Some(uid) = uid {
+        diffs.push(format!("owner of {:?} {} -> {}", path, meta.uid(), uid));
+    }
+    if let Some(gid) = gid {
+        diffs.push(format!("group of {:?} {} -> {}", path, meta.gid(), gid));
+    }
+    if (uid.is_some() || gid.is_some()) && !dryrun {
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    let mode = if meta.is_dir() {
+        attrs.dir_mode
+    } else {
+        attrs.mode
+    };
+    let current = meta.permissions().mode() & 0o7777;
+    if let Some(mode) = mode.filter(|mode| *mode != current) {
+        diffs.push(format!("mode of {:?} {:o} -> {:o}", path, current, mode));
+        if !dryrun {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_attributes() {
+        let src = concat!(
+            "mode: \"0644\"\n",
+            "dir_mode: \"0755\"\n",
+            "owner: root\n",
+            "attributes:\n",
+            "- { target: \"pkgs/ssh/*\", mode: \"600\" }\n",
+            "- { target: [pkgs/ssh/config, pkgs/gnupg], dir_mode: \"0o700\", group: 0 }\n",
+        );
+        let yaml = YamlLoader::load_from_str(src).unwrap();
+        let ast = Value::from_yaml(yaml[0].clone()).unwrap();
+        let rules = parse(ast.as_hash().unwrap(), "tasks.cp").unwrap();
+        assert_eq!(
+            rules.of(Path::new("pkgs/fish/config.fish")),
+            Attributes {
+                mode: Some(0o644),
+                dir_mode: Some(0o755),
+                owner: Some("root".to_owned()),
+                group: None,
+            }
+        );
+        assert_eq!(rules.of(Path::new("pkgs/ssh/config")).mode, Some(0o600));
+        assert_eq!(rules.of(Path::new("pkgs/ssh/config")).group, None);
+        assert_eq!(rules.of(Path::new("pkgs/gnupg")).dir_mode, Some(0o700));
+        assert_eq!(
+            rules.of(Path::new("pkgs/gnupg")).group,
+            Some("0".to_owned())
+        );
+        for src in [
+            "mode: \"0999\"",
+            "mode: 0644",
+            "mode: 0o644",
+            "dir_mode: 700",
+        ] {
+            let yaml = YamlLoader::load_from_str(src).unwrap();
+            let ast = Value::from_yaml(yaml[0].clone()).unwrap();
+            assert!(
+                parse(ast.as_hash().unwrap(), "tasks.cp").is_err(),
+                "{}",
+                src
+            );
+        }
+        assert_eq!(uid_of("root").unwrap(), 0);
+        assert_eq!(uid_of("1000").unwrap(), 1000);
+        assert!(uid_of("dotman-no-such-user").is_err());
+    }
+}
@@ -45,19 +45,6 @@ fn parse_brew_list(src: &str) -> IResult<&str, HashMap<String, String>> {
     ))
 }
 
-#[cfg(test)]
-mod test_brew_list_parser {
-    use super::*;
-
-    #[test]
-    fn test_parse_installed_packages() {
-        assert_eq!(
-            parse_installed_package("arm-none-eabi-gcc 10.3-2021.07").unwrap(),
-            ("", ("arm-none-eabi-gcc", "10.3-2021.07"))
-        );
-    }
-}
-
 async fn calcurate_cache() -> Result<Cache, TaskError> {
     let output_formulae = process::Command::new("brew")
         .arg("list")
@@ -221,3 +208,16 @@ pub fn parse(obj: &HashMap<String, crate::ast::Value>) -> Result<crate::TaskEnti
         )),
     }
 }
+
+#[cfg(test)]
+mod test_brew_list_parser {
+    use super::*;
+
+    #[test]
+    fn test_parse_installed_packages() {
+        assert_eq!(
+            parse_installed_package("arm-none-eabi-gcc 10.3-2021.07").unwrap(),
+            ("", ("arm-none-eabi-gcc", "10.3-2021.07"))
+        );
+    }
+}
@@ -2,20 +2,117 @@
 use futures::future::BoxFuture;
 use futures::stream::StreamExt;
 use futures::FutureExt;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use kstring::KStringBase;
+use liquid::model::ValueView;
 use std::collections::HashMap;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
 use tokio::{fs, io};
+use xxhash_rust::xxh3;
 
+use super::attrs::{self, AttributeRules, Attributes};
+use super::exclude;
+use crate::engine::Engine;
+use crate::state::{self, Stat, State};
+use crate::util::{describe_render_error, reflink_atomic, write_atomic};
 use crate::TaskEntity;
 
-type Templates = HashMap<Vec<String>, liquid::Object>;
+/// Compiled `templates` section with `template` and `liquid_suffix` options.
+#[derive(Debug, Clone)]
+struct Templates {
+    /// `vars` of each entry of `templates`
+    vars: Vec<liquid::Object>,
+    /// `engine` of each entry of `templates`
+    engines: Vec<Option<Engine>>,
+    /// `target` patterns of all entries, relative to playbook
+    targets: GlobSet,
+    /// Index of the entry which owns each pattern of `targets`
+    owners: Vec<usize>,
+    /// Render every file (`template: true`)
+    all: bool,
+    /// Render `*.liquid` and strip the suffix (`liquid_suffix: true`)
+    liquid_suffix: bool,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            vars: Vec::new(),
+            engines: Vec::new(),
+            targets: GlobSet::empty(),
+            owners: Vec::new(),
+            all: false,
+            liquid_suffix: false,
+        }
+    }
+}
+
+/// Variables and template engine to render a file.
+#[derive(Debug, Clone, Copy)]
+struct VarSet<'a> {
+    vars: &'a liquid::Object,
+    /// Engine of the playbook is used if `None`
+    engine: Option<Engine>,
+}
+
+impl Templates {
+    /// Select variables to render `target`. The first matched entry of `templates` wins,
+    /// and `default` is used for files rendered by `template` or `liquid_suffix` option.
+    fn select<'a>(&'a self, target: &Path, default: &'a liquid::Object) -> Option<VarSet<'a>> {
+        if let Some(owner) = self
+            .targets
+            .matches(target)
+            .into_iter()
+            .map(|idx| self.owners[idx])
+            .min()
+        {
+            Some(VarSet {
+                vars: &self.vars[owner],
+                engine: self.engines[owner],
+            })
+        } else if self.all || (self.liquid_suffix && is_liquid_file(target)) {
+            Some(VarSet {
+                vars: default,
+                engine: None,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// How `cp` treats symlinks in the source tree (`symlinks` option).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Symlinks {
+    /// Replicate symlinks as symlinks
+    #[default]
+    Preserve,
+    /// Copy what symlinks point to
+    Follow,
+    /// Fail on symlinks
+    Error,
+}
+
+/// How `cp` materializes files which are neither templates nor decrypted (`strategy` option).
+/// Those are always written as copies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Strategy {
+    /// Copy files
+    #[default]
+    Copy,
+    /// Hardlink the source file
+    Hardlink,
+    /// Copy sharing data blocks with the source where the filesystem supports it
+    Reflink,
+    /// Symlink to the source file
+    Symlink,
+}
 
 #[derive(Debug, Clone)]
 enum FileType {
-    #[allow(dead_code)]
     Symlink(PathBuf),
     File(PathBuf),
     Other(PathBuf),
@@ -23,71 +120,220 @@ enum FileType {
     Dir(PathBuf),
 }
 
-fn enlist_descendants(path: &Path) -> BoxFuture<io::Result<Vec<PathBuf>>> {
+impl FileType {
+    /// Type of `path` whose metadata is `meta`.
+    fn from_metadata(path: &Path, meta: &std::fs::Metadata) -> Self {
+        let path = path.to_owned();
+        if meta.file_type().is_symlink() {
+            Self::Symlink(path)
+        } else if meta.is_file() {
+            Self::File(path)
+        } else if meta.is_dir() {
+            Self::Dir(path)
+        } else {
+            Self::Other(path)
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Self::Symlink(path)
+            | Self::File(path)
+            | Self::Other(path)
+            | Self::Nothing(path)
+            | Self::Dir(path) => path,
+        }
+    }
+}
+
+/// Predicate telling whether a path (and whether it is a directory) is enlisted.
+type Keep<'a> = &'a (dyn Fn(&Path, bool) -> bool + Sync);
+
+/// Enlist `path` and its descendants kept by `keep` with their types, skipping whole subtrees
+/// of directories it rejects. `path` itself is always enlisted and followed (nothing if it does
+/// not exist), but symlinks below it are followed only if `follow` is set.
+fn enlist_descendants<'a>(
+    path: &'a Path,
+    follow: bool,
+    keep: Keep<'a>,
+) -> BoxFuture<'a, io::Result<Vec<FileType>>> {
+    enlist(path, true, follow, keep)
+}
+
+fn enlist<'a>(
+    path: &'a Path,
+    root: bool,
+    follow: bool,
+    keep: Keep<'a>,
+) -> BoxFuture<'a, io::Result<Vec<FileType>>> {
     async move {
-        if fs::metadata(path).await.is_err() {
+        let meta = if root || follow {
+            fs::metadata(path).await
+        } else {
+            fs::symlink_metadata(path).await
+        };
+        let meta = match meta {
+            Ok(meta) => meta,
+            // missing root, dangling symlink being followed or file removed while walking
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        if !root && !keep(path, meta.is_dir()) {
             return Ok(Vec::new());
         }
-        if fs::metadata(path).await?.is_dir() {
-            let read_dir = tokio_stream::wrappers::ReadDirStream::new(fs::read_dir(path).await?);
-            let entries: Vec<io::Result<Vec<PathBuf>>> = read_dir
-                .then(|entry| async { enlist_descendants(&entry.expect("TODO").path()).await })
-                .collect::<Vec<_>>()
-                .await;
-            let mut entries = entries
-                .into_iter()
-                .collect::<io::Result<Vec<_>>>()?
-                .concat();
-            entries.push(path.to_owned());
-            Ok(entries)
-        } else {
-            Ok(vec![path.to_owned()])
+        let mut entries = vec![FileType::from_metadata(path, &meta)];
+        if meta.is_dir() {
+            let mut read_dir = fs::read_dir(path).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                entries.extend(enlist(&entry.path(), false, follow, keep).await?);
+            }
         }
+        Ok(entries)
     }
     .boxed()
 }
 
 fn is_target_root(path: &Path) -> bool {
-    path.to_str() == Some("") || path.to_str() == Some(&std::path::MAIN_SEPARATOR.to_string())
+    path.to_str() == Some("") || path.to_str() == Some(std::path::MAIN_SEPARATOR_STR)
+}
+
+/// Split `name##conditions` into `name` and `conditions`.
+fn split_alternate(path: &Path) -> Option<(PathBuf, &str)> {
+    let name = path.file_name()?.to_str()?;
+    let (name, conditions) = name.split_once("##")?;
+    Some((path.with_file_name(name), conditions))
+}
+
+/// Specificity of alternate `conditions` (e.g. `host.sakanainu,os.Linux`) if all of them hold.
+/// `vars` must contain `facts` and `_scenario`.
+fn alternate_specificity(conditions: &str, vars: &liquid::Object) -> anyhow::Result<Option<u32>> {
+    let hostname = vars
+        .get("facts")
+        .and_then(|facts| facts.as_object())
+        .and_then(|facts| facts.get("hostname"))
+        .map(|hostname| hostname.to_kstr().into_owned());
+    let scenario = vars
+        .get("_scenario")
+        .map(|scenario| scenario.to_kstr().into_owned());
+    let mut specificity = 0;
+    for condition in conditions.split(',') {
+        let (class, value) = condition.split_once('.').unwrap_or((condition, ""));
+        let (matched, weight) = match class {
+            "default" => (true, 0),
+            "os" => (value == crate::util::OS, 1),
+            "arch" => (value == crate::util::ARCH, 2),
+            "scenario" => (scenario.as_deref() == Some(value), 4),
+            "host" | "hostname" => (hostname.as_deref() == Some(value), 8),
+            _ => anyhow::bail!("unknown alternate condition {:?}", condition),
+        };
+        if !matched {
+            return Ok(None);
+        }
+        specificity += weight;
+    }
+    Ok(Some(specificity))
+}
+
+/// Lexically resolve `.` and `..` of `path`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => (),
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Target of the symlink replicating symlink `src` of tree `root`. Relative targets pointing
+/// outside of the tree are made absolute, so that the replica points to the same file.
+async fn replicate_link(root: &Path, src: &Path) -> io::Result<PathBuf> {
+    let target = fs::read_link(src).await?;
+    if target.is_absolute() {
+        return Ok(target);
+    }
+    let src = std::path::absolute(src)?;
+    let resolved = normalize(&src.parent().unwrap_or(&src).join(&target));
+    if resolved.starts_with(normalize(&std::path::absolute(root)?)) {
+        Ok(target)
+    } else {
+        Ok(resolved)
+    }
 }
 
 async fn file_table(
     src: &Path,
     dest: &Path,
+    liquid_suffix: bool,
+    symlinks: Symlinks,
+    rules: &exclude::Rules<'_>,
+    vars: &liquid::Object,
 ) -> anyhow::Result<HashMap<PathBuf, (FileType, FileType)>> {
-    let src_descendants = enlist_descendants(src).await?;
-    let dest_descendants = enlist_descendants(dest).await?;
+    let follow = symlinks == Symlinks::Follow;
+    let src_descendants = enlist_descendants(src, follow, &|path, is_dir| {
+        !rules.is_excluded(path, is_dir)
+    })
+    .await?;
+    // excluded destination files are neither compared nor deleted
+    let dest_descendants = enlist_descendants(dest, false, &|path, is_dir| {
+        !rules.is_excluded(&exclude::source_of(src, dest, path), is_dir)
+    })
+    .await?;
     let mut hash = HashMap::new();
-    for src_descendant in src_descendants {
-        let meta = fs::metadata(&src_descendant).await?;
-        let src_filetype = if meta.is_file() {
-            FileType::File(src_descendant.to_owned())
-        } else if meta.is_dir() {
-            FileType::Dir(src_descendant.to_owned())
-        } else {
-            FileType::Other(src_descendant.to_owned())
-        };
+    // most specific alternate of each destination
+    let mut alternates: HashMap<PathBuf, (u32, PathBuf)> = HashMap::new();
+    for src_filetype in src_descendants {
+        let src_descendant = src_filetype.path().to_owned();
         let stripped = src_descendant.strip_prefix(Path::new(src))?.to_owned();
+        let is_file = matches!(src_filetype, FileType::File(_));
         if is_target_root(&stripped) {
             hash.insert(
                 src_descendant,
                 (src_filetype, FileType::Nothing(dest.to_owned())),
             );
+        } else if let Some((name, conditions)) = split_alternate(&stripped).filter(|_| is_file) {
+            if let Some(specificity) = alternate_specificity(conditions, vars)? {
+                let name = dest_name(&name, liquid_suffix);
+                match alternates.get(&name) {
+                    Some((selected, selected_src))
+                        if (*selected, selected_src) >= (specificity, &src_descendant) => {}
+                    _ => {
+                        alternates.insert(name, (specificity, src_descendant));
+                    }
+                }
+            }
         } else {
+            let stripped = if is_file {
+                dest_name(&stripped, liquid_suffix)
+            } else {
+                stripped
+            };
             hash.insert(
                 stripped.clone(),
                 (src_filetype, FileType::Nothing(dest.join(stripped))),
             );
         }
     }
-    for dest_descendant in dest_descendants {
-        let meta = fs::metadata(&dest_descendant).await?;
-        let dest_filetype = if meta.is_file() {
-            FileType::File(dest_descendant.to_owned())
-        } else {
-            FileType::Other(dest_descendant.to_owned())
-        };
+    for (stripped, (_, src_descendant)) in alternates {
+        hash.insert(
+            stripped.clone(),
+            (
+                FileType::File(src_descendant),
+                FileType::Nothing(dest.join(stripped)),
+            ),
+        );
+    }
+    for dest_filetype in dest_descendants {
+        let dest_descendant = dest_filetype.path();
         let stripped = dest_descendant.strip_prefix(Path::new(dest))?.to_owned();
+        if split_alternate(&stripped).is_some() {
+            // alternates are never deployed, so never deleted either
+            continue;
+        }
         if is_target_root(&stripped) {
             hash.entry(src.to_owned())
                 .and_modify(|pair| *pair = (pair.0.clone(), dest_filetype.clone()))
@@ -104,24 +350,10 @@ async fn file_table(
     Ok(hash)
 }
 
-fn match_template_target<'a>(
-    templates: &'a Templates,
-    src: &Path,
-    target: &Path,
-) -> Option<&'a liquid::Object> {
-    for (target_patterns, var_set) in templates {
-        let target = target.strip_prefix(src).unwrap_or(target);
-        for pattern in target_patterns {
-            if Path::new(pattern) == target {
-                return Some(var_set);
-            }
-        }
-    }
-    None
-}
-
 enum SyncStatus {
     Changed,
+    /// Content is same but attributes differ
+    MetadataChanged(Vec<String>),
     UnChanged,
     WellKnownError(String),
 }
@@ -143,194 +375,856 @@ impl SyncError {
     }
 }
 
-async fn sync_file(ctx: &CpContext, src: &FileType, dest: &FileType) -> anyhow::Result<SyncStatus> {
-    match (&src, &dest, ctx.merge) {
-        (FileType::Nothing(_), FileType::Nothing(_), _) => Ok(SyncStatus::UnChanged),
-        (FileType::Nothing(_), _, true) => Ok(SyncStatus::UnChanged),
-        (FileType::Nothing(_), FileType::Dir(dest), false) => {
-            // TODO: fix to unlink
-            if !ctx.dryrun {
-                fs::remove_dir(dest).await?;
+/// Content written to the destination instead of copying the source file.
+struct Generated {
+    content: Vec<u8>,
+    /// Contains secrets, so that it must be readable only by owner
+    sensitive: bool,
+    /// Depends on more than the source and variables, so that it is never recorded
+    volatile: bool,
+}
+
+/// Whether `path` is an encrypted file which is decrypted during cp.
+fn is_encrypted_file(path: &Path) -> bool {
+    let path = split_alternate(path).map_or_else(|| path.to_owned(), |(name, _)| name);
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("enc" | "age")
+    )
+}
+
+/// Whether `path` is named `*.liquid` (or `*.liquid.enc`, `*.liquid##host.name`).
+fn is_liquid_file(path: &Path) -> bool {
+    let path = &split_alternate(path).map_or_else(|| path.to_owned(), |(name, _)| name);
+    let path = if is_encrypted_file(path) {
+        path.with_extension("")
+    } else {
+        path.to_owned()
+    };
+    path.extension().and_then(|ext| ext.to_str()) == Some("liquid")
+}
+
+/// Destination name of `path`. The suffix of encrypted files (and `.liquid` if `liquid_suffix`)
+/// is removed.
+fn dest_name(path: &Path, liquid_suffix: bool) -> PathBuf {
+    let path = if is_encrypted_file(path) {
+        path.with_extension("")
+    } else {
+        path.to_owned()
+    };
+    if liquid_suffix && path.extension().and_then(|ext| ext.to_str()) == Some("liquid") {
+        path.with_extension("")
+    } else {
+        path
+    }
+}
+
+async fn decrypt_file(ctx: &CpContext, src: &Path) -> anyhow::Result<Result<Vec<u8>, String>> {
+    if src.extension().and_then(|ext| ext.to_str()) == Some("age") {
+        let identity = match ctx.filters.keyring().age_identity() {
+            Ok(identity) => identity,
+            Err(e) => return Ok(Err(format!("cannot decrypt {:?} due to {}", src, e))),
+        };
+        let output = tokio::process::Command::new("age")
+            .arg("--decrypt")
+            .arg("--identity")
+            .arg(identity)
+            .arg(src)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Ok(Err(format!(
+                "cannot decrypt {:?} with age due to {}",
+                src,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        return Ok(Ok(output.stdout));
+    }
+    Ok(ctx
+        .filters
+        .keyring()
+        .decrypt_bytes(&fs::read_to_string(src).await?)
+        .map_err(|e| format!("cannot decrypt {:?} due to {}", src, e)))
+}
+
+/// Whether `src` is decrypted during cp (encrypted file or listed in `decrypt`).
+fn is_decrypted(ctx: &CpContext, src: &Path) -> bool {
+    let target = src.strip_prefix(&ctx.base).unwrap_or(src);
+    is_encrypted_file(src)
+        || ctx
+            .decrypt
+            .iter()
+            .any(|pattern| Path::new(pattern) == target)
+}
+
+/// Decrypt and render `src` if needed. `Ok(Ok(None))` means `src` can be copied as is.
+async fn generate(
+    ctx: &CpContext,
+    src: &Path,
+    var_set: Option<VarSet<'_>>,
+) -> anyhow::Result<Result<Option<Generated>, String>> {
+    let encrypted = is_decrypted(ctx, src);
+    let content = match (encrypted, var_set) {
+        (false, None) => return Ok(Ok(None)),
+        (true, _) => match decrypt_file(ctx, src).await? {
+            Ok(content) => content,
+            Err(msg) => return Ok(Err(msg)),
+        },
+        (false, Some(_)) => fs::read(src).await?,
+    };
+    let var_set = if let Some(var_set) = var_set {
+        var_set
+    } else {
+        return Ok(Ok(Some(Generated {
+            content,
+            sensitive: true,
+            volatile: false,
+        })));
+    };
+    let template_src = String::from_utf8(content)?;
+    let engine = var_set.engine.unwrap_or_else(|| ctx.filters.engine());
+    match engine
+        .get()
+        .render(&template_src, var_set.vars, &ctx.filters)
+    {
+        Ok(rendered) => Ok(Ok(Some(Generated {
+            content: rendered.text.into_bytes(),
+            sensitive: encrypted || rendered.sensitive,
+            volatile: rendered.volatile,
+        }))),
+        Err(e) => Ok(Err(format!(
+            "cannot render template {:?} due to {}",
+            src,
+            describe_render_error(&e)
+        ))),
+    }
+}
+
+/// Deploy `src` to `dest`, writing `generated` content instead if rendered or decrypted.
+/// Files containing secrets are made readable only by owner, and copies keep the source mode.
+async fn deploy_file(
+    src: &Path,
+    dest: &Path,
+    generated: Option<&Generated>,
+    reflink: bool,
+) -> io::Result<()> {
+    match generated {
+        Some(generated) => {
+            let mode = if generated.sensitive {
+                Some(0o600)
+            } else {
+                None
+            };
+            write_atomic(dest, &generated.content, mode).await
+        }
+        None => {
+            let meta = fs::metadata(src).await?;
+            let mode = meta.permissions().mode() & 0o7777;
+            // filesystems without reflinks get a copy
+            if !(reflink && reflink_atomic(src, dest, mode).await?) {
+                write_atomic(dest, &fs::read(src).await?, Some(mode)).await?;
             }
-            Ok(SyncStatus::Changed)
+            Ok(())
         }
-        (FileType::Nothing(_), FileType::Symlink(dest), false) => {
-            // TODO: fix to unlink
+    }
+}
+
+/// Hash of the content of `path`, read in chunks on a blocking thread.
+async fn hash_file(path: &Path) -> io::Result<u128> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = xxh3::Xxh3::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = std::io::Read::read(&mut file, &mut buf)?;
+            if len == 0 {
+                return Ok(hasher.digest128());
+            }
+            hasher.update(&buf[..len]);
+        }
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Whether `dest` has the content `src` would be deployed with. Sizes are compared first, and
+/// contents are hashed only if they are equal. Timestamps are not trusted, since `touch -r` and
+/// coarse clocks make different contents look unmodified.
+async fn same_content(
+    src: &Path,
+    dest_meta: &std::fs::Metadata,
+    dest: &Path,
+    generated: Option<&Generated>,
+) -> io::Result<bool> {
+    match generated {
+        Some(generated) => Ok(generated.content.len() as u64 == dest_meta.len()
+            && xxh3::xxh3_128(&generated.content) == hash_file(dest).await?),
+        None => {
+            if fs::metadata(src).await?.len() != dest_meta.len() {
+                return Ok(false);
+            }
+            let (src_hash, dest_hash) = tokio::try_join!(hash_file(src), hash_file(dest))?;
+            Ok(src_hash == dest_hash)
+        }
+    }
+}
+
+/// Number of files compared or written at once.
+const CONCURRENCY: usize = 16;
+
+/// Operation of a sync plan.
+enum Op<'a> {
+    /// Remove what is at the destination (a directory with its descendants) to put another type
+    Clear { dir: bool },
+    /// Create a directory
+    CreateDir(Attributes),
+    /// Set attributes of an existing directory
+    UpdateDir(Attributes),
+    /// Deploy `src`. An existing destination file is compared first if `compare`.
+    Write {
+        src: PathBuf,
+        var_set: Option<VarSet<'a>>,
+        /// Hash of what rendering depends on besides the source
+        inputs: Option<String>,
+        attrs: Attributes,
+        compare: bool,
+        /// Clone the source rather than copying it where supported
+        reflink: bool,
+    },
+    /// Create a symlink to the target
+    Link(PathBuf),
+    /// Create a hardlink of the source file
+    Hardlink(PathBuf),
+    /// Remove a file, symlink or anything else but a directory
+    RemoveFile,
+    /// Remove a directory whose children are removed already
+    RemoveDir,
+}
+
+impl Op<'_> {
+    /// Steps run phase by phase: clear, create directories, write files, delete files and
+    /// remove directories.
+    fn phase(&self) -> u8 {
+        match self {
+            Op::Clear { .. } => 0,
+            Op::CreateDir(_) => 1,
+            Op::UpdateDir(_) | Op::Write { .. } | Op::Link(_) | Op::Hardlink(_) => 2,
+            Op::RemoveFile => 3,
+            Op::RemoveDir => 4,
+        }
+    }
+
+    /// Whether steps of the phase are independent of each other, so that they run in parallel.
+    fn is_concurrent(&self) -> bool {
+        matches!(
+            self,
+            Op::UpdateDir(_) | Op::Write { .. } | Op::Link(_) | Op::Hardlink(_)
+        )
+    }
+}
+
+/// Operation on a destination path.
+struct Step<'a> {
+    dest: PathBuf,
+    op: Op<'a>,
+}
+
+impl Step<'_> {
+    /// Description shown by dry-run.
+    fn describe(&self) -> String {
+        match &self.op {
+            Op::Clear { .. } => format!("remove {:?} to replace it", self.dest),
+            Op::CreateDir(_) => format!("create directory {:?}", self.dest),
+            Op::UpdateDir(_) => format!("update attributes of {:?}", self.dest),
+            Op::Write { compare: true, .. } => format!("update {:?}", self.dest),
+            Op::Write { .. } => format!("create {:?}", self.dest),
+            Op::Link(target) => format!("link {:?} -> {:?}", self.dest, target),
+            Op::Hardlink(src) => format!("hardlink {:?} to {:?}", self.dest, src),
+            Op::RemoveFile => format!("remove {:?}", self.dest),
+            Op::RemoveDir => format!("remove directory {:?}", self.dest),
+        }
+    }
+}
+
+/// Plan to sync `tbl`. Nothing is touched, and `Err` is returned for source files which cannot
+/// be deployed. Parents are created before their children and removed after them.
+async fn plan<'a>(
+    ctx: &'a CpContext,
+    src_base: &Path,
+    tbl: &HashMap<PathBuf, (FileType, FileType)>,
+    var_sets: &HashMap<&PathBuf, VarSet<'a>>,
+) -> anyhow::Result<Result<Vec<Step<'a>>, String>> {
+    let mut steps = Vec::new();
+    // hash of each variable set, which are shared by many files
+    let mut inputs = HashMap::new();
+    for (src, dest) in tbl.values() {
+        let attrs = match src {
+            FileType::File(src) | FileType::Dir(src) => ctx
+                .attributes
+                .of(src.strip_prefix(&ctx.base).unwrap_or(src)),
+            _ => Attributes::default(),
+        };
+        let op = match (src, dest) {
+            (FileType::Other(src), _) => {
+                return Ok(Err(format!("{:?} has unknown file type", src)));
+            }
+            (FileType::Nothing(_), FileType::Nothing(_)) => None,
+            (FileType::Nothing(_), _) if ctx.merge => None,
+            (FileType::Nothing(_), FileType::Dir(_)) => Some(Op::RemoveDir),
+            (FileType::Nothing(_), _) => Some(Op::RemoveFile),
+            (FileType::File(src), dest) => {
+                let var_set = var_sets.get(src).copied();
+                let strategy = if var_set.is_some() || is_decrypted(ctx, src) {
+                    Strategy::Copy
+                } else {
+                    ctx.strategy
+                };
+                match (strategy, dest) {
+                    (Strategy::Symlink, FileType::Symlink(dest))
+                        if fs::read_link(dest).await? == std::path::absolute(src)? =>
+                    {
+                        None
+                    }
+                    (Strategy::Symlink, _) => Some(Op::Link(std::path::absolute(src)?)),
+                    (Strategy::Hardlink, FileType::File(dest))
+                        if is_same_file(src, dest).await? =>
+                    {
+                        None
+                    }
+                    (Strategy::Hardlink, _) => Some(Op::Hardlink(src.clone())),
+                    (Strategy::Copy | Strategy::Reflink, dest) => Some(Op::Write {
+                        src: src.clone(),
+                        var_set,
+                        inputs: var_set.map(|var_set| {
+                            inputs
+                                .entry(var_set.vars as *const liquid::Object as usize)
+                                .or_insert_with(|| inputs_of(ctx, var_set))
+                                .clone()
+                        }),
+                        attrs,
+                        // a hardlink of the source is replaced rather than written through
+                        compare: matches!(dest, FileType::File(dest) if !is_same_file(src, dest).await?),
+                        reflink: strategy == Strategy::Reflink,
+                    }),
+                }
+            }
+            (FileType::Dir(_), FileType::Dir(_)) if attrs.manages_dir() => {
+                Some(Op::UpdateDir(attrs))
+            }
+            (FileType::Dir(_), FileType::Dir(_)) => None,
+            (FileType::Dir(_), _) => Some(Op::CreateDir(attrs)),
+            (FileType::Symlink(src), dest) => {
+                if ctx.symlinks != Symlinks::Preserve {
+                    return Ok(Err(format!(
+                        "{:?} is symlink, which cp.symlinks forbids",
+                        src
+                    )));
+                }
+                let target = replicate_link(src_base, src).await?;
+                match dest {
+                    FileType::Symlink(dest) if fs::read_link(dest).await? == target => None,
+                    _ => Some(Op::Link(target)),
+                }
+            }
+        };
+        let op = match op {
+            Some(op) => op,
+            None => continue,
+        };
+        // anything of another type is removed before the new one is put, and symlinks are
+        // replaced rather than written through
+        let replaced = match &op {
+            Op::Write { compare, .. } => !compare,
+            Op::CreateDir(_) | Op::Link(_) | Op::Hardlink(_) => true,
+            _ => false,
+        };
+        if replaced && !matches!(dest, FileType::Nothing(_)) {
+            steps.push(Step {
+                dest: dest.path().to_owned(),
+                op: Op::Clear {
+                    dir: matches!(dest, FileType::Dir(_)),
+                },
+            });
+        }
+        steps.push(Step {
+            dest: dest.path().to_owned(),
+            op,
+        });
+    }
+    // descendants of a replaced directory go with it
+    let cleared = steps
+        .iter()
+        .filter(|step| matches!(step.op, Op::Clear { dir: true }))
+        .map(|step| step.dest.clone())
+        .collect::<Vec<_>>();
+    steps.retain(|step| {
+        !matches!(step.op, Op::RemoveFile | Op::RemoveDir)
+            || !cleared
+                .iter()
+                .any(|dir| step.dest != *dir && step.dest.starts_with(dir))
+    });
+    steps.sort_by(|a, b| {
+        a.op.phase().cmp(&b.op.phase()).then_with(|| {
+            // children are deleted before their parents
+            if a.op.phase() >= Op::RemoveFile.phase() {
+                b.dest.cmp(&a.dest)
+            } else {
+                a.dest.cmp(&b.dest)
+            }
+        })
+    });
+    Ok(Ok(steps))
+}
+
+/// Apply `step` of a plan. Destination is only compared if `ctx.dryrun`.
+async fn apply(ctx: &CpContext, step: &Step<'_>) -> anyhow::Result<SyncStatus> {
+    let dest = step.dest.as_path();
+    match &step.op {
+        Op::Clear { dir } => {
             if !ctx.dryrun {
-                fs::remove_file(dest).await?;
+                backup(ctx, dest)?;
+                forget(ctx, dest);
+                if *dir {
+                    ignore_not_found(fs::remove_dir_all(dest).await)?;
+                } else {
+                    ignore_not_found(fs::remove_file(dest).await)?;
+                }
             }
             Ok(SyncStatus::Changed)
         }
-        (FileType::Nothing(_), FileType::File(dest) | FileType::Other(dest), false) => {
+        Op::CreateDir(attrs) => {
             if !ctx.dryrun {
-                fs::remove_file(dest).await?;
+                fs::create_dir_all(dest).await?;
+                attrs::apply(dest, attrs, false)?;
             }
             Ok(SyncStatus::Changed)
         }
-        (&FileType::File(src), &FileType::File(dest), _) => {
-            let (src_buf, need_to_write) =
-                if let Some(var_set) = match_template_target(&ctx.templates, &ctx.base, src) {
-                    match liquid::ParserBuilder::with_stdlib()
-                        .build()?
-                        .parse(&fs::read_to_string(src).await?)
-                    {
-                        Ok(template) => (template.render(var_set)?.as_bytes().to_vec(), true),
-                        Err(_) => {
-                            return Ok(SyncStatus::WellKnownError(format!(
-                                "cannot parse template {:?}",
-                                src
-                            )))
-                        }
+        Op::UpdateDir(attrs) => attributes_status(dest, attrs, ctx.dryrun),
+        Op::Write {
+            src,
+            var_set,
+            inputs,
+            attrs,
+            compare: true,
+            reflink,
+        } => {
+            let dest_meta = fs::metadata(dest).await?;
+            let src_meta = fs::metadata(src).await?;
+            // the destination is still what the last deploy wrote from the same source.
+            // decrypted files are never recorded, so a record is of a plain copy before.
+            let record = ctx
+                .state
+                .as_ref()
+                .filter(|_| !is_decrypted(ctx, src))
+                .and_then(|state| state.get(dest))
+                .filter(|record| {
+                    std::path::absolute(src).is_ok_and(|src| record.src == src)
+                        && record.dest == Stat::of(&dest_meta)
+                });
+            if let Some(record) = record.filter(|record| record.inputs == *inputs) {
+                let src_stat = Stat::of(&src_meta);
+                if record.src_stat == Some(src_stat)
+                    || record.src_hash == Some(state::hex(hash_file(src).await?))
+                {
+                    let status = attributes_status(dest, attrs, ctx.dryrun)?;
+                    if let Some(state) = ctx.state.as_ref().filter(|_| !ctx.dryrun) {
+                        state.record(
+                            dest,
+                            state::Record {
+                                src_stat: Some(src_stat),
+                                // changed attributes change ctime of the destination
+                                dest: Stat::of(&fs::metadata(dest).await?),
+                                ..record.clone()
+                            },
+                        );
                     }
-                } else {
-                    (fs::read(src).await?, false)
-                };
-            let dest_buf = fs::read(dest).await?;
-            if md5::compute(&src_buf) != md5::compute(dest_buf) {
+                    return Ok(status);
+                }
+            }
+            let generated = match generate(ctx, src, *var_set).await? {
+                Ok(generated) => generated,
+                Err(msg) => return Ok(SyncStatus::WellKnownError(msg)),
+            };
+            // files containing secrets must not be readable by others unless mode is given
+            let too_permissive = match &generated {
+                Some(generated) if generated.sensitive && attrs.mode.is_none() => {
+                    dest_meta.permissions().mode() & 0o077 != 0
+                }
+                _ => false,
+            };
+            // the recorded output tells the destination content without reading it
+            let same = match (
+                record.and_then(|record| record.output_hash.as_ref()),
+                &generated,
+            ) {
+                (Some(output_hash), Some(generated)) => {
+                    *output_hash == state::hex(xxh3::xxh3_128(&generated.content))
+                }
+                _ => same_content(src, &dest_meta, dest, generated.as_ref()).await?,
+            };
+            if too_permissive || !same {
                 if !ctx.dryrun {
-                    if need_to_write {
-                        let mut writer = io::BufWriter::new(fs::File::create(dest).await?);
-                        writer.write_all(&src_buf).await?;
-                        writer.flush().await?;
-                    } else {
-                        fs::copy(src, dest).await?;
-                    }
+                    backup(ctx, dest)?;
+                    deploy_file(src, dest, generated.as_ref(), *reflink).await?;
+                    attrs::apply(dest, attrs, false)?;
+                    record_deployed(ctx, src, dest, inputs, generated.as_ref()).await?;
                 }
                 Ok(SyncStatus::Changed)
             } else {
-                Ok(SyncStatus::UnChanged)
+                let status = attributes_status(dest, attrs, ctx.dryrun)?;
+                if !ctx.dryrun {
+                    record_deployed(ctx, src, dest, inputs, generated.as_ref()).await?;
+                }
+                Ok(status)
             }
         }
-        (&FileType::File(src), &FileType::Dir(dest), _) => {
+        Op::Write {
+            src,
+            var_set,
+            inputs,
+            attrs,
+            compare: false,
+            reflink,
+        } => {
             if !ctx.dryrun {
-                fs::remove_dir(dest).await?;
-                if let Some(var_set) = match_template_target(&ctx.templates, &ctx.base, src) {
-                    let mut writer = io::BufWriter::new(fs::File::create(dest).await?);
-                    match liquid::ParserBuilder::with_stdlib()
-                        .build()?
-                        .parse(&fs::read_to_string(src).await?)
-                    {
-                        Ok(template) => {
-                            let rendered = template.render(var_set)?;
-                            writer.write_all(rendered.as_bytes()).await?;
-                        }
-                        Err(_) => {
-                            return Ok(SyncStatus::WellKnownError(format!(
-                                "cannot parse template {:?}",
-                                src
-                            )))
-                        }
-                    }
-                } else {
-                    fs::copy(src, dest).await?;
-                }
+                let generated = match generate(ctx, src, *var_set).await? {
+                    Ok(generated) => generated,
+                    Err(msg) => return Ok(SyncStatus::WellKnownError(msg)),
+                };
+                create_parent(dest).await?;
+                deploy_file(src, dest, generated.as_ref(), *reflink).await?;
+                attrs::apply(dest, attrs, false)?;
+                record_deployed(ctx, src, dest, inputs, generated.as_ref()).await?;
             }
             Ok(SyncStatus::Changed)
         }
-        (&FileType::File(src), &FileType::Other(dest), _) => {
+        Op::Link(target) => {
             if !ctx.dryrun {
-                fs::remove_file(dest).await?;
-                if let Some(var_set) = match_template_target(&ctx.templates, &ctx.base, src) {
-                    let mut writer = io::BufWriter::new(fs::File::create(dest).await?);
-                    match liquid::ParserBuilder::with_stdlib()
-                        .build()?
-                        .parse(&fs::read_to_string(src).await?)
-                    {
-                        Ok(template) => {
-                            let rendered = template.render(var_set)?;
-                            writer.write_all(rendered.as_bytes()).await?;
-                        }
-                        Err(_) => {
-                            return Ok(SyncStatus::WellKnownError(format!(
-                                "cannot parse template {:?}",
-                                src
-                            )))
-                        }
-                    }
-                } else {
-                    fs::copy(src, dest).await?;
-                }
+                create_parent(dest).await?;
+                fs::symlink(target, dest).await?;
             }
             Ok(SyncStatus::Changed)
         }
-        (&FileType::File(src), &FileType::Nothing(dest), _) => {
+        Op::Hardlink(src) => {
             if !ctx.dryrun {
-                let dest_parent = dest
-                    .parent()
-                    .ok_or_else(|| SyncError::new(format!("cannot take parent of {:?}", dest)))?;
-                fs::create_dir_all(dest_parent).await?;
-                if let Some(var_set) = match_template_target(&ctx.templates, &ctx.base, src) {
-                    let mut writer = io::BufWriter::new(fs::File::create(dest).await?);
-                    match liquid::ParserBuilder::with_stdlib()
-                        .build()?
-                        .parse(&fs::read_to_string(src).await?)
-                    {
-                        Ok(template) => {
-                            let rendered = template.render(var_set)?;
-                            writer.write_all(rendered.as_bytes()).await?;
-                        }
-                        Err(_) => {
-                            return Ok(SyncStatus::WellKnownError(format!(
-                                "cannot parse template {:?}",
-                                src
-                            )))
-                        }
+                create_parent(dest).await?;
+                match fs::hard_link(src, dest).await {
+                    Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                        return Ok(SyncStatus::WellKnownError(format!(
+                            "cannot hardlink {:?} to {:?} on another filesystem",
+                            dest, src
+                        )));
                     }
-                } else {
-                    fs::copy(src, dest).await?;
+                    result => result?,
                 }
             }
             Ok(SyncStatus::Changed)
         }
-        (&FileType::File(_), &FileType::Symlink(_), _) => Ok(SyncStatus::WellKnownError(
-            "symlink is unsupported.".to_owned(),
-        )),
-        (FileType::Dir(_), _, _) => Ok(SyncStatus::UnChanged),
-        (FileType::Other(_), _, _) => {
-            Ok(SyncStatus::WellKnownError("unknown file type".to_owned()))
+        Op::RemoveFile => {
+            // symlinks are removed by themselves, never what they point to
+            if !ctx.dryrun {
+                backup(ctx, dest)?;
+                forget(ctx, dest);
+                ignore_not_found(fs::remove_file(dest).await)?;
+            }
+            Ok(SyncStatus::Changed)
+        }
+        Op::RemoveDir => {
+            if ctx.dryrun {
+                return Ok(SyncStatus::Changed);
+            }
+            match fs::remove_dir(dest).await {
+                // excluded files are kept with their directory
+                Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => Ok(SyncStatus::UnChanged),
+                result => {
+                    ignore_not_found(result)?;
+                    Ok(SyncStatus::Changed)
+                }
+            }
         }
-        (FileType::Symlink(_), _, _) => Ok(SyncStatus::WellKnownError(
-            "symlink is unsupported.".to_owned(),
-        )),
+    }
+}
+
+/// Whether `a` and `b` are hardlinks of the same file.
+async fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    let (a, b) = tokio::try_join!(fs::metadata(a), fs::metadata(b))?;
+    Ok((a.dev(), a.ino()) == (b.dev(), b.ino()))
+}
+
+/// Compare attributes of `dest` with `attrs`, setting them unless `dryrun`.
+fn attributes_status(dest: &Path, attrs: &Attributes, dryrun: bool) -> anyhow::Result<SyncStatus> {
+    let diffs = attrs::apply(dest, attrs, dryrun)?;
+    if diffs.is_empty() {
+        Ok(SyncStatus::UnChanged)
+    } else {
+        Ok(SyncStatus::MetadataChanged(diffs))
+    }
+}
+
+/// Hash of what rendering depends on besides the source: variables, engine and partials.
+fn inputs_of(ctx: &CpContext, var_set: VarSet<'_>) -> String {
+    let mut hasher = xxh3::Xxh3::new();
+    // keys of JSON objects are sorted, unlike liquid objects
+    if let Ok(vars) = serde_json::to_value(var_set.vars) {
+        hasher.update(vars.to_string().as_bytes());
+    }
+    let engine = var_set.engine.unwrap_or_else(|| ctx.filters.engine());
+    hasher.update(format!("{:?}", engine).as_bytes());
+    hasher.update(&ctx.filters.partials().fingerprint().to_le_bytes());
+    state::hex(hasher.digest128())
+}
+
+/// Record `dest` deployed from `src` unless its content cannot be told from the record
+/// (secrets and templates using volatile filters).
+async fn record_deployed(
+    ctx: &CpContext,
+    src: &Path,
+    dest: &Path,
+    inputs: &Option<String>,
+    generated: Option<&Generated>,
+) -> io::Result<()> {
+    let state = match &ctx.state {
+        Some(state) => state,
+        None => return Ok(()),
+    };
+    if generated.is_some_and(|generated| generated.sensitive || generated.volatile) {
+        state.forget(dest);
+        return Ok(());
+    }
+    let src_hash = hash_file(src).await?;
+    state.record(
+        dest,
+        state::Record {
+            src: std::path::absolute(src)?,
+            src_stat: Some(Stat::of(&fs::metadata(src).await?)),
+            src_hash: Some(state::hex(src_hash)),
+            inputs: inputs.clone(),
+            output_hash: Some(state::hex(match generated {
+                Some(generated) => xxh3::xxh3_128(&generated.content),
+                None => src_hash,
+            })),
+            dest: Stat::of(&fs::metadata(dest).await?),
+        },
+    );
+    Ok(())
+}
+
+/// Forget the record of `dest` which is removed.
+fn forget(ctx: &CpContext, dest: &Path) {
+    if let Some(state) = &ctx.state {
+        state.forget(dest);
+    }
+}
+
+async fn create_parent(dest: &Path) -> anyhow::Result<()> {
+    let dest_parent = dest
+        .parent()
+        .ok_or_else(|| SyncError::new(format!("cannot take parent of {:?}", dest)))?;
+    fs::create_dir_all(dest_parent).await?;
+    Ok(())
+}
+
+/// Save `dest` to the backup of the run before overwriting or deleting it.
+fn backup(ctx: &CpContext, dest: &Path) -> io::Result<()> {
+    match &ctx.backup {
+        Some(backup) => backup.save(dest),
+        None => Ok(()),
+    }
+}
+
+/// Ignore `NotFound`, which happens when the parent directory has been removed already.
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
     }
 }
 
 // TODO: handle error when src directory is not found.
 async fn execute_cp(ctx: &CpContext, src: &str, dest: &str) -> crate::TaskResult {
-    let src_base = ctx.base.join(Path::new(src));
+    let src = crate::util::resolve_template(src, &ctx.vars, &ctx.filters).map_err(|e| {
+        crate::TaskError::WellKnown(format!(
+            "cannot resolve source path {:?} due to {}",
+            src,
+            describe_render_error(&e)
+        ))
+    })?;
+    let src_base = ctx.base.join(Path::new(&src));
     if fs::metadata(&src_base).await.is_err() {
         return Err(crate::TaskError::WellKnown(format!(
             "src {:?} is not found",
             src_base
         )));
     }
-    let dest = crate::util::resolve_liquid_template(dest).map_err(|e| {
+    let dest = crate::util::resolve_template(dest, &ctx.vars, &ctx.filters).map_err(|e| {
         crate::TaskError::WellKnown(format!(
-            "cannot resolve disitination path {:?} due to {:?}",
-            dest, e
+            "cannot resolve disitination path {:?} due to {}",
+            dest,
+            describe_render_error(&e)
         ))
     })?;
-    let tbl = file_table(&src_base, Path::new(&dest)).await.map_err(|e| {
+    let rules = ctx.exclude.load(&ctx.base, &src_base).map_err(|e| {
+        crate::TaskError::WellKnown(format!("cannot load ignore files due to {}", e))
+    })?;
+    let tbl = file_table(
+        &src_base,
+        Path::new(&dest),
+        ctx.templates.liquid_suffix,
+        ctx.symlinks,
+        &rules,
+        &ctx.default_vars,
+    )
+    .await
+    .map_err(|e| {
         crate::TaskError::WellKnown(format!(
             "cannot resolve disitination path {:?} due to {:?}",
             dest, e
         ))
     })?;
-    let mut changed = false;
-    for (src, dest) in tbl.values() {
-        match sync_file(ctx, src, dest).await? {
-            SyncStatus::Changed => {
-                changed = true;
+    // select template of each file at once
+    let var_sets = tbl
+        .values()
+        .filter_map(|(src, _)| match src {
+            FileType::File(src) => ctx
+                .templates
+                .select(
+                    src.strip_prefix(&ctx.base).unwrap_or(src),
+                    &ctx.default_vars,
+                )
+                .map(|var_set| (src, var_set)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    if ctx.strict && ctx.dryrun {
+        // dry-run renders only files to be compared, so render the others here
+        let mut errors = Vec::new();
+        for (src, var_set) in &var_sets {
+            if let Err(msg) = generate(ctx, src, Some(*var_set)).await? {
+                errors.push(msg);
             }
-            SyncStatus::UnChanged => (),
-            SyncStatus::WellKnownError(msg) => {
-                return Err(crate::TaskError::WellKnown(msg));
+        }
+        if !errors.is_empty() {
+            errors.sort();
+            return Err(crate::TaskError::WellKnown(errors.join("\n  -> ")));
+        }
+    }
+    let steps = match plan(ctx, &src_base, &tbl, &var_sets).await? {
+        Ok(steps) => steps,
+        Err(msg) => return Err(crate::TaskError::WellKnown(msg)),
+    };
+    let mut changed = false;
+    for phase in steps.chunk_by(|a, b| a.op.phase() == b.op.phase()) {
+        let concurrency = if phase[0].op.is_concurrent() {
+            CONCURRENCY
+        } else {
+            1
+        };
+        // every step of a phase finishes before an error is returned
+        let applies = phase
+            .iter()
+            .map(|step| apply(ctx, step))
+            .collect::<Vec<_>>();
+        let statuses = futures::stream::iter(applies)
+            .buffered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        for (step, status) in phase.iter().zip(statuses) {
+            match status? {
+                SyncStatus::Changed => {
+                    changed = true;
+                    // dry-run shows the plan
+                    if ctx.dryrun {
+                        if let Ok(mut notes) = ctx.notes.lock() {
+                            notes.push(step.describe());
+                        }
+                    }
+                }
+                SyncStatus::MetadataChanged(diffs) => {
+                    changed = true;
+                    if let Ok(mut notes) = ctx.notes.lock() {
+                        notes.extend(diffs);
+                    }
+                }
+                SyncStatus::UnChanged => (),
+                SyncStatus::WellKnownError(msg) => {
+                    return Err(crate::TaskError::WellKnown(msg));
+                }
             }
         }
     }
     Ok(changed)
 }
 
+/// Render source file `path` as `execute_cp` would deploy it. `None` if `path` is not deployed.
+async fn preview_cp(
+    ctx: &CpContext,
+    src: &str,
+    dest: &str,
+    path: &Path,
+) -> Option<Result<crate::Preview, crate::TaskError>> {
+    let path = std::fs::canonicalize(path).ok()?;
+    let src = crate::util::resolve_template(src, &ctx.vars, &ctx.filters).ok()?;
+    let src_base = ctx.base.join(Path::new(&src));
+    if !path.starts_with(std::fs::canonicalize(&src_base).ok()?) {
+        return None;
+    }
+    let dest = match crate::util::resolve_template(dest, &ctx.vars, &ctx.filters) {
+        Ok(dest) => dest,
+        Err(e) => {
+            return Some(Err(crate::TaskError::WellKnown(format!(
+                "cannot resolve disitination path {:?} due to {}",
+                dest,
+                describe_render_error(&e)
+            ))))
+        }
+    };
+    let rules = match ctx.exclude.load(&ctx.base, &src_base) {
+        Ok(rules) => rules,
+        Err(e) => return Some(Err(e.into())),
+    };
+    let tbl = match file_table(
+        &src_base,
+        Path::new(&dest),
+        ctx.templates.liquid_suffix,
+        ctx.symlinks,
+        &rules,
+        &ctx.default_vars,
+    )
+    .await
+    {
+        Ok(tbl) => tbl,
+        Err(e) => return Some(Err(e.into())),
+    };
+    let (src, dest) = tbl
+        .into_values()
+        .find_map(|(src, dest)| match (src, dest) {
+            (FileType::File(src), dest) if std::fs::canonicalize(&src).ok()? == path => {
+                Some((src, dest))
+            }
+            _ => None,
+        })?;
+    let var_set = ctx.templates.select(
+        src.strip_prefix(&ctx.base).unwrap_or(&src),
+        &ctx.default_vars,
+    );
+    let (content, sensitive) = match generate(ctx, &src, var_set).await {
+        Ok(Ok(Some(generated))) => (generated.content, generated.sensitive),
+        Ok(Ok(None)) => match fs::read(&src).await {
+            Ok(content) => (content, false),
+            Err(e) => return Some(Err(anyhow::Error::from(e).into())),
+        },
+        Ok(Err(msg)) => return Some(Err(crate::TaskError::WellKnown(msg))),
+        Err(e) => return Some(Err(e.into())),
+    };
+    Some(Ok(crate::Preview {
+        dest: dest.path().to_owned(),
+        content,
+        sensitive,
+    }))
+}
+
 /// Implementation of [Task trait](../../trait.Task.html).
 #[derive(Debug)]
 pub struct CpTask {
@@ -338,35 +1232,100 @@ pub struct CpTask {
     dest: String,
     merge: bool,
     templates: Templates,
+    decrypt: Vec<String>,
+    attributes: Arc<AttributeRules>,
+    symlinks: Symlinks,
+    strategy: Strategy,
+    exclude: Arc<exclude::Patterns>,
+    /// `backup` option overriding the playbook
+    backup: Option<bool>,
+}
+
+impl CpTask {
+    /// Source files of this task rendered as templates, relative to `base`. Empty if `src` is
+    /// resolved only when the task runs.
+    pub fn template_sources(&self, base: &Path) -> Vec<PathBuf> {
+        if self.src.contains("{{") || self.src.contains("{%") {
+            return Vec::new();
+        }
+        let default = liquid::Object::new();
+        let mut sources = Vec::new();
+        let mut pending = vec![base.join(&self.src)];
+        while let Some(path) = pending.pop() {
+            if let Ok(entries) = std::fs::read_dir(&path) {
+                pending.extend(entries.filter_map(|entry| entry.ok().map(|entry| entry.path())));
+            } else if path.is_file()
+                && self
+                    .templates
+                    .select(path.strip_prefix(base).unwrap_or(&path), &default)
+                    .is_some()
+            {
+                sources.push(path);
+            }
+        }
+        sources
+    }
 }
 
 #[derive(Debug, Clone)]
 struct CpContext {
     base: PathBuf,
     dryrun: bool,
+    strict: bool,
     merge: bool,
     templates: Templates,
+    /// Variables of files rendered without matching entry of `templates`
+    default_vars: liquid::Object,
+    decrypt: Vec<String>,
+    attributes: Arc<AttributeRules>,
+    symlinks: Symlinks,
+    strategy: Strategy,
+    exclude: Arc<exclude::Patterns>,
+    backup: Option<crate::backup::Backup>,
+    /// Record of the last deploy
+    state: Option<State>,
+    vars: liquid::Object,
+    filters: crate::filters::Filters,
+    /// Metadata-only changes to report
+    notes: Arc<Mutex<Vec<String>>>,
 }
 
 impl CpContext {
-    fn extend(ctx: &crate::TaskContext, merge: bool, templates: Templates) -> Self {
-        let templates = templates
-            .into_iter()
-            .map(|(target, mut object)| {
-                if !object.contains_key("_scenario") {
-                    object.insert(
-                        KStringBase::from_static("_scenario"),
-                        liquid::model::Value::scalar(ctx.scenario.clone()),
-                    );
-                }
-                (target, object)
-            })
-            .collect::<HashMap<_, _>>();
+    fn extend(ctx: &crate::TaskContext, task: &CpTask) -> Self {
+        let extend_vars = |template_vars: &liquid::Object| {
+            let mut object = ctx.vars.clone();
+            object.extend(template_vars.clone());
+            object.extend(ctx.overrides.clone());
+            if !object.contains_key("_scenario") {
+                object.insert(
+                    KStringBase::from_static("_scenario"),
+                    liquid::model::Value::scalar(ctx.scenario.clone()),
+                );
+            }
+            object
+        };
+        let default_vars = extend_vars(&liquid::Object::new());
+        let templates = Templates {
+            vars: task.templates.vars.iter().map(extend_vars).collect(),
+            ..task.templates.clone()
+        };
         Self {
-            merge,
+            merge: task.merge,
             templates,
+            default_vars,
+            decrypt: task.decrypt.clone(),
+            attributes: task.attributes.clone(),
+            symlinks: task.symlinks,
+            strategy: task.strategy,
+            exclude: task.exclude.clone(),
+            backup: crate::backup::Backup::for_task(ctx.backup, task.backup).cloned(),
+            state: ctx.state.cloned(),
             base: ctx.base.clone(),
             dryrun: ctx.dryrun,
+            strict: ctx.strict,
+            vars: ctx.vars.clone(),
+            filters: ctx.filters.clone(),
+            notes: Arc::new(Mutex::new(Vec::new())),
         }
     }
 }
@@ -378,24 +1337,32 @@ impl crate::Task for CpTask {
     }
 
     async fn execute(&self, ctx: &crate::TaskContext) -> crate::TaskResult {
-        execute_cp(
-            &CpContext::extend(ctx, self.merge, self.templates.clone()),
-            &self.src,
-            &self.dest,
-        )
-        .await
+        let cp_ctx = CpContext::extend(ctx, self);
+        let result = execute_cp(&cp_ctx, &self.src, &self.dest).await;
+        if let (Ok(mut notes), Ok(diffs)) = (ctx.notes.lock(), cp_ctx.notes.lock()) {
+            notes.extend(diffs.iter().cloned());
+        }
+        result
+    }
+
+    async fn preview(
+        &self,
+        ctx: &crate::TaskContext,
+        path: &Path,
+    ) -> Option<Result<crate::Preview, crate::TaskError>> {
+        preview_cp(&CpContext::extend(ctx, self), &self.src, &self.dest, path).await
     }
 }
 
 fn parse_cp_templates(
     yaml: &crate::ast::Value,
-) -> Result<(Vec<String>, liquid::Object), crate::Error> {
+) -> Result<(Vec<String>, liquid::Object, Option<Engine>), crate::Error> {
     let hash = yaml.as_hash().ok_or_else(|| {
         crate::Error::InvalidPlaybook("cp.templates must be hash".to_owned(), yaml.to_owned())
     })?;
     crate::ast::verify_hash(
         hash,
-        &["type", "vars", "target"],
+        &["type", "vars", "target", "engine"],
         Some("tasks.cp.templates"),
     )?;
     let target = match hash.get("target").ok_or_else(|| {
@@ -421,8 +1388,8 @@ fn parse_cp_templates(
             invalid.to_owned(),
         )),
     }?;
-    let mut context = liquid::Object::new();
-    hash.get("vars")
+    let context = hash
+        .get("vars")
         .ok_or_else(|| {
             crate::Error::InvalidPlaybook("cp.template must have vars".to_owned(), yaml.to_owned())
         })?
@@ -434,38 +1401,40 @@ fn parse_cp_templates(
             )
         })?
         .iter()
-        .map(|(name, val)| {
-            let name = KStringBase::from_string(name.to_owned());
-            match val {
-                crate::ast::Value::Str(str) => context.insert(
-                    KStringBase::from(str),
-                    liquid::model::Value::scalar(str.to_owned()),
-                ),
-                crate::ast::Value::Int(int) => {
-                    context.insert(name, liquid::model::Value::scalar(*int))
-                }
-                crate::ast::Value::Real(float) => {
-                    context.insert(name, liquid::model::Value::scalar(*float))
-                }
-                _ => {
-                    return Err(crate::Error::InvalidPlaybook(
-                        "children of cp.templates.vars must be string: <string|int|float>"
-                            .to_owned(),
-                        val.to_owned(),
-                    ))
-                }
-            };
-            Ok(())
-        })
-        .collect::<Result<Vec<()>, crate::Error>>()?;
-    Ok((target, context))
+        .map(|(name, val)| (KStringBase::from_string(name.to_owned()), val.to_liquid()))
+        .collect::<liquid::Object>();
+    let engine = hash
+        .get("engine")
+        .map(|engine| crate::engine::parse(engine, "cp.templates.engine"))
+        .transpose()?;
+    Ok((target, context, engine))
 }
 
 /// parse task section as a cp task
 pub fn parse(obj: &HashMap<String, crate::ast::Value>) -> Result<crate::TaskEntity, crate::Error> {
     crate::ast::verify_hash(
         obj,
-        &["type", "src", "dest", "merge", "templates"],
+        &[
+            "type",
+            "src",
+            "dest",
+            "merge",
+            "templates",
+            "template",
+            "liquid_suffix",
+            "decrypt",
+            "mode",
+            "dir_mode",
+            "owner",
+            "group",
+            "attributes",
+            "symlinks",
+            "strategy",
+            "exclude",
+            "include",
+            "gitignore",
+            "backup",
+        ],
         Some("tasks.cp"),
     )?;
     let src = obj
@@ -488,7 +1457,7 @@ pub fn parse(obj: &HashMap<String, crate::ast::Value>) -> Result<crate::TaskEnti
             })
         })
         .unwrap_or(Ok(true))?;
-    let templates = obj
+    let entries = obj
         .get("templates")
         .map(|templates| {
             templates
@@ -501,13 +1470,1012 @@ pub fn parse(obj: &HashMap<String, crate::ast::Value>) -> Result<crate::TaskEnti
                 })?
                 .iter()
                 .map(parse_cp_templates)
-                .collect::<Result<Templates, crate::Error>>()
+                .collect::<Result<Vec<_>, crate::Error>>()
         })
-        .unwrap_or_else(|| Ok(HashMap::new()))?;
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+    let parse_flag = |key: &str| {
+        obj.get(key)
+            .map(|val| {
+                val.as_bool().ok_or_else(|| {
+                    crate::Error::InvalidPlaybook(
+                        format!("cp.{} must be boolean", key),
+                        val.to_owned(),
+                    )
+                })
+            })
+            .unwrap_or(Ok(false))
+    };
+    let mut targets = GlobSetBuilder::new();
+    let mut owners = Vec::new();
+    let mut vars = Vec::new();
+    let mut engines = Vec::new();
+    for (owner, (patterns, var_set, engine)) in entries.into_iter().enumerate() {
+        for pattern in patterns {
+            let glob = GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| {
+                    crate::Error::InvalidPlaybook(
+                        format!("invalid cp.templates.target {} due to {}", pattern, e),
+                        crate::ast::Value::Str(pattern.clone()),
+                    )
+                })?;
+            targets.add(glob);
+            owners.push(owner);
+        }
+        vars.push(var_set);
+        engines.push(engine);
+    }
+    let templates = Templates {
+        vars,
+        engines,
+        targets: targets.build().map_err(|e| {
+            crate::Error::PlaybookLoadFailed(format!("cannot compile cp.templates due to {}", e))
+        })?,
+        owners,
+        all: parse_flag("template")?,
+        liquid_suffix: parse_flag("liquid_suffix")?,
+    };
+    let decrypt = obj
+        .get("decrypt")
+        .map(|decrypt| {
+            decrypt
+                .as_array()
+                .and_then(|decrypt| {
+                    decrypt
+                        .iter()
+                        .map(|path| path.as_str().map(|path| path.to_owned()))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| {
+                    crate::Error::InvalidPlaybook(
+                        "cp.decrypt must be array of string".to_owned(),
+                        decrypt.to_owned(),
+                    )
+                })
+        })
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+    let attributes = Arc::new(attrs::parse(obj, "cp")?);
+    let exclude = Arc::new(exclude::parse(obj, "cp")?);
+    let backup = obj
+        .get("backup")
+        .map(|val| {
+            val.as_bool().ok_or_else(|| {
+                crate::Error::InvalidPlaybook(
+                    "cp.backup must be boolean".to_owned(),
+                    val.to_owned(),
+                )
+            })
+        })
+        .transpose()?;
+    let symlinks = match obj.get("symlinks") {
+        None => Symlinks::default(),
+        Some(val) => match val.as_str() {
+            Some("preserve") => Symlinks::Preserve,
+            Some("follow") => Symlinks::Follow,
+            Some("error") => Symlinks::Error,
+            _ => {
+                return Err(crate::Error::InvalidPlaybook(
+                    "cp.symlinks must be \"preserve\", \"follow\" or \"error\"".to_owned(),
+                    val.to_owned(),
+                ))
+            }
+        },
+    };
+    let strategy = match obj.get("strategy") {
+        None => Strategy::default(),
+        Some(val) => match val.as_str() {
+            Some("copy") => Strategy::Copy,
+            Some("hardlink") => Strategy::Hardlink,
+            Some("reflink") => Strategy::Reflink,
+            Some("symlink") => Strategy::Symlink,
+            _ => {
+                return Err(crate::Error::InvalidPlaybook(
+                    "cp.strategy must be \"copy\", \"hardlink\", \"reflink\" or \"symlink\""
+                        .to_owned(),
+                    val.to_owned(),
+                ))
+            }
+        },
+    };
+    // links share mode and ownership with the source, so these would be silently ignored
+    if matches!(strategy, Strategy::Hardlink | Strategy::Symlink) && attributes.manages_files() {
+        return Err(crate::Error::InvalidPlaybook(
+            "cp.mode, cp.owner and cp.group (including those of cp.attributes) cannot be set \
+             with cp.strategy hardlink or symlink, only cp.dir_mode can"
+                .to_owned(),
+            obj["strategy"].to_owned(),
+        ));
+    }
     Ok(TaskEntity::Cp(CpTask {
         src,
         dest,
         merge,
         templates,
+        decrypt,
+        attributes,
+        symlinks,
+        strategy,
+        exclude,
+        backup,
     }))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Temporary directory removed when dropped, even if the test panics.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("dotman-test-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    impl CpContext {
+        /// Context copying files under `base` without any option.
+        fn for_test(base: &Path) -> Self {
+            Self {
+                base: base.to_owned(),
+                dryrun: false,
+                strict: false,
+                merge: false,
+                templates: Templates::default(),
+                default_vars: liquid::Object::new(),
+                decrypt: Vec::new(),
+                attributes: Default::default(),
+                symlinks: Symlinks::default(),
+                strategy: Strategy::default(),
+                exclude: Default::default(),
+                backup: None,
+                state: None,
+                vars: liquid::Object::new(),
+                filters: crate::filters::Filters::default(),
+                notes: Default::default(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cp_encrypted_files() {
+        let dir = TempDir::new("cp-enc");
+        let src = dir.join("pkgs/ssh");
+        let dest = dir.join("out");
+        std::fs::create_dir_all(&src).unwrap();
+        let keyring = crate::secret::Keyring::from_passphrase(b"passphrase");
+        std::fs::write(
+            src.join("config.enc"),
+            keyring.encrypt_bytes(b"Host github.com\n").unwrap(),
+        )
+        .unwrap();
+        std::fs::write(src.join("key"), keyring.encrypt_bytes(b"private").unwrap()).unwrap();
+        let ctx = CpContext {
+            decrypt: vec!["pkgs/ssh/key".to_owned()],
+            filters: crate::filters::Filters::new(dir.to_owned(), false, keyring),
+            ..CpContext::for_test(&dir)
+        };
+        let (src, dest) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        assert!(execute_cp(&ctx, src, dest).await.unwrap());
+        let out = dir.join("out");
+        assert_eq!(
+            std::fs::read_to_string(out.join("config")).unwrap(),
+            "Host github.com\n"
+        );
+        assert_eq!(std::fs::read_to_string(out.join("key")).unwrap(), "private");
+        assert_eq!(
+            std::fs::metadata(out.join("config"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+        assert!(!out.join("config.enc").exists());
+        // compared with decrypted content
+        assert!(!execute_cp(&ctx, src, dest).await.unwrap());
+        // the key file is not an age identity
+        std::fs::write(dir.join("pkgs/ssh/known_hosts.age"), "age").unwrap();
+        let e = execute_cp(&ctx, src, dest).await.unwrap_err();
+        assert!(format!("{:?}", e).contains("age_identity is not configured"));
+    }
+
+    #[tokio::test]
+    async fn test_cp_template_targets() {
+        let dir = TempDir::new("cp-tmpl");
+        let src = dir.join("pkgs/app");
+        let dest = dir.join("out");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a.conf"), "a={{ name }}").unwrap();
+        std::fs::write(src.join("sub/b.conf"), "b={{ name }}").unwrap();
+        std::fs::write(src.join("c.toml.liquid"), "c={{ _scenario }}").unwrap();
+        std::fs::write(src.join("d.txt"), "d={{ name }}").unwrap();
+        let mut targets = GlobSetBuilder::new();
+        for pattern in ["pkgs/app/*.conf", "pkgs/app/**/*.conf"] {
+            targets.add(
+                GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .build()
+                    .unwrap(),
+            );
+        }
+        let var_set = |name: &str| {
+            let mut object = liquid::Object::new();
+            object.insert(
+                KStringBase::from_static("name"),
+                liquid::model::Value::scalar(name.to_owned()),
+            );
+            object
+        };
+        let mut default_vars = liquid::Object::new();
+        default_vars.insert(
+            KStringBase::from_static("_scenario"),
+            liquid::model::Value::scalar("test"),
+        );
+        let ctx = CpContext {
+            templates: Templates {
+                vars: vec![var_set("first"), var_set("second")],
+                engines: vec![None, Some(Engine::Liquid)],
+                targets: targets.build().unwrap(),
+                owners: vec![0, 1],
+                all: false,
+                liquid_suffix: true,
+            },
+            default_vars,
+            ..CpContext::for_test(&dir)
+        };
+        let (src, dest) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        assert!(execute_cp(&ctx, src, dest).await.unwrap());
+        let out = dir.join("out");
+        let read = |path: &str| std::fs::read_to_string(out.join(path)).unwrap();
+        // the first matched entry wins
+        assert_eq!(read("a.conf"), "a=first");
+        // `*` does not match `/`
+        assert_eq!(read("sub/b.conf"), "b=second");
+        assert_eq!(read("c.toml"), "c=test");
+        assert_eq!(read("d.txt"), "d={{ name }}");
+        assert!(!out.join("c.toml.liquid").exists());
+    }
+
+    #[test]
+    fn test_template_sources() {
+        let dir = TempDir::new("cp-sources");
+        std::fs::create_dir_all(dir.join("pkgs/sway")).unwrap();
+        std::fs::write(dir.join("pkgs/sway/config"), "{% include 'colors' %}").unwrap();
+        std::fs::write(dir.join("pkgs/sway/bar.liquid"), "{% include 'fonts' %}").unwrap();
+        std::fs::write(dir.join("pkgs/sway/README"), "{% include 'docs' %}").unwrap();
+        let src = concat!(
+            "src: pkgs/sway\n",
+            "dest: out\n",
+            "liquid_suffix: true\n",
+            "templates:\n",
+            "- { target: pkgs/sway/config, vars: {} }\n",
+        );
+        let yaml = yaml_rust::YamlLoader::load_from_str(src).unwrap();
+        let obj = crate::ast::Value::from_yaml(yaml[0].clone()).unwrap();
+        let task = match parse(obj.as_hash().unwrap()).unwrap() {
+            crate::TaskEntity::Cp(task) => task,
+            _ => unreachable!(),
+        };
+        let mut sources = task.template_sources(&dir);
+        sources.sort();
+        assert_eq!(
+            sources,
+            vec![
+                dir.join("pkgs/sway/bar.liquid"),
+                dir.join("pkgs/sway/config")
+            ]
+        );
+        let err = crate::partials::Partials::default()
+            .verify_files(&sources)
+            .unwrap_err();
+        let err = format!("{:?}", err);
+        assert!(err.contains("colors") && err.contains("fonts") && !err.contains("docs"));
+    }
+
+    #[test]
+    fn test_parse_strategy_attributes() {
+        let parse_src = |src: &str| {
+            let yaml = yaml_rust::YamlLoader::load_from_str(src).unwrap();
+            let obj = crate::ast::Value::from_yaml(yaml[0].clone()).unwrap();
+            parse(obj.as_hash().unwrap())
+        };
+        assert!(parse_src("{ src: a, dest: b, strategy: symlink, dir_mode: \"0700\" }").is_ok());
+        assert!(parse_src("{ src: a, dest: b, strategy: copy, mode: \"0600\" }").is_ok());
+        for src in [
+            "{ src: a, dest: b, strategy: symlink, mode: \"0600\" }",
+            "{ src: a, dest: b, strategy: hardlink, owner: root }",
+            "{ src: a, dest: b, strategy: hardlink, attributes: [{ target: a, group: wheel }] }",
+        ] {
+            assert!(parse_src(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_parse_nested_template_vars() {
+        let src = concat!(
+            "target: pkgs/sway/config\n",
+            "vars:\n",
+            "  term: alacritty\n",
+            "  gaps: 4\n",
+            "  bar: true\n",
+            "  monitors:\n",
+            "  - { name: eDP-1, scale: 1.5 }\n",
+            "  - { name: DP-1, scale: 1.0 }\n",
+            "  colors: { bg: '#000000', fg: '#ffffff' }\n",
+        );
+        let yaml = yaml_rust::YamlLoader::load_from_str(src).unwrap();
+        let (targets, vars, engine) =
+            parse_cp_templates(&crate::ast::Value::from_yaml(yaml[0].clone()).unwrap()).unwrap();
+        assert_eq!(targets, vec!["pkgs/sway/config".to_owned()]);
+        assert_eq!(engine, None);
+        let template = concat!(
+            "{{ term }} {{ gaps }} {{ bar }}\n",
+            "{% for m in monitors %}output {{ m.name }} scale {{ m.scale }}\n{% endfor %}",
+            "{{ colors.bg }} {{ colors.fg }}",
+        );
+        let rendered = liquid::ParserBuilder::with_stdlib()
+            .build()
+            .unwrap()
+            .parse(template)
+            .unwrap()
+            .render(&vars)
+            .unwrap();
+        assert_eq!(
+            rendered,
+            concat!(
+                "alacritty 4 true\n",
+                "output eDP-1 scale 1.5\n",
+                "output DP-1 scale 1\n",
+                "#000000 #ffffff",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cp_alternates() {
+        let dir = TempDir::new("cp-alt");
+        let src = dir.join("pkgs/sway");
+        let dest = dir.join("out");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("config"), "default").unwrap();
+        std::fs::write(src.join("config##host.sakanainu"), "host").unwrap();
+        std::fs::write(src.join(format!("config##os.{}", crate::util::OS)), "os").unwrap();
+        std::fs::write(src.join("config##host.other"), "other").unwrap();
+        std::fs::write(src.join("bar##os.Plan9"), "plan9").unwrap();
+        std::fs::write(src.join("bar##scenario.desktop,default"), "desktop").unwrap();
+        std::fs::write(src.join("input##os.Plan9"), "plan9").unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::fs::write(dest.join("input##os.Plan9"), "kept").unwrap();
+        let mut facts = liquid::Object::new();
+        facts.insert(
+            KStringBase::from_static("hostname"),
+            liquid::model::Value::scalar("sakanainu"),
+        );
+        let mut default_vars = liquid::Object::new();
+        default_vars.insert(
+            KStringBase::from_static("facts"),
+            liquid::model::Value::Object(facts),
+        );
+        default_vars.insert(
+            KStringBase::from_static("_scenario"),
+            liquid::model::Value::scalar("desktop"),
+        );
+        let ctx = CpContext {
+            default_vars,
+            ..CpContext::for_test(&dir)
+        };
+        let (src, dest) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        assert!(execute_cp(&ctx, src, dest).await.unwrap());
+        let out = dir.join("out");
+        let read = |path: &str| std::fs::read_to_string(out.join(path)).unwrap();
+        // host is more specific than os
+        assert_eq!(read("config"), "host");
+        assert_eq!(read("bar"), "desktop");
+        assert!(!out.join("input").exists());
+        assert_eq!(read("input##os.Plan9"), "kept");
+        assert_eq!(std::fs::read_dir(&out).unwrap().count(), 3);
+        assert!(!execute_cp(&ctx, src, dest).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cp_strict_dryrun() {
+        let dir = TempDir::new("cp-strict");
+        let src = dir.join("pkgs/fish");
+        let dest = dir.join("out");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("config.fish.liquid"), "set -x EDITOR {{ editor }}").unwrap();
+        std::fs::write(
+            src.join("env.fish.liquid"),
+            "set -x PAGER {{ pager | default: 'less' }}",
+        )
+        .unwrap();
+        let mut ctx = CpContext {
+            dryrun: true,
+            strict: true,
+            templates: Templates {
+                liquid_suffix: true,
+                ..Templates::default()
+            },
+            ..CpContext::for_test(&dir)
+        };
+        let (src, dest) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        match execute_cp(&ctx, src, dest).await {
+            Err(crate::TaskError::WellKnown(msg)) => {
+                assert!(msg.contains("config.fish.liquid"), "{}", msg);
+                assert!(msg.contains("undefined variable `editor`"), "{}", msg);
+                assert!(!msg.contains("env.fish.liquid"), "{}", msg);
+            }
+            _ => panic!("undefined variable must be reported"),
+        }
+        assert!(!dir.join("out").exists());
+        ctx.strict = false;
+        assert!(execute_cp(&ctx, src, dest).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cp_preview() {
+        let dir = TempDir::new("cp-preview");
+        let src = dir.join("pkgs/git");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(
+            src.join("gitconfig.liquid"),
+            "[user]\n  name = {{ _scenario }}",
+        )
+        .unwrap();
+        std::fs::write(src.join("ignore"), "{{ raw }}").unwrap();
+        std::fs::write(
+            src.join("token.liquid"),
+            "token = {{ \"github/token\" | secret }}\nuser = {{ _scenario }}\n",
+        )
+        .unwrap();
+        std::env::set_var("DOTMAN_TEST_PREVIEW_GITHUB_TOKEN", "s3cr3t");
+        let mut default_vars = liquid::Object::new();
+        default_vars.insert(
+            KStringBase::from_static("_scenario"),
+            liquid::model::Value::scalar("work"),
+        );
+        let ctx = CpContext {
+            dryrun: true,
+            strict: true,
+            merge: true,
+            templates: Templates {
+                liquid_suffix: true,
+                ..Templates::default()
+            },
+            default_vars,
+            filters: crate::filters::Filters::new(
+                PathBuf::new(),
+                false,
+                crate::secret::Keyring::default().with_provider(crate::secret::Provider::Env(
+                    "DOTMAN_TEST_PREVIEW_".to_owned(),
+                )),
+            ),
+            ..CpContext::for_test(&dir)
+        };
+        let (src, dest) = (src.to_str().unwrap(), dir.join("out"));
+        let dest = dest.to_str().unwrap();
+        let preview = preview_cp(&ctx, src, dest, &dir.join("pkgs/git/gitconfig.liquid"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(preview.dest, dir.join("out/gitconfig"));
+        assert_eq!(preview.content, b"[user]\n  name = work");
+        let preview = preview_cp(&ctx, src, dest, &dir.join("pkgs/git/ignore"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(preview.content, b"{{ raw }}");
+        assert!(!preview.sensitive);
+        assert_eq!(preview.output(false, false).unwrap(), b"{{ raw }}");
+        assert!(preview_cp(&ctx, src, dest, &dir.join("pkgs/git/missing"))
+            .await
+            .is_none());
+        assert!(!dir.join("out").exists());
+
+        // secrets are redacted unless asked, even where they are unchanged
+        let preview = preview_cp(&ctx, src, dest, &dir.join("pkgs/git/token.liquid"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(preview.sensitive);
+        let contains = |output: Vec<u8>| String::from_utf8(output).unwrap().contains("s3cr3t");
+        assert!(!contains(preview.output(false, false).unwrap()));
+        assert!(!contains(preview.output(true, false).unwrap()));
+        assert!(contains(preview.output(false, true).unwrap()));
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        std::fs::write(dir.join("out/token"), "token = s3cr3t\nuser = home\n").unwrap();
+        let diff = String::from_utf8(preview.output(true, false).unwrap()).unwrap();
+        assert!(!diff.contains("s3cr3t") && !diff.contains("home"));
+        assert!(diff.contains("-[redacted]\n+[redacted]\n"));
+        assert!(contains(preview.output(true, true).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_cp_attributes() {
+        let dir = TempDir::new("cp-attrs");
+        let src = dir.join("pkgs/ssh");
+        let dest = dir.join("out");
+        std::fs::create_dir_all(src.join("keys")).unwrap();
+        std::fs::write(src.join("config"), "Host *").unwrap();
+        std::fs::write(src.join("keys/id"), "key").unwrap();
+        let yaml = yaml_rust::YamlLoader::load_from_str(concat!(
+            "dir_mode: \"0700\"\n",
+            "attributes:\n",
+            "- { target: \"pkgs/ssh/**\", mode: \"0600\" }\n",
+        ))
+        .unwrap();
+        let ast = crate::ast::Value::from_yaml(yaml[0].clone()).unwrap();
+        let ctx = CpContext {
+            merge: true,
+            attributes: Arc::new(attrs::parse(ast.as_hash().unwrap(), "cp").unwrap()),
+            ..CpContext::for_test(&dir)
+        };
+        let (src, dest) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        assert!(execute_cp(&ctx, src, dest).await.unwrap());
+        let mode = |path: &str| {
+            std::fs::metadata(dir.join("out").join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("config"), 0o600);
+        assert_eq!(mode("keys"), 0o700);
+        assert_eq!(mode("keys/id"), 0o600);
+        assert!(ctx.notes.lock().unwrap().is_empty());
+        assert!(!execute_cp(&ctx, src, dest).await.unwrap());
+        // same content with different mode is a change
+        let config = dir.join("out/config");
+        std::fs::set_permissions(&config, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(execute_cp(&ctx, src, dest).await.unwrap());
+        assert_eq!(mode("config"), 0o600);
+        assert_eq!(
+            *ctx.notes.lock().unwrap(),
+            vec![format!("mode of {:?} 644 -> 600", config)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cp_symlinks() {
+        let dir = TempDir::new("cp-symlinks");
+        let src = dir.join("pkgs/app");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(dir.join("pkgs/themes")).unwrap();
+        std::fs::write(dir.join("pkgs/themes/dark"), "dark").unwrap();
+        std::fs::write(dir.join("victim"), "victim").unwrap();
+        std::fs::write(src.join("config"), "config").unwrap();
+        std::os::unix::fs::symlink("config", src.join("inner")).unwrap();
+        std::os::unix::fs::symlink("../themes/dark", src.join("outer")).unwrap();
+        std::os::unix::fs::symlink("../themes", src.join("themes")).unwrap();
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        std::os::unix::fs::symlink(dir.join("victim"), dir.join("out/config")).unwrap();
+        let mut ctx = CpContext {
+            merge: true,
+            symlinks: Symlinks::Preserve,
+            ..CpContext::for_test(&dir)
+        };
+        let src = src.to_str().unwrap();
+        let dest = dir.join("out");
+        assert!(execute_cp(&ctx, src, dest.to_str().unwrap()).await.unwrap());
+        let link = |path: &str| std::fs::read_link(dest.join(path)).unwrap();
+        assert_eq!(link("inner"), Path::new("config"));
+        // relative targets outside of the tree keep pointing to the same file
+        assert_eq!(link("outer"), dir.join("pkgs/themes/dark"));
+        assert_eq!(link("themes"), dir.join("pkgs/themes"));
+        // destination symlink is replaced, not written through
+        assert!(!std::fs::symlink_metadata(dest.join("config"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(dest.join("config")).unwrap(),
+            "config"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("victim")).unwrap(),
+            "victim"
+        );
+        assert!(!execute_cp(&ctx, src, dest.to_str().unwrap()).await.unwrap());
+
+        ctx.symlinks = Symlinks::Follow;
+        let dest = dir.join("out-follow");
+        assert!(execute_cp(&ctx, src, dest.to_str().unwrap()).await.unwrap());
+        assert!(std::fs::symlink_metadata(dest.join("themes"))
+            .unwrap()
+            .is_dir());
+        assert_eq!(
+            std::fs::read_to_string(dest.join("themes/dark")).unwrap(),
+            "dark"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dest.join("inner")).unwrap(),
+            "config"
+        );
+
+        ctx.symlinks = Symlinks::Error;
+        let dest = dir.join("out-error");
+        assert!(matches!(
+            execute_cp(&ctx, src, dest.to_str().unwrap()).await,
+            Err(crate::TaskError::WellKnown(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cp_transitions() {
+        fn make(path: &Path, kind: &str, name: &str) {
+            match kind {
+                "file" => std::fs::write(path, name).unwrap(),
+                "dir" => {
+                    std::fs::create_dir(path).unwrap();
+                    std::fs::write(path.join(name), name).unwrap();
+                }
+                "symlink" => std::os::unix::fs::symlink(name, path).unwrap(),
+                "fifo" => {
+                    let path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+                    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o644) }, 0);
+                }
+                _ => (),
+            }
+        }
+        fn kind_of(path: &Path) -> &'static str {
+            match std::fs::symlink_metadata(path) {
+                Err(_) => "nothing",
+                Ok(meta) if meta.file_type().is_symlink() => "symlink",
+                Ok(meta) if meta.is_dir() => "dir",
+                Ok(meta) if meta.is_file() => "file",
+                Ok(_) => "fifo",
+            }
+        }
+        let dir = TempDir::new("cp-transitions");
+        let kinds = ["nothing", "file", "dir", "symlink", "fifo"];
+        let mut ctx = CpContext {
+            symlinks: Symlinks::Preserve,
+            backup: Some(crate::backup::Backup::new(&dir.join("backups"), true)),
+            ..CpContext::for_test(&dir)
+        };
+        for merge in [false, true] {
+            for src_kind in &kinds[..4] {
+                for dest_kind in kinds {
+                    let name = format!("{}-{}-{}", src_kind, dest_kind, merge);
+                    let src = dir.join("pkgs").join(&name);
+                    let dest = dir.join("out").join(&name);
+                    std::fs::create_dir_all(&src).unwrap();
+                    std::fs::create_dir_all(&dest).unwrap();
+                    make(&src.join("x"), src_kind, "new");
+                    make(&dest.join("x"), dest_kind, "old");
+                    let (src, dest_str) = (src.to_str().unwrap(), dest.to_str().unwrap());
+                    ctx.merge = merge;
+                    ctx.dryrun = true;
+                    let changed = execute_cp(&ctx, src, dest_str).await.unwrap();
+                    assert_eq!(kind_of(&dest.join("x")), dest_kind, "{}", name);
+                    ctx.dryrun = false;
+                    assert_eq!(
+                        execute_cp(&ctx, src, dest_str).await.unwrap(),
+                        changed,
+                        "{}",
+                        name
+                    );
+                    let expected = if *src_kind == "nothing" && merge {
+                        dest_kind
+                    } else {
+                        src_kind
+                    };
+                    assert_eq!(kind_of(&dest.join("x")), expected, "{}", name);
+                    match expected {
+                        "file" if *src_kind == "file" => {
+                            assert_eq!(std::fs::read_to_string(dest.join("x")).unwrap(), "new")
+                        }
+                        "dir" if *src_kind == "dir" => {
+                            assert!(dest.join("x/new").exists(), "{}", name);
+                            assert_eq!(
+                                dest.join("x/old").exists(),
+                                merge && dest_kind == "dir",
+                                "{}",
+                                name
+                            );
+                        }
+                        "symlink" if *src_kind == "symlink" => assert_eq!(
+                            std::fs::read_link(dest.join("x")).unwrap(),
+                            Path::new("new")
+                        ),
+                        _ => (),
+                    }
+                    assert!(!execute_cp(&ctx, src, dest_str).await.unwrap(), "{}", name);
+                }
+            }
+        }
+        assert!(!ctx.backup.as_ref().unwrap().is_empty());
+        // dry-run lists the plan
+        let notes = ctx.notes.lock().unwrap().clone();
+        let replaced = dir.join("out/file-dir-false/x");
+        assert!(notes.contains(&format!("remove {:?} to replace it", replaced)));
+        assert!(notes.contains(&format!("create {:?}", replaced)));
+
+        // nothing is touched if any source file cannot be deployed
+        let src = dir.join("pkgs/fifo");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("a"), "a").unwrap();
+        make(&src.join("b"), "fifo", "");
+        let dest = dir.join("out/fifo");
+        assert!(matches!(
+            execute_cp(&ctx, src.to_str().unwrap(), dest.to_str().unwrap()).await,
+            Err(crate::TaskError::WellKnown(_))
+        ));
+        assert!(!dest.exists());
+    }
+
+    /// Write a tree shaped like plugin managers' checkouts with a template at its root, and
+    /// return a context deploying it.
+    fn fixture_tree(dir: &Path, plugins: usize, files: usize) -> CpContext {
+        let src = dir.join("pkgs/neovim");
+        for plugin in 0..plugins {
+            let plugin = src.join(format!("pack/plugin{}/start/lua", plugin));
+            std::fs::create_dir_all(&plugin).unwrap();
+            for file in 0..files {
+                std::fs::write(
+                    plugin.join(format!("module{}.lua", file)),
+                    format!("return {}\n", file).repeat(64),
+                )
+                .unwrap();
+            }
+        }
+        std::fs::write(
+            src.join("init.lua.liquid"),
+            "vim.o.tabstop = {{ tabstop }}\n",
+        )
+        .unwrap();
+        let mut default_vars = liquid::Object::new();
+        default_vars.insert("tabstop".into(), liquid::model::Value::scalar(4));
+        CpContext {
+            templates: Templates {
+                liquid_suffix: true,
+                ..Templates::default()
+            },
+            default_vars,
+            ..CpContext::for_test(dir)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cp_fixture_tree() {
+        let dir = TempDir::new("cp-tree");
+        let src = dir.join("pkgs/neovim");
+        let dest = dir.join("out");
+        let ctx = fixture_tree(&dir, 20, 25);
+        let (src_str, dest_str) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        assert!(execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        assert_eq!(
+            std::fs::read_to_string(dest.join("init.lua")).unwrap(),
+            "vim.o.tabstop = 4\n"
+        );
+        let module = Path::new("pack/plugin7/start/lua/module3.lua");
+        assert!(!execute_cp(&ctx, src_str, dest_str).await.unwrap());
+
+        // content of the same size is compared even if mtime matches the source as by `touch -r`
+        std::fs::write(dest.join(module), "return 9\n".repeat(64)).unwrap();
+        let mtime = std::fs::metadata(src.join(module))
+            .unwrap()
+            .modified()
+            .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(dest.join(module))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert!(execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        assert_eq!(
+            std::fs::read_to_string(dest.join(module)).unwrap(),
+            "return 3\n".repeat(64)
+        );
+        std::fs::write(dest.join("init.lua"), "vim.o.tabstop = 8\n").unwrap();
+        assert!(execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        assert!(!execute_cp(&ctx, src_str, dest_str).await.unwrap());
+    }
+
+    /// Timing of deploying and re-checking a large tree. Run with
+    /// `cargo test --release bench_cp_fixture_tree -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_cp_fixture_tree() {
+        let dir = TempDir::new("cp-bench");
+        let ctx = fixture_tree(&dir, 200, 50);
+        let src = dir.join("pkgs/neovim");
+        let dest = dir.join("out");
+        let (src, dest) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        for (label, changed) in [("deploy", true), ("unchanged", false)] {
+            let start = std::time::Instant::now();
+            assert_eq!(execute_cp(&ctx, src, dest).await.unwrap(), changed);
+            println!("{}: {:?}", label, start.elapsed());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cp_state() {
+        let dir = TempDir::new("cp-state");
+        let src = dir.join("pkgs/app");
+        let dest = dir.join("out");
+        let path = dir.join("state.json");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("plain"), "plain").unwrap();
+        std::fs::write(src.join("greeting.liquid"), "hello {{ name }}").unwrap();
+        let mut default_vars = liquid::Object::new();
+        default_vars.insert("name".into(), liquid::model::Value::scalar("alice"));
+        let mut ctx = CpContext {
+            templates: Templates {
+                liquid_suffix: true,
+                ..Templates::default()
+            },
+            default_vars,
+            state: Some(State::load(&path, false)),
+            ..CpContext::for_test(&dir)
+        };
+        let (src, dest_str) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        assert!(execute_cp(&ctx, src, dest_str).await.unwrap());
+        ctx.state.as_ref().unwrap().save().await.unwrap();
+
+        // tamper without changing size, mtime or inode, which still changes ctime
+        let greeting = dest.join("greeting");
+        let modified = std::fs::metadata(&greeting).unwrap().modified().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&greeting)
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"HELLO ALICE").unwrap();
+        file.set_modified(modified).unwrap();
+        drop(file);
+        ctx.state = Some(State::load(&path, false));
+        assert!(execute_cp(&ctx, src, dest_str).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&greeting).unwrap(), "hello alice");
+        ctx.state.as_ref().unwrap().save().await.unwrap();
+        ctx.state = Some(State::load(&path, false));
+        assert!(!execute_cp(&ctx, src, dest_str).await.unwrap());
+        // a chmod invalidates the record, which is refreshed once contents are compared
+        std::fs::set_permissions(&greeting, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(!execute_cp(&ctx, src, dest_str).await.unwrap());
+        ctx.state.as_ref().unwrap().save().await.unwrap();
+        assert_eq!(
+            State::load(&path, false).get(&greeting).unwrap().dest,
+            Stat::of(&std::fs::metadata(&greeting).unwrap())
+        );
+        ctx.state = Some(State::load(&path, true));
+        assert!(!execute_cp(&ctx, src, dest_str).await.unwrap());
+        ctx.state.as_ref().unwrap().save().await.unwrap();
+
+        // changed variables invalidate records of templates
+        ctx.state = Some(State::load(&path, false));
+        ctx.default_vars
+            .insert("name".into(), liquid::model::Value::scalar("bob"));
+        assert!(execute_cp(&ctx, src, dest_str).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&greeting).unwrap(), "hello bob");
+        ctx.state.as_ref().unwrap().save().await.unwrap();
+        ctx.state = Some(State::load(&path, false));
+        assert!(!execute_cp(&ctx, src, dest_str).await.unwrap());
+
+        // removed files are forgotten
+        std::fs::remove_file(dir.join("pkgs/app/plain")).unwrap();
+        assert!(execute_cp(&ctx, src, dest_str).await.unwrap());
+        ctx.state.as_ref().unwrap().save().await.unwrap();
+        assert!(State::load(&path, false).get(&dest.join("plain")).is_none());
+        assert!(State::load(&path, false).get(&greeting).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cp_strategy() {
+        let dir = TempDir::new("cp-strategy");
+        let src = dir.join("pkgs/app");
+        let dest = dir.join("out");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("plain"), "plain").unwrap();
+        std::fs::write(src.join("greeting.liquid"), "hello {{ name }}").unwrap();
+        let mut default_vars = liquid::Object::new();
+        default_vars.insert("name".into(), liquid::model::Value::scalar("alice"));
+        let mut ctx = CpContext {
+            templates: Templates {
+                liquid_suffix: true,
+                ..Templates::default()
+            },
+            default_vars,
+            strategy: Strategy::Hardlink,
+            ..CpContext::for_test(&dir)
+        };
+        let (plain, greeting) = (dest.join("plain"), dest.join("greeting"));
+        let ino = |path: &Path| std::fs::metadata(path).unwrap().ino();
+        let (src_str, dest_str) = (src.to_str().unwrap(), dest.to_str().unwrap());
+
+        assert!(execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        assert_eq!(ino(&plain), ino(&src.join("plain")));
+        // templates are always copied
+        assert!(!std::fs::symlink_metadata(&greeting)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read_to_string(&greeting).unwrap(), "hello alice");
+        assert!(!execute_cp(&ctx, src_str, dest_str).await.unwrap());
+
+        ctx.strategy = Strategy::Symlink;
+        assert!(execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        assert_eq!(
+            std::fs::read_link(&plain).unwrap(),
+            std::path::absolute(src.join("plain")).unwrap()
+        );
+        assert!(!std::fs::symlink_metadata(&greeting)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(!execute_cp(&ctx, src_str, dest_str).await.unwrap());
+
+        // copies replace hardlinks rather than writing through them into the source
+        ctx.strategy = Strategy::Hardlink;
+        assert!(execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        ctx.strategy = Strategy::Copy;
+        assert!(execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        assert_ne!(ino(&plain), ino(&src.join("plain")));
+        assert!(!execute_cp(&ctx, src_str, dest_str).await.unwrap());
+
+        // reflinks fall back to copies where unsupported, and compare by content either way
+        ctx.strategy = Strategy::Reflink;
+        assert!(!execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        std::fs::write(src.join("plain"), "PLAIN").unwrap();
+        assert!(execute_cp(&ctx, src_str, dest_str).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&plain).unwrap(), "PLAIN");
+        assert_ne!(ino(&plain), ino(&src.join("plain")));
+        assert!(!execute_cp(&ctx, src_str, dest_str).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_cp_exclude() {
+        let dir = TempDir::new("cp-exclude");
+        let src = dir.join("pkgs/nvim");
+        let dest = dir.join("out");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(dest.join("cache")).unwrap();
+        std::fs::write(src.join("init.lua"), "init").unwrap();
+        std::fs::write(src.join(".netrwhist"), "history").unwrap();
+        std::fs::write(src.join(".dotmanignore"), "cache/\n").unwrap();
+        std::fs::write(dest.join("cache/state"), "state").unwrap();
+        std::fs::write(dest.join("stale"), "stale").unwrap();
+        let yaml = yaml_rust::YamlLoader::load_from_str("exclude: \"**/.netrwhist\"").unwrap();
+        let ast = crate::ast::Value::from_yaml(yaml[0].clone()).unwrap();
+        let ctx = CpContext {
+            exclude: Arc::new(exclude::parse(ast.as_hash().unwrap(), "cp").unwrap()),
+            ..CpContext::for_test(&dir)
+        };
+        let (src, dest) = (src.to_str().unwrap(), dest.to_str().unwrap());
+        assert!(execute_cp(&ctx, src, dest).await.unwrap());
+        let out = dir.join("out");
+        assert!(out.join("init.lua").exists());
+        assert!(!out.join(".netrwhist").exists());
+        assert!(!out.join(".dotmanignore").exists());
+        assert!(!out.join("stale").exists());
+        // ignored destination files are kept even with `merge: false`
+        assert!(out.join("cache/state").exists());
+        assert!(!execute_cp(&ctx, src, dest).await.unwrap());
+    }
+}
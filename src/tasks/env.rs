@@ -1,7 +1,7 @@
 //! Builtin env task.
 use std::{collections::HashMap, env};
 
-use crate::util::resolve_liquid_template;
+use crate::util::{describe_render_error, resolve_template};
 
 /// Implementation of [Task trait](../../trait.Task.html).
 pub struct EnvTask {
@@ -28,12 +28,16 @@ impl crate::Task for EnvTask {
         }
     }
 
-    async fn execute(&self, _: &crate::TaskContext) -> crate::TaskResult {
+    async fn execute(&self, ctx: &crate::TaskContext) -> crate::TaskResult {
         let mut changed = false;
         for (name, value) in &self.envs {
             if let Some(value) = value {
-                let value = resolve_liquid_template(value).map_err(|_| {
-                    crate::TaskError::WellKnown(format!("cannot resolve env value {}", value))
+                let value = resolve_template(value, ctx.vars, ctx.filters).map_err(|e| {
+                    crate::TaskError::WellKnown(format!(
+                        "cannot resolve env value {} due to {}",
+                        value,
+                        describe_render_error(&e)
+                    ))
                 })?;
                 match env::var(name) {
                     Ok(s) => {
@@ -0,0 +1,231 @@
+//! Paths of `cp` trees skipped by `exclude`, `include`, `.dotmanignore` and `.gitignore`.
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::ast::Value;
+use crate::Error;
+
+/// Ignore file honoured in every source tree. It is never deployed itself.
+pub const DOTMANIGNORE: &str = ".dotmanignore";
+
+/// `exclude`, `include` and `gitignore` options of a task.
+#[derive(Debug, Clone)]
+pub struct Patterns {
+    /// Patterns relative to playbook
+    exclude: GlobSet,
+    /// Only files matching these are deployed if given
+    include: Option<GlobSet>,
+    /// Honour `.gitignore` files in the tree
+    gitignore: bool,
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Self {
+            exclude: GlobSet::empty(),
+            include: None,
+            gitignore: false,
+        }
+    }
+}
+
+/// Patterns with ignore files loaded from a source tree.
+#[derive(Debug)]
+pub struct Rules<'a> {
+    base: &'a Path,
+    patterns: &'a Patterns,
+    /// Ignore files of each directory, parents first
+    ignores: Vec<Gitignore>,
+}
+
+impl<'a> Rules<'a> {
+    /// Whether `path` (of the source tree) is skipped.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if path.file_name() == Some(DOTMANIGNORE.as_ref()) {
+            return true;
+        }
+        let relative = path.strip_prefix(self.base).unwrap_or(path);
+        if self.patterns.exclude.is_match(relative) {
+            return true;
+        }
+        if let Some(include) = &self.patterns.include {
+            // directories are traversed to find included files
+            if !is_dir && !include.is_match(relative) {
+                return true;
+            }
+        }
+        // the deepest ignore file deciding the path wins
+        for ignore in self.ignores.iter().rev() {
+            if !path.starts_with(ignore.path()) {
+                continue;
+            }
+            match ignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => (),
+            }
+        }
+        false
+    }
+}
+
+impl Patterns {
+    /// Load ignore files of directories under `root` which are not skipped themselves.
+    pub fn load<'a>(&'a self, base: &'a Path, root: &Path) -> anyhow::Result<Rules<'a>> {
+        let mut rules = Rules {
+            base,
+            patterns: self,
+            ignores: Vec::new(),
+        };
+        let mut dirs = vec![root.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            let mut builder = GitignoreBuilder::new(&dir);
+            let mut names = vec![DOTMANIGNORE];
+            if self.gitignore {
+                // .dotmanignore is added later to take precedence
+                names.insert(0, ".gitignore");
+            }
+            let mut found = false;
+            for name in names {
+                let file = dir.join(name);
+                if file.is_file() {
+                    if let Some(e) = builder.add(&file) {
+                        anyhow::bail!("cannot read {:?} due to {}", file, e);
+                    }
+                    found = true;
+                }
+            }
+            if found {
+                rules.ignores.push(builder.build()?);
+            }
+            let mut children = Vec::new();
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if std::fs::symlink_metadata(&path)?.is_dir() && !rules.is_excluded(&path, true) {
+                    children.push(path);
+                }
+            }
+            // visit parents before children so that `ignores` stays ordered
+            children.sort_unstable_by(|a, b| b.cmp(a));
+            dirs.extend(children);
+        }
+        Ok(rules)
+    }
+}
+
+fn parse_globs(
+    obj: &HashMap<String, Value>,
+    name: &str,
+    key: &str,
+) -> Result<Option<GlobSet>, Error> {
+    let patterns = match obj.get(name) {
+        None => return Ok(None),
+        Some(Value::Str(pattern)) => vec![pattern.as_str()],
+        Some(Value::Array(patterns)) => patterns
+            .iter()
+            .map(|pattern| {
+                pattern.as_str().ok_or_else(|| {
+                    Error::InvalidPlaybook(
+                        format!("{} must be string or array of string", key),
+                        pattern.to_owned(),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(invalid) => {
+            return Err(Error::InvalidPlaybook(
+                format!("{} must be string or array of string", key),
+                invalid.to_owned(),
+            ))
+        }
+    };
+    let mut globs = GlobSetBuilder::new();
+    for pattern in patterns {
+        globs.add(
+            GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| {
+                    Error::InvalidPlaybook(
+                        format!("invalid {} {} due to {}", key, pattern, e),
+                        Value::Str(pattern.to_owned()),
+                    )
+                })?,
+        );
+    }
+    globs
+        .build()
+        .map(Some)
+        .map_err(|e| Error::PlaybookLoadFailed(format!("cannot compile {} due to {}", key, e)))
+}
+
+/// Parse `exclude`, `include` and `gitignore` of task `obj`.
+pub fn parse(obj: &HashMap<String, Value>, prefix: &str) -> Result<Patterns, Error> {
+    let gitignore = match obj.get("gitignore") {
+        None => false,
+        Some(val) => val.as_bool().ok_or_else(|| {
+            Error::InvalidPlaybook(
+                format!("{}.gitignore must be boolean", prefix),
+                val.to_owned(),
+            )
+        })?,
+    };
+    Ok(Patterns {
+        exclude: parse_globs(obj, "exclude", &format!("{}.exclude", prefix))?
+            .unwrap_or_else(GlobSet::empty),
+        include: parse_globs(obj, "include", &format!("{}.include", prefix))?,
+        gitignore,
+    })
+}
+
+/// Map `path` of the destination tree `dest` into the source tree `src`.
+pub fn source_of(src: &Path, dest: &Path, path: &Path) -> PathBuf {
+    src.join(path.strip_prefix(dest).unwrap_or(path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_exclude() {
+        let base = std::env::temp_dir().join(format!("dotman-test-exclude-{}", std::process::id()));
+        let root = base.join("pkgs/nvim");
+        std::fs::create_dir_all(root.join("lua/cache")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n/lua/cache/\n").unwrap();
+        std::fs::write(root.join("lua/.dotmanignore"), "!keep.log\n").unwrap();
+        let yaml = YamlLoader::load_from_str(concat!(
+            "exclude: [\"**/.netrwhist\", \"**/.gitignore\"]\n",
+            "gitignore: true\n",
+        ))
+        .unwrap();
+        let ast = Value::from_yaml(yaml[0].clone()).unwrap();
+        let patterns = parse(ast.as_hash().unwrap(), "cp").unwrap();
+        let rules = patterns.load(&base, &root).unwrap();
+        assert!(rules.is_excluded(&root.join(".netrwhist"), false));
+        assert!(rules.is_excluded(&root.join(".gitignore"), false));
+        assert!(rules.is_excluded(&root.join("lua/.dotmanignore"), false));
+        assert!(rules.is_excluded(&root.join("debug.log"), false));
+        assert!(rules.is_excluded(&root.join("lua/cache"), true));
+        assert!(!rules.is_excluded(&root.join("lua/keep.log"), false));
+        assert!(!rules.is_excluded(&root.join("init.lua"), false));
+
+        let yaml = YamlLoader::load_from_str("include: \"**/*.lua\"").unwrap();
+        let ast = Value::from_yaml(yaml[0].clone()).unwrap();
+        let patterns = parse(ast.as_hash().unwrap(), "cp").unwrap();
+        let rules = patterns.load(&base, &root).unwrap();
+        assert!(!rules.is_excluded(&root.join("lua/init.lua"), false));
+        assert!(!rules.is_excluded(&root.join("lua"), true));
+        assert!(rules.is_excluded(&root.join("README.md"), false));
+        // .gitignore is not honoured unless `gitignore: true`
+        assert!(!rules.is_excluded(&root.join("lua/cache"), true));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -1,14 +1,22 @@
-//! Builtin env task.
+//! Builtin link task.
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::os;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use tokio::{fs, io};
 
+use crate::state::{Record, Stat};
+
 /// Implementation of [Task trait](../../trait.Task.html).
 pub struct LinkTask {
     src: String,
     dest: String,
+    /// `backup` option overriding the playbook
+    backup: Option<bool>,
+    /// Replace a destination which is not a symlink
+    force: bool,
+    /// Point to `src` by a path relative to `dest`
+    relative: bool,
 }
 
 #[cfg(target_family = "unix")]
@@ -25,80 +33,166 @@ fn symlink(src: &Path, dest: &Path) -> io::Result<()> {
     }
 }
 
+/// Path to `target` relative to directory `base`, both absolute.
+fn relative_to(target: &Path, base: &Path) -> PathBuf {
+    let target = target.components().collect::<Vec<_>>();
+    let base = base.components().collect::<Vec<_>>();
+    let common = target.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..base.len() {
+        path.push(Component::ParentDir);
+    }
+    path.extend(&target[common..]);
+    if path.as_os_str().is_empty() {
+        path.push(Component::CurDir);
+    }
+    path
+}
+
 #[async_trait::async_trait]
 impl crate::Task for LinkTask {
     fn name(&self) -> String {
         format!("link {} => {}", self.src, self.dest)
     }
 
-    async fn execute(&self, _: &crate::TaskContext) -> crate::TaskResult {
-        let src = crate::util::resolve_liquid_template(&self.src).map_err(|e| {
-            crate::TaskError::WellKnown(format!("cannot resolve tasks.link.src due to {:?}", e))
-        })?;
-        let dest = crate::util::resolve_liquid_template(&self.dest).map_err(|e| {
-            crate::TaskError::WellKnown(format!("cannot resolve tasks.link.dest due to {:?}", e))
+    async fn execute(&self, ctx: &crate::TaskContext) -> crate::TaskResult {
+        let src = crate::util::resolve_template(&self.src, ctx.vars, ctx.filters).map_err(|e| {
+            crate::TaskError::WellKnown(format!(
+                "cannot resolve tasks.link.src due to {}",
+                crate::util::describe_render_error(&e)
+            ))
         })?;
-        let src = OsStr::new(&src);
-        let dest = OsStr::new(&dest);
-        let src = Path::new(src).canonicalize().map_err(|e| {
+        let dest =
+            crate::util::resolve_template(&self.dest, ctx.vars, ctx.filters).map_err(|e| {
+                crate::TaskError::WellKnown(format!(
+                    "cannot resolve tasks.link.dest due to {}",
+                    crate::util::describe_render_error(&e)
+                ))
+            })?;
+        let src = ctx
+            .base
+            .join(OsStr::new(&src))
+            .canonicalize()
+            .map_err(|e| {
+                crate::TaskError::WellKnown(format!(
+                    "cannot canonicalize tasks.link.src due to {:?}",
+                    e
+                ))
+            })?;
+        let dest = Path::new(OsStr::new(&dest));
+        let parent = dest.parent().ok_or_else(|| {
             crate::TaskError::WellKnown(format!(
-                "cannot canonicalize tasks.link.dest due to {:?}",
-                e
+                "cannot take parent of desitination path {:?}",
+                dest
             ))
         })?;
-        let dest = Path::new(dest);
-
-        if fs::read_link(&dest)
-            .await
-            .map(|p| p == src)
-            .unwrap_or(false)
-        {
-            return Ok(false);
-        }
-        if let Ok(meta) = fs::metadata(&dest).await {
-            // TODO: use is_link
-            if meta.is_dir() {
-                fs::remove_dir_all(&dest).await.map_err(|e| {
+        let target = if self.relative {
+            // resolve symlinks in the parent as the link will be followed from there
+            let parent = match fs::canonicalize(parent).await {
+                Ok(parent) => parent,
+                Err(_) => std::path::absolute(parent).map_err(|e| {
                     crate::TaskError::WellKnown(format!(
-                        "cannot remove dir {:?} due to {:?}",
-                        dest, e
-                    ))
-                })?;
-            } else {
-                fs::remove_file(&dest).await.map_err(|e| {
-                    crate::TaskError::WellKnown(format!(
-                        "cannot remove dir {:?} due to {:?}",
+                        "cannot resolve parent of {:?} due to {:?}",
                         dest, e
                     ))
+                })?,
+            };
+            relative_to(&src, &parent)
+        } else {
+            src
+        };
+
+        let existing = match fs::symlink_metadata(dest).await {
+            Ok(meta) => Some(meta),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(crate::TaskError::WellKnown(format!(
+                    "cannot inspect {:?} due to {:?}",
+                    dest, e
+                )))
+            }
+        };
+        if let Some(meta) = &existing {
+            if meta.file_type().is_symlink() {
+                // a symlink cannot be modified in place, so the same inode still points to `target`
+                let recorded = ctx
+                    .state
+                    .and_then(|state| state.get(dest))
+                    .filter(|record| record.src == target && record.dest == Stat::of(meta))
+                    .is_some();
+                if recorded || fs::read_link(dest).await.is_ok_and(|p| p == target) {
+                    if !ctx.dryrun {
+                        record(ctx, &target, dest).await;
+                    }
+                    return Ok(false);
+                }
+            } else if !self.force {
+                return Err(crate::TaskError::WellKnown(format!(
+                    "{:?} exists and is not a symlink, which link.force: true replaces",
+                    dest
+                )));
+            }
+        }
+        if ctx.dryrun {
+            return Ok(true);
+        }
+        if let Some(meta) = existing {
+            if let Some(backup) = crate::backup::Backup::for_task(ctx.backup, self.backup) {
+                backup.save(dest).map_err(|e| {
+                    crate::TaskError::WellKnown(format!("cannot back up {:?} due to {:?}", dest, e))
                 })?;
             }
+            // symlinks to directories are removed by themselves, never what they point to
+            let removed = if meta.is_dir() {
+                fs::remove_dir_all(dest).await
+            } else {
+                fs::remove_file(dest).await
+            };
+            removed.map_err(|e| {
+                crate::TaskError::WellKnown(format!("cannot remove {:?} due to {:?}", dest, e))
+            })?;
         }
-        fs::create_dir_all(dest.parent().ok_or_else(|| {
-            crate::TaskError::WellKnown(format!(
-                "cannot take parent of desitination path {:?}",
-                dest
-            ))
-        })?)
-        .await
-        .map_err(|e| {
+        fs::create_dir_all(parent).await.map_err(|e| {
             crate::TaskError::WellKnown(format!(
                 "cannot create parent dir of desitination path {:?} due to {:?}",
                 dest, e
             ))
         })?;
-        symlink(&src, dest).map_err(|e| {
+        symlink(&target, dest).map_err(|e| {
             crate::TaskError::WellKnown(format!(
                 "cannot link {:?} to {:?} due to {:?}",
-                src, dest, e
+                target, dest, e
             ))
         })?;
+        record(ctx, &target, dest).await;
         Ok(true)
     }
 }
 
+/// Record symlink `dest` pointing to `src`.
+async fn record(ctx: &crate::TaskContext<'_>, src: &Path, dest: &Path) {
+    if let (Some(state), Ok(meta)) = (ctx.state, fs::symlink_metadata(dest).await) {
+        state.record(
+            dest,
+            Record {
+                src: src.to_owned(),
+                src_stat: None,
+                src_hash: None,
+                inputs: None,
+                output_hash: None,
+                dest: Stat::of(&meta),
+            },
+        );
+    }
+}
+
 /// parse task as a link task
 pub fn parse(obj: &HashMap<String, crate::ast::Value>) -> Result<crate::TaskEntity, crate::Error> {
-    crate::ast::verify_hash(obj, &["type", "src", "dest"], Some("tasks.link"))?;
+    crate::ast::verify_hash(
+        obj,
+        &["type", "src", "dest", "backup", "force", "relative"],
+        Some("tasks.link"),
+    )?;
     let src = obj
         .get("src")
         .ok_or_else(|| crate::Error::PlaybookLoadFailed("link.src is required".to_owned()))?
@@ -111,5 +205,152 @@ pub fn parse(obj: &HashMap<String, crate::ast::Value>) -> Result<crate::TaskEnti
         .as_str()
         .ok_or_else(|| crate::Error::PlaybookLoadFailed("link.dest must be string".to_owned()))?
         .to_owned();
-    Ok(crate::TaskEntity::Link(LinkTask { src, dest }))
+    let backup = obj
+        .get("backup")
+        .map(|val| {
+            val.as_bool().ok_or_else(|| {
+                crate::Error::InvalidPlaybook(
+                    "link.backup must be boolean".to_owned(),
+                    val.to_owned(),
+                )
+            })
+        })
+        .transpose()?;
+    let flag = |key: &str| {
+        obj.get(key)
+            .map(|val| {
+                val.as_bool().ok_or_else(|| {
+                    crate::Error::InvalidPlaybook(
+                        format!("link.{} must be boolean", key),
+                        val.to_owned(),
+                    )
+                })
+            })
+            .transpose()
+            .map(|flag| flag.unwrap_or(false))
+    };
+    Ok(crate::TaskEntity::Link(LinkTask {
+        src,
+        dest,
+        backup,
+        force: flag("force")?,
+        relative: flag("relative")?,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Task;
+
+    #[tokio::test]
+    async fn test_link() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-link-{}", std::process::id()));
+        let base = dir.join("dotfiles");
+        std::fs::create_dir_all(base.join("pkgs/vim")).unwrap();
+        std::fs::write(base.join("pkgs/vim/vimrc"), "set nu").unwrap();
+        let src = base.join("pkgs/vim/vimrc").canonicalize().unwrap();
+        let out = dir.join("out");
+        let backup = crate::backup::Backup::new(&dir.join("backups"), true);
+        let (cache, notes) = (Default::default(), Default::default());
+        let (vars, filters) = (liquid::Object::new(), crate::filters::Filters::default());
+        let ctx = |dryrun| crate::TaskContext {
+            base: base.clone(),
+            dryrun,
+            strict: false,
+            scenario: "default".to_owned(),
+            cache: &cache,
+            vars: &vars,
+            overrides: &vars,
+            filters: &filters,
+            notes: &notes,
+            backup: if dryrun { None } else { Some(&backup) },
+            state: None,
+        };
+        let task = |dest: &str, force, relative| LinkTask {
+            src: "pkgs/vim/vimrc".to_owned(),
+            dest: out.join(dest).to_str().unwrap().to_owned(),
+            backup: None,
+            force,
+            relative,
+        };
+
+        // nothing at the destination; `src` is relative to the base
+        assert!(task("nothing", false, false)
+            .execute(&ctx(true))
+            .await
+            .unwrap());
+        assert!(std::fs::symlink_metadata(out.join("nothing")).is_err());
+        assert!(task("nothing", false, false)
+            .execute(&ctx(false))
+            .await
+            .unwrap());
+        assert_eq!(std::fs::read_link(out.join("nothing")).unwrap(), src);
+        assert!(!task("nothing", false, false)
+            .execute(&ctx(false))
+            .await
+            .unwrap());
+
+        // symlinks elsewhere, even broken, are replaced without force
+        std::os::unix::fs::symlink(dir.join("missing"), out.join("broken")).unwrap();
+        assert!(task("broken", false, false)
+            .execute(&ctx(true))
+            .await
+            .unwrap());
+        assert_eq!(
+            std::fs::read_link(out.join("broken")).unwrap(),
+            dir.join("missing")
+        );
+        assert!(task("broken", false, false)
+            .execute(&ctx(false))
+            .await
+            .unwrap());
+        assert_eq!(std::fs::read_link(out.join("broken")).unwrap(), src);
+
+        // files and directories need force, and are backed up before being replaced
+        std::fs::write(out.join("file"), "local").unwrap();
+        std::fs::create_dir_all(out.join("dir/sub")).unwrap();
+        std::fs::write(out.join("dir/sub/keep"), "keep").unwrap();
+        for dest in ["file", "dir"] {
+            for dryrun in [true, false] {
+                assert!(matches!(
+                    task(dest, false, false).execute(&ctx(dryrun)).await,
+                    Err(crate::TaskError::WellKnown(_))
+                ));
+            }
+            assert!(task(dest, true, false).execute(&ctx(true)).await.unwrap());
+            assert!(!std::fs::symlink_metadata(out.join(dest))
+                .unwrap()
+                .file_type()
+                .is_symlink());
+        }
+        assert_eq!(backup.len(), 1);
+        for dest in ["file", "dir"] {
+            assert!(task(dest, true, false).execute(&ctx(false)).await.unwrap());
+            assert_eq!(std::fs::read_link(out.join(dest)).unwrap(), src);
+        }
+        assert_eq!(backup.len(), 3);
+        assert_eq!(std::fs::read_to_string(&src).unwrap(), "set nu");
+
+        // relative links are resolved from the parent of the destination
+        let relative = out.join("nested/relative");
+        assert!(task("nested/relative", false, true)
+            .execute(&ctx(false))
+            .await
+            .unwrap());
+        let target = std::fs::read_link(&relative).unwrap();
+        assert!(target.is_relative());
+        assert_eq!(std::fs::read_to_string(&relative).unwrap(), "set nu");
+        assert!(!task("nested/relative", false, true)
+            .execute(&ctx(false))
+            .await
+            .unwrap());
+        // switching to an absolute link replaces it
+        assert!(task("nested/relative", false, false)
+            .execute(&ctx(false))
+            .await
+            .unwrap());
+        assert_eq!(std::fs::read_link(&relative).unwrap(), src);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,8 +1,10 @@
 //! Builtin tasks
+pub mod attrs;
 pub mod brew;
 pub mod cargo;
 pub mod cp;
 pub mod env;
+pub mod exclude;
 pub mod link;
 pub mod sh;
 #[cfg(feature = "network")]
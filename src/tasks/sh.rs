@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::path::Path;
 use tokio::{fs, io, io::AsyncReadExt, process};
 
+use crate::util::describe_render_error;
 use crate::{TaskEntity, TaskError};
 
 enum Sha256Set {
@@ -42,10 +43,38 @@ impl crate::Task for ShTask {
     }
 
     async fn execute(&self, ctx: &crate::TaskContext) -> crate::TaskResult {
+        let exe =
+            crate::util::resolve_template(&self.cmd.0, ctx.vars, ctx.filters).map_err(|e| {
+                TaskError::WellKnown(format!(
+                    "cannot resolve command {} due to {}",
+                    self.cmd.0,
+                    describe_render_error(&e)
+                ))
+            })?;
+        let args = self
+            .cmd
+            .1
+            .iter()
+            .map(|arg| {
+                crate::util::resolve_template(arg, ctx.vars, ctx.filters).map_err(|e| {
+                    TaskError::WellKnown(format!(
+                        "cannot resolve argument {} due to {}",
+                        arg,
+                        describe_render_error(&e)
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
         match &self.test {
             Some((path, Some(sha256))) => {
-                let path = crate::util::resolve_liquid_template(path)
-                    .map_err(|_| TaskError::WellKnown(format!("cannot resolve path {}", path)))?;
+                let path =
+                    crate::util::resolve_template(path, ctx.vars, ctx.filters).map_err(|e| {
+                        TaskError::WellKnown(format!(
+                            "cannot resolve path {} due to {}",
+                            path,
+                            describe_render_error(&e)
+                        ))
+                    })?;
                 let sha256 = sha256.get(&ctx.scenario).ok_or_else(|| {
                     crate::TaskError::WellKnown(format!("sh.sha256.{} is not found", &ctx.scenario))
                 })?;
@@ -55,8 +84,8 @@ impl crate::Task for ShTask {
                 {
                     Ok(false)
                 } else {
-                    process::Command::new(&self.cmd.0)
-                        .args(&self.cmd.1)
+                    process::Command::new(&exe)
+                        .args(&args)
                         .output()
                         .await
                         .map_err(|e| crate::TaskError::WellKnown(format!("sh error {:?}", e)))?;
@@ -73,13 +102,19 @@ impl crate::Task for ShTask {
                 }
             }
             Some((path, None)) => {
-                let path = crate::util::resolve_liquid_template(path)
-                    .map_err(|_| TaskError::WellKnown(format!("cannot resolve path {}", path)))?;
+                let path =
+                    crate::util::resolve_template(path, ctx.vars, ctx.filters).map_err(|e| {
+                        TaskError::WellKnown(format!(
+                            "cannot resolve path {} due to {}",
+                            path,
+                            describe_render_error(&e)
+                        ))
+                    })?;
                 if fs::metadata(&path).await.is_ok() {
                     return Ok(false);
                 }
-                process::Command::new(&self.cmd.0)
-                    .args(&self.cmd.1)
+                process::Command::new(&exe)
+                    .args(&args)
                     .output()
                     .await
                     .map_err(|e| crate::TaskError::WellKnown(format!("sh error {:?}", e)))?;
@@ -93,8 +128,8 @@ impl crate::Task for ShTask {
                 }
             }
             None => {
-                process::Command::new(&self.cmd.0)
-                    .args(&self.cmd.1)
+                process::Command::new(&exe)
+                    .args(&args)
                     .output()
                     .await
                     .map_err(|e| crate::TaskError::WellKnown(format!("sh error {:?}", e)))?;
@@ -1,11 +1,12 @@
 //! Builtin wget task.
 use std::collections::HashMap;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::{fs, io};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncReadExt;
 
 use sha2::{Digest, Sha256};
 
-use crate::util::resolve_liquid_template;
+use crate::util::{describe_render_error, resolve_template, write_atomic};
 use crate::TaskEntity;
 
 enum Sha256Set {
@@ -42,10 +43,18 @@ impl crate::Task for WgetTask {
     }
 
     async fn execute(&self, ctx: &crate::TaskContext) -> crate::TaskResult {
-        let dest = resolve_liquid_template(&self.dest).map_err(|e| {
+        let dest = resolve_template(&self.dest, ctx.vars, ctx.filters).map_err(|e| {
             crate::TaskError::WellKnown(format!(
-                "cannot resolve template {} due to {:?}",
-                &self.dest, e
+                "cannot resolve template {} due to {}",
+                &self.dest,
+                describe_render_error(&e)
+            ))
+        })?;
+        let url = resolve_template(&self.url, ctx.vars, ctx.filters).map_err(|e| {
+            crate::TaskError::WellKnown(format!(
+                "cannot resolve template {} due to {}",
+                &self.url,
+                describe_render_error(&e)
             ))
         })?;
         let mut buf = Vec::new();
@@ -57,13 +66,13 @@ impl crate::Task for WgetTask {
                 return Ok(false);
             }
         }
-        let res = reqwest::get(&self.url).await.map_err(|e| {
-            crate::TaskError::WellKnown(format!("cannot download {} due to {:?}", &self.url, e))
+        let res = reqwest::get(&url).await.map_err(|e| {
+            crate::TaskError::WellKnown(format!("cannot download {} due to {:?}", &url, e))
         })?;
         let buf = res.bytes().await.map_err(|e| {
             crate::TaskError::WellKnown(format!(
                 "cannot read response body of {} due to {:?}",
-                &self.url, e
+                &url, e
             ))
         })?;
 
@@ -73,19 +82,14 @@ impl crate::Task for WgetTask {
             ));
         }
 
-        let f = fs::File::create(&dest).await.map_err(|e| {
-            crate::TaskError::WellKnown(format!(
-                "cannot open {} as writ-mode due to {:?}",
-                &dest, e
-            ))
-        })?;
-        let mut writer = io::BufWriter::new(f);
-        writer.write_all(buf.as_ref()).await.map_err(|e| {
-            crate::TaskError::WellKnown(format!(
-                "cannot write response body to local file due to {:?}",
-                e
-            ))
-        })?;
+        write_atomic(Path::new(&dest), buf.as_ref(), None)
+            .await
+            .map_err(|e| {
+                crate::TaskError::WellKnown(format!(
+                    "cannot write response body to {} due to {:?}",
+                    &dest, e
+                ))
+            })?;
         Ok(true)
     }
 }
@@ -1,8 +1,37 @@
 //! Utilities for implementation of tasks.
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
 use kstring::KString;
 use std::env;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::AsyncWriteExt;
 
-fn liquid_object_for_global_resolve() -> liquid::Object {
+/// Operating system exposed to templates as `os`.
+#[cfg(target_os = "linux")]
+pub const OS: &str = "Linux";
+/// Operating system exposed to templates as `os`.
+#[cfg(target_os = "macos")]
+pub const OS: &str = "Darwin";
+/// Operating system exposed to templates as `os`.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub const OS: &str = std::env::consts::OS;
+
+/// CPU architecture exposed to templates as `arch`.
+pub const ARCH: &str = std::env::consts::ARCH;
+
+/// Directory of data dotman keeps between runs (`$XDG_STATE_HOME/dotman`, `~/.local/state/dotman`
+/// if unset).
+pub fn state_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .map(|dir| dir.join("dotman"))
+}
+
+fn liquid_object_for_global_resolve(vars: &liquid::Object) -> liquid::Object {
     let mut obj = liquid::Object::new();
     let mut env_obj = liquid::Object::new();
     for (name, val) in env::vars() {
@@ -15,42 +44,331 @@ fn liquid_object_for_global_resolve() -> liquid::Object {
         KString::from_static("env"),
         liquid::model::Value::Object(env_obj),
     );
-    #[cfg(target_os = "linux")]
-    obj.insert(KString::from_static("os"), liquid::model::value!("Linux"));
-    #[cfg(target_os = "macos")]
-    obj.insert(KString::from_static("os"), liquid::model::value!("Darwin"));
-    #[cfg(target_arch = "x86_64")]
+    obj.insert(KString::from_static("os"), liquid::model::Value::scalar(OS));
     obj.insert(
         KString::from_static("arch"),
-        liquid::model::value!("x86_64"),
-    );
-    #[cfg(target_arch = "x86")]
-    obj.insert(KString::from_static("arch"), liquid::model::value!("x86"));
-    #[cfg(target_arch = "aarch64")]
-    obj.insert(
-        KString::from_static("arch"),
-        liquid::model::value!("aarch64"),
+        liquid::model::Value::scalar(ARCH),
     );
+    for (name, val) in vars {
+        obj.insert(name.clone(), val.clone());
+    }
     obj
 }
 
-pub fn resolve_liquid_template(src: &str) -> Result<String, liquid::Error> {
-    let template = liquid::ParserBuilder::with_stdlib()
-        .build()
-        .unwrap()
-        .parse(src)?;
-    template.render(&liquid_object_for_global_resolve())
+/// Liquid parser shared by all templates, with stdlib, [dotman filters](../filters/index.html)
+/// and [partials](../partials/index.html). Built once per `filters`.
+pub fn parser(
+    filters: &crate::filters::Filters,
+) -> Result<&crate::engine::LiquidParser, liquid::Error> {
+    filters
+        .parser_cell()
+        .get_or_try_init(|| crate::engine::LiquidParser::new(filters))
+}
+
+/// Render `src` with environment variables, `os`, `arch` and `vars` by `engine` of the playbook.
+/// Used where no task context exists (e.g. loading playbook).
+pub fn resolve_liquid_template(
+    src: &str,
+    vars: &liquid::Object,
+    partials: &crate::partials::Partials,
+    engine: crate::engine::Engine,
+) -> Result<String, liquid::Error> {
+    resolve_template(
+        src,
+        vars,
+        &crate::filters::Filters::default()
+            .with_partials(partials.clone())
+            .with_engine(engine),
+    )
+}
+
+/// Render `src` with environment variables, `os`, `arch` and `vars` by the engine of `filters`.
+/// Secrets which `src` refers are decrypted.
+pub fn resolve_template(
+    src: &str,
+    vars: &liquid::Object,
+    filters: &crate::filters::Filters,
+) -> Result<String, liquid::Error> {
+    filters
+        .engine()
+        .get()
+        .render(src, &liquid_object_for_global_resolve(vars), filters)
+        .map(|rendered| rendered.text)
+}
+
+/// Path of undefined variable which `e` reports, e.g. `env.XDG_CONFIG_HOME`.
+pub fn undefined_variable(e: &liquid::Error) -> Option<String> {
+    let msg = e.to_string();
+    let field = |key: &str| {
+        msg.lines()
+            .find_map(|line| line.trim().strip_prefix(key))
+            .map(|value| value.to_owned())
+    };
+    if msg.contains("Unknown variable") {
+        field("requested variable=")
+    } else if msg.contains("Unknown index") {
+        Some(format!(
+            "{}.{}",
+            field("variable=")?,
+            field("requested index=")?
+        ))
+    } else {
+        None
+    }
+}
+
+/// Human readable description of render error.
+pub fn describe_render_error(e: &liquid::Error) -> String {
+    undefined_variable(e)
+        .map(|path| format!("undefined variable `{}`", path))
+        .unwrap_or_else(|| e.to_string().trim().to_owned())
+}
+
+/// Create a directory which only the user can access in the temporary directory, for files
+/// others must not read (e.g. a secret being edited). It has a random name and is created
+/// exclusively, so that nobody can prepare it in advance.
+pub fn private_temp_dir(prefix: &str) -> std::io::Result<PathBuf> {
+    loop {
+        let dir = env::temp_dir().join(format!("{}-{:016x}", prefix, OsRng.next_u64()));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            created => return created.map(|()| dir),
+        }
+    }
+}
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Write `content` to `dest` atomically. A temporary file next to `dest` is written, synced and
+/// renamed over it, so that an interrupted deploy never leaves a truncated file. The mode is
+/// `mode` if given, otherwise that of the existing `dest` (new files follow umask).
+pub async fn write_atomic(dest: &Path, content: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    let (dir, tmp) = temp_path(dest)?;
+    let existing = tokio::fs::metadata(dest).await.ok();
+    let mode = mode.or_else(|| {
+        existing
+            .as_ref()
+            .map(|meta| meta.permissions().mode() & 0o7777)
+    });
+    let written = async {
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // never expose the content with looser permissions than requested
+        options.mode(mode.map(|mode| mode & 0o700).unwrap_or(0o666));
+        let mut file = options.open(&tmp).await?;
+        file.write_all(content).await?;
+        file.sync_all().await?;
+        drop(file);
+        replace_with(&tmp, dest, existing.as_ref(), mode).await
+    }
+    .await;
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    written?;
+    sync_dir(dir).await;
+    Ok(())
+}
+
+/// Clone `src` to `dest` atomically like `write_atomic`, sharing data blocks with `src`
+/// (`FICLONE`). `Ok(false)` is returned without touching `dest` if the filesystem cannot.
+pub async fn reflink_atomic(src: &Path, dest: &Path, mode: u32) -> std::io::Result<bool> {
+    let (dir, tmp) = temp_path(dest)?;
+    let existing = tokio::fs::metadata(dest).await.ok();
+    let cloned = async {
+        let (src, clone) = (src.to_owned(), tmp.clone());
+        let cloned = tokio::task::spawn_blocking(move || clone_file(&src, &clone, mode))
+            .await
+            .map_err(std::io::Error::other)??;
+        if cloned {
+            replace_with(&tmp, dest, existing.as_ref(), Some(mode)).await?;
+        }
+        Ok::<_, std::io::Error>(cloned)
+    }
+    .await;
+    if !matches!(cloned, Ok(true)) {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    if cloned? {
+        sync_dir(dir).await;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn clone_file(src: &Path, dest: &Path, mode: u32) -> std::io::Result<bool> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    let src = std::fs::File::open(src)?;
+    let dest = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode & 0o700)
+        .open(dest)?;
+    // SAFETY: both descriptors are open for the duration of the call
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == 0 {
+        dest.sync_all()?;
+        return Ok(true);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV | libc::EINVAL) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_src: &Path, _dest: &Path, _mode: u32) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Directory of `dest` and a unique temporary path in it.
+fn temp_path(dest: &Path) -> std::io::Result<(&Path, PathBuf)> {
+    let dir = match dest.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+    let name = dest.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{:?} is not a file path", dest),
+        )
+    })?;
+    let tmp = dir.join(format!(
+        ".{}.dotman-{}-{}",
+        name.to_string_lossy(),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    Ok((dir, tmp))
+}
+
+/// Rename the written `tmp` over `dest` after giving it the owner of `existing` and `mode`.
+async fn replace_with(
+    tmp: &Path,
+    dest: &Path,
+    existing: Option<&std::fs::Metadata>,
+    mode: Option<u32>,
+) -> std::io::Result<()> {
+    if let Some(meta) = existing {
+        // keep the owner of files deployed by root, ignoring failures as non-root
+        let _ = std::os::unix::fs::chown(tmp, Some(meta.uid()), Some(meta.gid()));
+    }
+    if let Some(mode) = mode {
+        tokio::fs::set_permissions(tmp, std::fs::Permissions::from_mode(mode)).await?;
+    }
+    tokio::fs::rename(tmp, dest).await
+}
+
+/// Persist renames in `dir`.
+async fn sync_dir(dir: &Path) {
+    if let Ok(dir) = tokio::fs::File::open(dir).await {
+        let _ = dir.sync_all().await;
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[tokio::test]
+    async fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join("sshd_config");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        write_atomic(&dest, b"first", None).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"first");
+        std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(0o640)).unwrap();
+        // mode of the existing file is kept
+        write_atomic(&dest, b"second", None).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"second");
+        assert_eq!(mode(&dest), 0o640);
+        write_atomic(&dest, b"third", Some(0o600)).await.unwrap();
+        assert_eq!(mode(&dest), 0o600);
+        // no temporary file is left
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(write_atomic(&dir.join("missing/file"), b"", None)
+            .await
+            .is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_resolve_liquid_template() {
         assert_eq!(
-            resolve_liquid_template("{{env.HOME}}/.config").unwrap(),
+            resolve_liquid_template(
+                "{{env.HOME}}/.config",
+                &liquid::Object::new(),
+                &crate::partials::Partials::default(),
+                Default::default(),
+            )
+            .unwrap(),
             format!("{}/.config", std::env::var("HOME").unwrap())
         );
+        // the engine of the playbook
+        assert_eq!(
+            resolve_liquid_template(
+                "{{ env.HOME ~ '/.config' }}",
+                &liquid::Object::new(),
+                &crate::partials::Partials::default(),
+                crate::engine::Engine::Jinja,
+            )
+            .unwrap(),
+            format!("{}/.config", std::env::var("HOME").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let partials = crate::partials::Partials::default();
+        let vars = liquid::object!({ "font": { "size": 11 } });
+        let e = resolve_liquid_template(
+            "{{ env.DOTMAN_UNDEFINED }}",
+            &vars,
+            &partials,
+            Default::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            describe_render_error(&e),
+            "undefined variable `env.DOTMAN_UNDEFINED`"
+        );
+        let e = resolve_liquid_template("{{ theme }}", &vars, &partials, Default::default())
+            .unwrap_err();
+        assert_eq!(undefined_variable(&e), Some("theme".to_owned()));
+        assert_eq!(
+            resolve_liquid_template(
+                "{{ env.DOTMAN_UNDEFINED | default: '~/.config' }} {{ theme.bg | default: 'black' }} {{ font.size | default: 12 }}",
+                &vars,
+                &partials,
+                Default::default(),
+            )
+            .unwrap(),
+            "~/.config black 11"
+        );
+        assert_eq!(
+            resolve_liquid_template(
+                "{{ theme | strip | default: 'dark' }}",
+                &vars,
+                &partials,
+                Default::default(),
+            )
+            .unwrap(),
+            "dark"
+        );
+        // variables stay undefined outside of `default`
+        let e = resolve_liquid_template(
+            "{{ theme | default: 'dark' }} {{ theme }}",
+            &vars,
+            &partials,
+            Default::default(),
+        )
+        .unwrap_err();
+        assert_eq!(undefined_variable(&e), Some("theme".to_owned()));
     }
 }
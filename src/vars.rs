@@ -0,0 +1,276 @@
+//! Template variables defined in playbook, scenarios, taskgroups and command line.
+//!
+//! Layers are merged in the following order, later layers take precedence.
+//!
+//! 1. playbook `vars`
+//! 2. playbook `vars_files`
+//! 3. scenario `vars`
+//! 4. scenario `vars_files`
+//! 5. `vars` of the matched inventory host
+//! 6. taskgroup `vars`
+//! 7. `cp.templates[].vars` (only for templated files)
+//! 8. `--var` and `--vars-file` given on command line
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use yaml_rust::YamlLoader;
+
+use crate::{ast, Error};
+
+/// Variables of one layer.
+pub type Vars = HashMap<String, ast::Value>;
+
+/// Entry of `vars_files`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarsFile {
+    path: String,
+    optional: bool,
+}
+
+/// parse `vars` section.
+pub fn parse(yaml: &ast::Value, prefix: &str) -> Result<Vars, Error> {
+    yaml.as_hash()
+        .cloned()
+        .ok_or_else(|| Error::InvalidPlaybook(format!("{} must be hash", prefix), yaml.to_owned()))
+}
+
+/// Parse `key=value` given by `--var`.
+/// The value is interpreted as yaml scalar, so `--var font_size=11` is an integer.
+pub fn parse_assignment(src: &str) -> Result<(String, ast::Value), Error> {
+    let (key, val) = src.split_once('=').ok_or_else(|| {
+        Error::PlaybookLoadFailed(format!("--var {} must be formed as key=value", src))
+    })?;
+    let val = YamlLoader::load_from_str(val)
+        .ok()
+        .and_then(|yaml| yaml.into_iter().next())
+        .and_then(|yaml| ast::Value::from_yaml(yaml).ok())
+        .filter(|val| !matches!(val, ast::Value::Array(_) | ast::Value::Hash(_)))
+        .unwrap_or_else(|| ast::Value::Str(val.to_owned()));
+    Ok((key.to_owned(), val))
+}
+
+fn parse_vars_file(yaml: &ast::Value, prefix: &str) -> Result<VarsFile, Error> {
+    match yaml {
+        ast::Value::Str(path) => Ok(VarsFile {
+            path: path.to_owned(),
+            optional: false,
+        }),
+        ast::Value::Hash(hash) => {
+            ast::verify_hash(hash, &["path", "optional"], Some(prefix))?;
+            let path = hash
+                .get("path")
+                .and_then(|path| path.as_str())
+                .ok_or_else(|| {
+                    Error::InvalidPlaybook(
+                        format!("{}.path must be string", prefix),
+                        yaml.to_owned(),
+                    )
+                })?;
+            let optional = hash
+                .get("optional")
+                .map(|optional| {
+                    optional.as_bool().ok_or_else(|| {
+                        Error::InvalidPlaybook(
+                            format!("{}.optional must be boolean", prefix),
+                            yaml.to_owned(),
+                        )
+                    })
+                })
+                .unwrap_or(Ok(false))?;
+            Ok(VarsFile {
+                path: path.to_owned(),
+                optional,
+            })
+        }
+        _ => Err(Error::InvalidPlaybook(
+            format!("{} must be string or {{path, optional}}", prefix),
+            yaml.to_owned(),
+        )),
+    }
+}
+
+/// parse `vars_files` section.
+pub fn parse_files(yaml: &ast::Value, prefix: &str) -> Result<Vec<VarsFile>, Error> {
+    yaml.as_array()
+        .ok_or_else(|| {
+            Error::InvalidPlaybook(format!("{} must be array", prefix), yaml.to_owned())
+        })?
+        .iter()
+        .map(|file| parse_vars_file(file, prefix))
+        .collect()
+}
+
+pub(crate) fn parse_file_content(path: &Path, src: &str) -> Result<ast::Value, Error> {
+    let invalid_syntax = || Error::PlaybookLoadFailed(format!("{:?} has invalid syntax", path));
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => {
+            ast::Value::from_json(serde_json::from_str(src).map_err(|_| invalid_syntax())?).map_err(
+                |_| Error::PlaybookLoadFailed(format!("cannot convert {:?} to general ast", path)),
+            )
+        }
+        Some("toml") => Ok(ast::Value::from_toml(
+            toml::from_str(src).map_err(|_| invalid_syntax())?,
+        )),
+        _ => {
+            let yaml = YamlLoader::load_from_str(src)
+                .map_err(|_| invalid_syntax())?
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::PlaybookLoadFailed(format!("{:?} is empty", path)))?;
+            ast::Value::from_yaml(yaml).map_err(|_| {
+                Error::PlaybookLoadFailed(format!("cannot convert {:?} to general ast", path))
+            })
+        }
+    }
+}
+
+/// Load yaml, json or toml document as general ast. The format is chosen by its extension.
+pub(crate) fn load_document(path: &Path) -> Result<ast::Value, Error> {
+    let src = fs::read_to_string(path)
+        .map_err(|e| Error::PlaybookLoadFailed(format!("cannot read {:?} due to {:?}", path, e)))?;
+    parse_file_content(path, &src)
+}
+
+/// Load variables from yaml, json or toml file.
+pub fn load_file(path: &Path) -> Result<Vars, Error> {
+    parse(&load_document(path)?, "vars file")
+}
+
+/// Load `vars_files` relative to `base`. Paths are expanded with `vars` (e.g. `facts`).
+/// Later files take precedence.
+pub fn load_files(
+    files: &[VarsFile],
+    base: &Path,
+    vars: &liquid::Object,
+    partials: &crate::partials::Partials,
+    engine: crate::engine::Engine,
+) -> Result<Vars, Error> {
+    let mut loaded = HashMap::new();
+    for file in files {
+        let path = crate::util::resolve_liquid_template(&file.path, vars, partials, engine)
+            .map_err(|e| {
+                Error::PlaybookLoadFailed(format!(
+                    "cannot resolve vars file path {} due to {:?}",
+                    file.path, e
+                ))
+            })?;
+        let path = base.join(path);
+        if file.optional && fs::metadata(&path).is_err() {
+            continue;
+        }
+        loaded.extend(load_file(&path)?);
+    }
+    Ok(loaded)
+}
+
+/// Merge layers into a liquid object. Later layers take precedence.
+pub fn merge(base: &liquid::Object, layers: &[&Vars]) -> liquid::Object {
+    let mut obj = base.clone();
+    for layer in layers {
+        for (name, val) in layer.iter() {
+            obj.insert(name.to_owned().into(), val.to_liquid());
+        }
+    }
+    obj
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use liquid::model::ValueView;
+    use maplit::hashmap;
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(
+            parse_assignment("font_size=11").unwrap(),
+            ("font_size".to_owned(), ast::Value::Int(11))
+        );
+        assert_eq!(
+            parse_assignment("font=Hack Nerd Font").unwrap(),
+            (
+                "font".to_owned(),
+                ast::Value::Str("Hack Nerd Font".to_owned())
+            )
+        );
+        assert_eq!(
+            parse_assignment("list=[1, 2]").unwrap(),
+            ("list".to_owned(), ast::Value::Str("[1, 2]".to_owned()))
+        );
+        assert!(parse_assignment("font_size").is_err());
+    }
+
+    #[test]
+    fn test_load_files() {
+        let dir = std::env::temp_dir().join(format!("dotman-test-vars-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("common.yaml"), "font_size: 11\nmonitors: [DP-1]\n").unwrap();
+        fs::write(
+            dir.join("host.json"),
+            r#"{"font_size": 12, "email": "a@b"}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("work.toml"), "email = \"c@d\"\n").unwrap();
+        let mut facts = liquid::Object::new();
+        facts.insert("host".into(), liquid::model::Value::scalar("host"));
+        let files = vec![
+            VarsFile {
+                path: "common.yaml".to_owned(),
+                optional: false,
+            },
+            VarsFile {
+                path: "{{host}}.json".to_owned(),
+                optional: false,
+            },
+            VarsFile {
+                path: "work.toml".to_owned(),
+                optional: false,
+            },
+            VarsFile {
+                path: "missing.yaml".to_owned(),
+                optional: true,
+            },
+        ];
+        let vars = load_files(
+            &files,
+            &dir,
+            &facts,
+            &Default::default(),
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(vars.get("font_size"), Some(&ast::Value::Int(12)));
+        assert_eq!(vars.get("email"), Some(&ast::Value::Str("c@d".to_owned())));
+        assert_eq!(
+            vars.get("monitors"),
+            Some(&ast::Value::Array(vec![ast::Value::Str("DP-1".to_owned())]))
+        );
+        let required = vec![VarsFile {
+            path: "missing.yaml".to_owned(),
+            optional: false,
+        }];
+        assert!(load_files(
+            &required,
+            &dir,
+            &facts,
+            &Default::default(),
+            Default::default()
+        )
+        .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let playbook = hashmap! {
+            "font_size".to_owned() => ast::Value::Int(11),
+            "opacity".to_owned() => ast::Value::Real(0.8),
+        };
+        let taskgroup = hashmap! {
+            "font_size".to_owned() => ast::Value::Int(13),
+        };
+        let obj = merge(&liquid::Object::new(), &[&playbook, &taskgroup]);
+        assert_eq!(obj.get("font_size").unwrap().to_kstr(), "13");
+        assert_eq!(obj.get("opacity").unwrap().to_kstr(), "0.8");
+    }
+}